@@ -0,0 +1,99 @@
+// Reading and writing audio in the flow-synth livecode protocol
+//
+// On stdin, flow-synth first sends a stream header:
+//
+//     char     magic[4];    "FSYN"
+//     uint32_t version;     FLOWSYNTH_VERSION
+//     uint32_t rate;        sample rate in Hz
+//     uint32_t channels;
+//     uint32_t block_size;  most frames in a block
+//     uint32_t format;      FLOWSYNTH_FORMAT_F32: interleaved 32 bit floats
+//
+// Then each block is a uint32_t frame count, followed by that many frames. Answer each block on stdout
+// with the same number of frames, without the count. Everything is in the host's byte order.
+
+#ifndef FLOWSYNTH_H
+#define FLOWSYNTH_H
+
+#include <stdint.h>
+#include <stdio.h>
+#include <string.h>
+#include <unistd.h>
+
+#define FLOWSYNTH_VERSION 1
+#define FLOWSYNTH_FORMAT_F32 1
+
+struct flowsynth_header {
+    uint32_t rate;
+    uint32_t channels;
+    uint32_t block_size;
+    uint32_t format;
+};
+
+// read exactly len bytes, returning 0 on success and -1 at the end of the stream
+static int flowsynth_read_all(int fd, void *buf, size_t len) {
+    char *pos = buf;
+    while (len > 0) {
+        ssize_t n = read(fd, pos, len);
+        if (n <= 0)
+            return -1;
+        pos += n;
+        len -= n;
+    }
+    return 0;
+}
+
+// write exactly len bytes, returning 0 on success and -1 if the stream is closed
+static int flowsynth_write_all(int fd, const void *buf, size_t len) {
+    const char *pos = buf;
+    while (len > 0) {
+        ssize_t n = write(fd, pos, len);
+        if (n <= 0)
+            return -1;
+        pos += n;
+        len -= n;
+    }
+    return 0;
+}
+
+// read the stream header from stdin, returning 0 if it is one this file understands
+static int flowsynth_read_header(struct flowsynth_header *header) {
+    char magic[4];
+    uint32_t fields[5];
+    if (flowsynth_read_all(STDIN_FILENO, magic, sizeof magic) < 0
+            || flowsynth_read_all(STDIN_FILENO, fields, sizeof fields) < 0) {
+        fprintf(stderr, "flowsynth: no stream header\n");
+        return -1;
+    }
+    if (memcmp(magic, "FSYN", 4) != 0 || fields[0] != FLOWSYNTH_VERSION) {
+        fprintf(stderr, "flowsynth: unknown stream header\n");
+        return -1;
+    }
+    header->rate = fields[1];
+    header->channels = fields[2];
+    header->block_size = fields[3];
+    header->format = fields[4];
+    if (header->format != FLOWSYNTH_FORMAT_F32) {
+        fprintf(stderr, "flowsynth: unknown sample format %u\n", header->format);
+        return -1;
+    }
+    return 0;
+}
+
+// read a block from stdin into buffer, which must fit block_size frames. Returns the number of
+// frames, or -1 at the end of the stream.
+static long flowsynth_read_block(const struct flowsynth_header *header, float *buffer) {
+    uint32_t frames;
+    if (flowsynth_read_all(STDIN_FILENO, &frames, sizeof frames) < 0 || frames > header->block_size)
+        return -1;
+    if (flowsynth_read_all(STDIN_FILENO, buffer, frames * header->channels * sizeof(float)) < 0)
+        return -1;
+    return frames;
+}
+
+// write a block of frames to stdout, returning 0 on success
+static int flowsynth_write_block(const struct flowsynth_header *header, const float *buffer, size_t frames) {
+    return flowsynth_write_all(STDOUT_FILENO, buffer, frames * header->channels * sizeof(float));
+}
+
+#endif
@@ -1,24 +1,29 @@
 // Simple C livecode example
 
-#include <unistd.h>
+#include <stdlib.h>
 #include <math.h>
 
-// CAREFUL: make sure these match the input or bad things will happen
-const size_t BUFSIZE = 1024;
-const size_t CHANNELS = 2;
+#include "flowsynth.h"
 
 // processing function: absolute value distortion
-void f(float frame[CHANNELS]) {
-    for (size_t i = 0; i < CHANNELS; i++)
+void f(float *frame, size_t channels) {
+    for (size_t i = 0; i < channels; i++)
         frame[i] = fabs(frame[i])*2-1;
 }
 
 int main() {
-    float buffer[BUFSIZE][CHANNELS];
+    // flow-synth tells us the size of the blocks it sends before anything else
+    struct flowsynth_header header;
+    if (flowsynth_read_header(&header) < 0)
+        return 1;
+    float *buffer = malloc(header.block_size * header.channels * sizeof(float));
     for(;;) {
-        read(STDIN_FILENO, buffer, sizeof buffer);
-        for (size_t i = 0; i < BUFSIZE; i++)
-            f(buffer[i]);
-        write(STDOUT_FILENO, buffer, sizeof buffer);
+        long frames = flowsynth_read_block(&header, buffer);
+        if (frames < 0)
+            return 0;
+        for (long i = 0; i < frames; i++)
+            f(&buffer[i * header.channels], header.channels);
+        if (flowsynth_write_block(&header, buffer, frames) < 0)
+            return 0;
     }
 }
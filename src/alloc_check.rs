@@ -0,0 +1,75 @@
+//! Detects memory allocation in code that must not allocate, such as the JACK process callback.
+//!
+//! With the `alloc-check` feature, a global allocator counts every allocation, reallocation and free
+//! made inside `forbid`. Without it, `forbid` just runs its closure.
+
+#[cfg(feature = "alloc-check")]
+use std::alloc::{GlobalAlloc, Layout, System};
+#[cfg(feature = "alloc-check")]
+use std::cell::Cell;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+static VIOLATIONS: AtomicUsize = AtomicUsize::new(0);
+
+#[cfg(feature = "alloc-check")]
+thread_local!(static FORBIDDEN: Cell<bool> = Cell::new(false));
+
+/// Run `f`, counting any memory allocations it makes as violations.
+#[cfg(feature = "alloc-check")]
+pub fn forbid<T, F: FnOnce() -> T>(f: F) -> T {
+    let outer = FORBIDDEN.with(|forbidden| forbidden.replace(true));
+    let result = f();
+    FORBIDDEN.with(|forbidden| forbidden.set(outer));
+    result
+}
+#[cfg(not(feature = "alloc-check"))]
+#[inline(always)]
+pub fn forbid<T, F: FnOnce() -> T>(f: F) -> T {
+    f()
+}
+
+/// The number of allocations made inside `forbid` so far. Always 0 without the `alloc-check`
+/// feature.
+pub fn violations() -> usize {
+    VIOLATIONS.load(Ordering::Relaxed)
+}
+
+/// The system allocator, counting allocations made inside `forbid`.
+#[cfg(feature = "alloc-check")]
+pub struct CheckedAlloc;
+
+#[cfg(feature = "alloc-check")]
+impl CheckedAlloc {
+    fn check(&self) {
+        // try_with, since this may be called while the thread local is being torn down
+        if FORBIDDEN.try_with(|forbidden| forbidden.get()).unwrap_or(false) {
+            VIOLATIONS.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+#[cfg(feature = "alloc-check")]
+unsafe impl GlobalAlloc for CheckedAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        self.check();
+        System.alloc(layout)
+    }
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        self.check();
+        System.dealloc(ptr, layout)
+    }
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        self.check();
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[cfg(feature = "alloc-check")]
+#[test]
+fn test_forbid() {
+    let before = violations();
+    forbid(|| 1 + 1);
+    assert_eq!(violations(), before);
+    forbid(|| vec![1, 2, 3]);
+    assert!(violations() > before);
+}
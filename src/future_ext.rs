@@ -2,8 +2,9 @@ use std::cell::UnsafeCell;
 use std::ops::{Deref, DerefMut};
 use std::sync::{
     atomic::{AtomicBool, Ordering},
-    Arc,
+    Arc, Mutex,
 };
+use std::thread::{self, Thread};
 
 use crossbeam::queue::SegQueue;
 
@@ -56,6 +57,55 @@ impl Breaker {
     }
 }
 
+/// Wakes a task on behalf of a thread that must not allocate, such as a real-time audio thread. Waking
+/// a task directly may allocate, but unparking the helper thread that does it doesn't.
+pub struct RemoteWaker {
+    inner: Arc<RemoteWakerInner>,
+    thread: Thread,
+}
+struct RemoteWakerInner {
+    task: Mutex<Option<task::Waker>>,
+    done: AtomicBool,
+}
+impl RemoteWaker {
+    pub fn new() -> RemoteWaker {
+        let inner = Arc::new(RemoteWakerInner {
+            task: Mutex::new(None),
+            done: AtomicBool::new(false),
+        });
+        let handle = {
+            let inner = inner.clone();
+            thread::spawn(move || {
+                while !inner.done.load(Ordering::Relaxed) {
+                    thread::park();
+                    if let Some(ref task) = *inner.task.lock().unwrap() {
+                        task.wake();
+                    }
+                }
+            })
+        };
+        RemoteWaker {
+            inner,
+            thread: handle.thread().clone(),
+        }
+    }
+    /// Arrange for the current task to be woken. This has to happen before checking for whatever the
+    /// task is waiting on, or a wakeup could be missed.
+    pub fn register(&self, cx: &mut task::Context) {
+        *self.inner.task.lock().unwrap() = Some(cx.waker().clone());
+    }
+    /// Get the thread to `unpark` to wake the registered task.
+    pub fn thread(&self) -> Thread {
+        self.thread.clone()
+    }
+}
+impl Drop for RemoteWaker {
+    fn drop(&mut self) {
+        self.inner.done.store(true, Ordering::Relaxed);
+        self.thread.unpark();
+    }
+}
+
 /// A lock/mutex where attempting to lock produces a Future
 /// But you can also spin with `spin_lock` or try with `try_lock`
 ///
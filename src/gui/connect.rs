@@ -7,40 +7,81 @@ use std::cell::{Cell, RefCell};
 use std::rc::{Rc, Weak};
 use std::sync::Arc;
 
+/// The model behind a set of jacks. Jacks only ask the backend to connect or disconnect; the wires
+/// they draw are updated when the backend reports the change back through `JackContext::connected`
+/// and `JackContext::disconnected`.
 pub trait JackBackend {
+    type Id: Copy + PartialEq;
+    fn jack_id(&self) -> Self::Id;
+    /// Ids of everything this backend is currently connected to.
+    fn peers(&self) -> Vec<Self::Id>;
     fn name(&self) -> &str;
     fn can_connect(&self, other: &Self) -> bool;
-    fn connect(&self, other: &Self);
-    fn disconnect(&self);
+    /// Connect to another backend, or explain why that isn't possible.
+    fn connect(&self, other: &Self) -> Result<(), String>;
+    fn disconnect(&self, other: &Self);
+    /// Current fill level and capacity, for backends with bounded buffers.
+    fn fill(&self) -> Option<(usize, usize)> {
+        None
+    }
 }
 
 impl JackBackend for Arc<flow::OpaquePort> {
+    type Id = (flow::NodeId, flow::PortId);
+    fn jack_id(&self) -> Self::Id {
+        (self.node_id(), flow::OpaquePort::id(self))
+    }
+    fn peers(&self) -> Vec<Self::Id> {
+        self.edges()
+            .iter()
+            .map(|other| other.jack_id())
+            .collect()
+    }
     fn name(&self) -> &str {
         flow::OpaquePort::name(self)
     }
     fn can_connect(&self, other: &Self) -> bool {
         flow::OpaquePort::can_connect(self, other)
     }
-    fn connect(&self, other: &Self) {
-        flow::OpaquePort::connect(self, other).unwrap();
+    fn connect(&self, other: &Self) -> Result<(), String> {
+        flow::OpaquePort::connect(self, other).map_err(|err| match err {
+            flow::ConnectError::TypeMismatch(this, other) => {
+                format!("Type mismatch: {} vs {}", this, other)
+            }
+            err => format!("{:?}", err),
+        })
+    }
+    fn disconnect(&self, other: &Self) {
+        match flow::OpaquePort::disconnect_from(self, other) {
+            // the edge may already be gone, such as when the node at the other end was removed
+            Ok(()) | Err(flow::ConnectError::NotConnected) => {}
+            Err(err) => println!(
+                "Could not disconnect {} from {}: {:?}",
+                self.name(),
+                other.name(),
+                err
+            ),
+        }
     }
-    fn disconnect(&self) {
-        flow::OpaquePort::disconnect(self).unwrap();
+    fn fill(&self) -> Option<(usize, usize)> {
+        flow::OpaquePort::capacity(self).map(|capacity| (flow::OpaquePort::len(self), capacity))
     }
 }
 
 /// Jacks are GUI connection points
 /// wires (pipes) can connect jacks
+/// a jack may have any number of wires attached
 pub struct Jack<T: JackBackend> {
     jack_ctx: Weak<JackContext<T>>,
     backend: T,
     bounds: Cell<Box3>,
     origin: Cell<Pt3>,
-    connection: RefCell<Connection<T>>,
+    connections: RefCell<Vec<Connection<T>>>,
+    /// floating is a connection in process
+    floating: Cell<Option<Pt2>>,
 }
 
 enum Connection<T: JackBackend> {
-    None,
     /// head/tail distinction is so that only one endpoint draws the wire
     Head {
         endpoint: Weak<Jack<T>>,
@@ -48,15 +89,8 @@ enum Connection<T: JackBackend> {
     Tail {
         endpoint: Weak<Jack<T>>,
     },
-    /// floating is a connection in process
-    Floating {
-        pos: Pt2,
-    },
 }
 impl<T: JackBackend> Connection<T> {
-    fn is_connected(&self) -> bool {
-        self.endpoint().is_some()
-    }
     fn endpoint(&self) -> Option<Rc<Jack<T>>> {
         match self {
             Connection::Head {
@@ -65,25 +99,14 @@ impl<T: JackBackend> Connection<T> {
             | Connection::Tail {
                 endpoint,
             } => endpoint.upgrade(),
-            _ => None,
         }
     }
-    fn disconnect(&mut self) {
-        if self.is_connected() {
-            self.endpoint()
-                .map(|endpoint| endpoint.connection.replace(Connection::None));
-            *self = Connection::None;
-        }
-    }
-}
-impl<T: JackBackend> Drop for Jack<T> {
-    fn drop(&mut self) {
-        if self.connection.borrow().is_connected() {
-            self.backend.disconnect();
-        }
+    fn is_endpoint(&self, jack: &Rc<Jack<T>>) -> bool {
+        self.endpoint()
+            .map(|endpoint| Rc::ptr_eq(&endpoint, jack))
+            .unwrap_or(false)
     }
 }
-
 impl<T: JackBackend> Jack<T> {
     fn new(jack_ctx: &Rc<JackContext<T>>, backend: T, bounds: Box3, origin: Pt3) -> Jack<T> {
         Jack {
@@ -91,7 +114,8 @@ impl<T: JackBackend> Jack<T> {
             backend,
             bounds: Cell::new(bounds),
             origin: Cell::new(origin),
-            connection: RefCell::new(Connection::None),
+            connections: RefCell::new(Vec::new()),
+            floating: Cell::new(None),
         }
     }
     pub fn origin(&self) -> Pt3 {
@@ -108,26 +132,50 @@ impl<T: JackBackend> Jack<T> {
     pub fn name(&self) -> &str {
         self.backend.name()
     }
-    pub fn connect(self: &Rc<Jack<T>>, other: &Rc<Jack<T>>) {
-        // TODO: produce errors
-        let mut connection = self.connection.borrow_mut();
-        if self.backend.can_connect(&other.backend) {
-            // signal disconnect to backend
-            if connection.is_connected() {
-                self.backend.disconnect();
-            }
-            // update model
-            connection.disconnect();
-            *connection = Connection::Tail {
+    pub fn backend(&self) -> &T {
+        &self.backend
+    }
+    pub fn is_connected_to(self: &Rc<Jack<T>>, other: &Rc<Jack<T>>) -> bool {
+        self.connections
+            .borrow()
+            .iter()
+            .any(|connection| connection.is_endpoint(other))
+    }
+    /// Ask the backend to connect these two jacks.
+    pub fn connect(self: &Rc<Jack<T>>, other: &Rc<Jack<T>>) -> Result<(), String> {
+        if self.is_connected_to(other) {
+            Ok(())
+        } else {
+            self.backend.connect(&other.backend)
+        }
+    }
+    /// Ask the backend to disconnect these two jacks.
+    pub fn disconnect(self: &Rc<Jack<T>>, other: &Rc<Jack<T>>) {
+        if self.is_connected_to(other) {
+            self.backend.disconnect(&other.backend);
+        }
+    }
+    /// Add a wire to the model
+    fn attach(self: &Rc<Jack<T>>, other: &Rc<Jack<T>>) {
+        if !self.is_connected_to(other) {
+            self.connections.borrow_mut().push(Connection::Tail {
                 endpoint: Rc::downgrade(other),
-            };
-            *other.connection.borrow_mut() = Connection::Head {
+            });
+            other.connections.borrow_mut().push(Connection::Head {
                 endpoint: Rc::downgrade(self),
-            };
-            // signal connect to backend
-            self.backend.connect(&other.backend);
+            });
         }
     }
+    /// Remove a wire from the model
+    fn detach(self: &Rc<Jack<T>>, other: &Rc<Jack<T>>) {
+        self.connections
+            .borrow_mut()
+            .retain(|connection| connection.endpoint().is_some() && !connection.is_endpoint(other));
+        other
+            .connections
+            .borrow_mut()
+            .retain(|connection| connection.endpoint().is_some() && !connection.is_endpoint(self));
+    }
 }
 impl<T: JackBackend> GuiComponent for Rc<Jack<T>> {
     fn set_bounds(&mut self, bounds: Box3) {
@@ -176,56 +224,47 @@ impl<T: JackBackend> GuiComponent for Rc<Jack<T>> {
                         .upgrade()
                         .expect("no events expected during shutdown");
                     let mut in_progress = ctx.in_progress.borrow_mut();
-                    let mut connection = self.connection.borrow_mut();
-                    if let Some((ref mut weak_in_progress, ref mut endpoint)) = in_progress
-                        .as_mut()
-                        .and_then(|wip| wip.upgrade().map(|ep| (wip, ep)))
-                    {
-                        if self.backend.can_connect(&endpoint.backend) {
+                    if let Some(endpoint) = in_progress.as_ref().and_then(|wip| wip.upgrade()) {
+                        if Rc::ptr_eq(&endpoint, self) {
+                            // clicking the same jack again aborts the connection
+                            self.floating.set(None);
+                            *in_progress = None;
+                        } else if self.is_connected_to(&endpoint) {
+                            // connection in progress to a jack we are already wired to:
+                            // click removes the existing wire
+                            endpoint.floating.set(None);
+                            *in_progress = None;
+                            self.disconnect(&endpoint);
+                        } else {
                             // connection in progress:
-                            // click establishes new connection
-
-                            // signal disconnect to backend
-                            if connection.is_connected() {
-                                self.backend.disconnect();
+                            // click establishes new connection, leaving existing ones intact
+                            match self.connect(&endpoint) {
+                                Ok(()) => {
+                                    endpoint.floating.set(None);
+                                    *in_progress = None;
+                                    *ctx.message.borrow_mut() = None;
+                                }
+                                Err(reason) => {
+                                    // keep the wire floating so the user can try another jack
+                                    *ctx.message.borrow_mut() =
+                                        Some((reason, pos.with_z(0.0) + self.origin()));
+                                }
                             }
-                            // update model
-                            connection.disconnect();
-                            *connection = Connection::Tail {
-                                endpoint: weak_in_progress.clone(),
-                            };
-                            *endpoint.connection.borrow_mut() = Connection::Head {
-                                endpoint: Rc::downgrade(self),
-                            };
-                            *in_progress = None;
-                            // signal connect to backend
-                            self.backend.connect(&endpoint.backend);
                         }
                     } else {
                         // no connection in progress:
                         // begin connecting
-
-                        // signal disconnect to backend
-                        if connection.is_connected() {
-                            self.backend.disconnect();
-                        }
-                        // update model
-                        connection.disconnect();
-                        *connection = Connection::Floating {
-                            pos: pos + self.origin().drop_z(),
-                        };
+                        *ctx.message.borrow_mut() = None;
+                        self.floating.set(Some(pos + self.origin().drop_z()));
                         *in_progress = Some(Rc::downgrade(self));
                     }
                 }
             }
-            EventData::MouseMove(mouse_pos) => match *self.connection.borrow_mut() {
-                Connection::Floating {
-                    ref mut pos,
-                } => {
-                    *pos = mouse_pos + self.origin().drop_z();
+            EventData::MouseMove(mouse_pos) => {
+                if self.floating.get().is_some() {
+                    self.floating.set(Some(mouse_pos + self.origin().drop_z()));
                 }
-                _ => {}
-            },
+            }
             _ => {}
         }
     }
@@ -238,6 +277,8 @@ pub struct JackContext<T: JackBackend> {
     bounds: Cell<Box3>,
     jacks: RefCell<Vec<Weak<Jack<T>>>>,
     in_progress: RefCell<Option<Weak<Jack<T>>>>,
+    /// why the last connection attempt was refused, and where
+    message: RefCell<Option<(String, Pt3)>>,
 }
 
 impl<T: JackBackend> JackContext<T> {
@@ -246,15 +287,49 @@ impl<T: JackBackend> JackContext<T> {
             bounds: Cell::new(bounds),
             jacks: RefCell::new(Vec::new()),
             in_progress: RefCell::new(None),
+            message: RefCell::new(None),
         })
     }
     pub fn new_jack(self: &Rc<JackContext<T>>, backend: T, bounds: Box3, origin: Pt3) -> Rc<Jack<T>> {
         let jack = Rc::new(Jack::new(&self, backend, bounds, origin));
+        // pick up any connections that were made before this jack existed
+        for peer in jack.backend.peers() {
+            if let Some(other) = self.find_jack(peer) {
+                other.attach(&jack);
+            }
+        }
         let mut jacks = self.jacks.borrow_mut();
         weak_cleanup(&mut jacks);
         jacks.push(Rc::downgrade(&jack));
         jack
     }
+    pub fn find_jack(&self, id: T::Id) -> Option<Rc<Jack<T>>> {
+        self.jacks
+            .borrow()
+            .iter()
+            .filter_map(|jack| jack.upgrade())
+            .find(|jack| jack.backend.jack_id() == id)
+    }
+    /// Find the jack under a point, given in the same coordinates as the wires.
+    pub fn jack_at(&self, pos: Pt2) -> Option<Rc<Jack<T>>> {
+        self.jacks
+            .borrow()
+            .iter()
+            .filter_map(|jack| jack.upgrade())
+            .find(|jack| jack.intersect(pos - jack.origin().drop_z()))
+    }
+    /// Notify the context that the backend connected two jacks
+    pub fn connected(&self, a: T::Id, b: T::Id) {
+        if let (Some(a), Some(b)) = (self.find_jack(a), self.find_jack(b)) {
+            a.attach(&b);
+        }
+    }
+    /// Notify the context that the backend disconnected two jacks
+    pub fn disconnected(&self, a: T::Id, b: T::Id) {
+        if let (Some(a), Some(b)) = (self.find_jack(a), self.find_jack(b)) {
+            a.detach(&b);
+        }
+    }
 }
 
 /// Drop dropped Weak Ts
@@ -274,26 +349,38 @@ impl<T: JackBackend> GuiComponent for Rc<JackContext<T>> {
         self.bounds().flatten().drop_z().intersect(pos)
     }
     fn render(&mut self, device: &mut gl::Device, ctx: &mut RenderContext) {
+        if let Some((ref message, pos)) = *self.message.borrow() {
+            ctx.draw_text(message, pos + Pt3::new(8.0, 16.0, 0.0), [1.0, 0.4, 0.4]);
+        }
         let jacks = self.jacks.borrow();
         for jack in jacks.iter() {
             if let Some(jack) = jack.upgrade() {
-                match *jack.connection.borrow() {
-                    Connection::Head {
-                        ref endpoint,
-                    } => {
-                        if let Some(endpoint) = endpoint.upgrade() {
-                            let a = jack.connection_point();
-                            let b = endpoint.connection_point();
-                            let min_z = a.z.min(b.z);
-                            ctx.draw_pipe(&[a.with_z(min_z), b.with_z(min_z)]);
+                for connection in jack.connections.borrow().iter() {
+                    match *connection {
+                        Connection::Head {
+                            ref endpoint,
+                        } => {
+                            if let Some(endpoint) = endpoint.upgrade() {
+                                let a = jack.connection_point();
+                                let b = endpoint.connection_point();
+                                let min_z = a.z.min(b.z);
+                                ctx.draw_pipe(&[a.with_z(min_z), b.with_z(min_z)]);
+                            }
                         }
+                        _ => {}
                     }
-                    Connection::Floating {
-                        pos,
-                    } => {
-                        ctx.draw_pipe(&[jack.connection_point().with_z(0.0), pos.with_z(0.0)]);
-                    }
-                    _ => {}
+                }
+                if let Some(pos) = jack.floating.get() {
+                    ctx.draw_pipe(&[jack.connection_point().with_z(0.0), pos.with_z(0.0)]);
+                }
+                // the module texture is only redrawn when dirty, so live buffer levels are drawn
+                // here instead
+                if let Some((len, capacity)) = jack.backend.fill() {
+                    ctx.draw_text(
+                        &format!("{}/{}", len, capacity),
+                        jack.connection_point() - Pt3::new(48.0, 8.0, 0.0),
+                        [0.6, 0.6, 0.6],
+                    );
                 }
             }
         }
@@ -6,6 +6,7 @@ pub mod geom;
 pub mod layout;
 pub mod menu;
 pub mod module_gui;
+pub mod patch;
 pub mod render;
 pub mod root;
 pub mod textbox;
@@ -1,4 +1,4 @@
-use gui::{button::*, component::*, connect::*, event::*, geom::*, layout, render::*};
+use gui::{button::*, component::*, connect::*, event::*, geom::*, layout, patch::*, render::*};
 use module::*;
 
 use futures::executor::ThreadPool;
@@ -45,6 +45,12 @@ impl<T: Module + 'static> GuiModuleFactory for BasicGuiModuleFactory<T> {
 pub type BodyUpdate = bool;
 pub trait ModuleGui {
     fn new_body(&mut self, ctx: &mut RenderContext, bounds: Box3) -> Box<dyn GuiComponent<BodyUpdate>>;
+    /// The graph inside a module that wraps a patch of its own, such as a macro.
+    fn inner_graph(&self) -> Option<Arc<flow::Graph>>;
+    /// Ports inside the module's patch that are exposed as its own ports, by node and port name.
+    fn exposed(&self) -> Vec<(flow::NodeId, String)>;
+    /// Expose a port inside the module's patch, or stop exposing it if it already is.
+    fn toggle_exposed(&mut self, port: &Arc<flow::OpaquePort>);
 }
 impl<T> ModuleGui for T {
     default fn new_body(
@@ -54,11 +60,19 @@ impl<T> ModuleGui for T {
     ) -> Box<dyn GuiComponent<BodyUpdate>> {
         Box::new(NullComponent {})
     }
+    default fn inner_graph(&self) -> Option<Arc<flow::Graph>> {
+        None
+    }
+    default fn exposed(&self) -> Vec<(flow::NodeId, String)> {
+        Vec::new()
+    }
+    default fn toggle_exposed(&mut self, port: &Arc<flow::OpaquePort>) {}
 }
 
 const TITLE_BAR_HEIGHT: f32 = 24.0;
 const BORDER_SIZE: f32 = 1.0;
 const JACK_HEIGHT: f32 = 20.0;
+const DOUBLE_CLICK_TIME: f32 = 0.4;
 
 struct NullComponent {}
 impl<T: Default> GuiComponent<T> for NullComponent {
@@ -78,20 +92,27 @@ impl<T: Default> GuiComponent<T> for NullComponent {
 pub struct GuiModuleWrapper<T: Module + 'static> {
     module: T,
     node: Arc<flow::Node>,
+    graph: Arc<flow::Graph>,
 
     target: TextureTarget,
     body: Box<dyn GuiComponent<BodyUpdate>>,
 
     delete_button: Button,
+    jack_ctx: Rc<JackContext<Arc<flow::OpaquePort>>>,
     jacks: Vec<Rc<Jack<Arc<flow::OpaquePort>>>>,
+    patch: Option<Patch>,
     bounds: Box3,
     drag: Option<Pt2>,
+    last_click: Option<f32>,
     dirty: bool,
 }
 
+/// Closing a module in the GUI removes it from the graph entirely.
 impl<T: Module + 'static> Drop for GuiModuleWrapper<T> {
     fn drop(&mut self) {
         self.module.stop();
+        // disconnects all ports and fails anything the module was still waiting on
+        let _ = self.graph.remove_node(self.node.id());
     }
 }
 
@@ -115,45 +136,24 @@ impl<T: Module + 'static> GuiModuleWrapper<T> {
         let mut module = T::new(ifc);
         let ports = module.ports();
 
-        // Bounds pos is relative to the window, so we drop it and keep just the size,
-        // for the purposes of the layout solver
-        let mut solver = layout::Layout::new(Box3::new(0.0.into(), bounds.size));
-
-        // set up two main areas, for the jacks and the body
-        let jack_area = solver.add_node();
-        let body_area = solver.add_node();
-        solver.stack(layout::Axis::Y, &[jack_area, body_area]);
-        solver.suggest(
-            jack_area,
-            layout::Field::Height,
-            ports.len() as f64 * JACK_HEIGHT as f64,
-            layout::REQUIRED,
-        );
-        solver.suggest(
-            jack_area,
-            layout::Field::Y,
-            TITLE_BAR_HEIGHT as f64,
-            layout::REQUIRED,
-        );
-
-        // stack all jacks vertically inside the jack area
-        let jack_layouts = solver.add_nodes(ports.len());
-        solver.equalize(layout::Field::Height, &jack_layouts, layout::REQUIRED);
-        solver.stack(layout::Axis::Y, &jack_layouts);
-        solver.insert_inside(jack_area, &jack_layouts);
+        let (jack_bounds, body_bounds) = layout_areas(bounds, ports.len());
         let jacks: Vec<_> = ports
             .iter()
-            .zip(jack_layouts)
-            .map(|(port, layout)| jack_ctx.new_jack(port.clone(), solver.query(layout), bounds.pos))
+            .zip(jack_bounds)
+            .map(|(port, jack_bounds)| jack_ctx.new_jack(port.clone(), jack_bounds, bounds.pos))
             .collect();
 
-        let body = module.new_body(&mut ctx, solver.query(body_area));
+        let body = module.new_body(&mut ctx, body_bounds);
+        let patch = module
+            .inner_graph()
+            .map(|inner| Patch::new(ctx.clone(), Box3::default(), inner, executor.clone()));
 
         module.start(executor);
 
         GuiModuleWrapper {
             module,
             node,
+            graph,
             target,
             body,
             delete_button: Button::new(
@@ -164,12 +164,28 @@ impl<T: Module + 'static> GuiModuleWrapper<T> {
                     size: Pt3::new(TITLE_BAR_HEIGHT, TITLE_BAR_HEIGHT, 0.0),
                 },
             ),
+            jack_ctx,
             jacks,
+            patch,
             bounds,
             drag: None,
+            last_click: None,
             dirty: true,
         }
     }
+    /// Replace the jacks, after the module's ports have changed.
+    fn rebuild_jacks(&mut self) {
+        let ports = self.module.ports();
+        let (jack_bounds, body_bounds) = layout_areas(self.bounds, ports.len());
+        let jacks: Vec<_> = ports
+            .iter()
+            .zip(jack_bounds)
+            .map(|(port, jack_bounds)| self.jack_ctx.new_jack(port.clone(), jack_bounds, self.bounds.pos))
+            .collect();
+        self.jacks = jacks;
+        self.body.set_bounds(body_bounds);
+        self.dirty = true;
+    }
     fn render_self(&mut self, device: &mut gl::Device) {
         // borders
         self.target.ctx().draw_rect(
@@ -214,10 +230,49 @@ impl<T: Module + 'static> GuiModuleWrapper<T> {
     }
 }
 
+/// Find the bounds of each jack and of the body of a module, relative to the module.
+fn layout_areas(bounds: Box3, n_jacks: usize) -> (Vec<Box3>, Box3) {
+    // Bounds pos is relative to the window, so we drop it and keep just the size,
+    // for the purposes of the layout solver
+    let mut solver = layout::Layout::new(Box3::new(0.0.into(), bounds.size));
+
+    // set up two main areas, for the jacks and the body
+    let jack_area = solver.add_node();
+    let body_area = solver.add_node();
+    solver.stack(layout::Axis::Y, &[jack_area, body_area]);
+    solver.suggest(
+        jack_area,
+        layout::Field::Height,
+        n_jacks as f64 * JACK_HEIGHT as f64,
+        layout::REQUIRED,
+    );
+    solver.suggest(
+        jack_area,
+        layout::Field::Y,
+        TITLE_BAR_HEIGHT as f64,
+        layout::REQUIRED,
+    );
+
+    // stack all jacks vertically inside the jack area
+    let jack_layouts = solver.add_nodes(n_jacks);
+    solver.equalize(layout::Field::Height, &jack_layouts, layout::REQUIRED);
+    solver.stack(layout::Axis::Y, &jack_layouts);
+    solver.insert_inside(jack_area, &jack_layouts);
+    let jacks = jack_layouts.into_iter().map(|layout| solver.query(layout)).collect();
+    (jacks, solver.query(body_area))
+}
+
 pub trait GuiModule: GuiComponent<GuiModuleUpdate> {
     fn node(&self) -> Arc<flow::Node>;
     fn name(&self) -> &'static str;
     fn jacks(&self) -> &[Rc<Jack<Arc<flow::OpaquePort>>>];
+    /// The patch inside a macro module.
+    fn inner(&self) -> Option<&Patch>;
+    fn inner_mut(&mut self) -> Option<&mut Patch>;
+    fn exposed(&self) -> Vec<(flow::NodeId, String)>;
+    fn toggle_exposed(&mut self, port: &Arc<flow::OpaquePort>);
+    fn save_state(&self) -> Option<String>;
+    fn load_state(&mut self, state: &str);
 }
 
 impl<T: Module> GuiModule for GuiModuleWrapper<T> {
@@ -230,11 +285,33 @@ impl<T: Module> GuiModule for GuiModuleWrapper<T> {
     fn jacks(&self) -> &[Rc<Jack<Arc<flow::OpaquePort>>>] {
         &self.jacks
     }
+    fn inner(&self) -> Option<&Patch> {
+        self.patch.as_ref()
+    }
+    fn inner_mut(&mut self) -> Option<&mut Patch> {
+        self.patch.as_mut()
+    }
+    fn exposed(&self) -> Vec<(flow::NodeId, String)> {
+        ModuleGui::exposed(&self.module)
+    }
+    fn toggle_exposed(&mut self, port: &Arc<flow::OpaquePort>) {
+        ModuleGui::toggle_exposed(&mut self.module, port);
+        self.rebuild_jacks();
+    }
+    fn save_state(&self) -> Option<String> {
+        self.module.save_state()
+    }
+    fn load_state(&mut self, state: &str) {
+        self.module.load_state(state);
+        self.dirty = true;
+    }
 }
 
 pub enum GuiModuleUpdate {
     Unchanged,
     Closed,
+    /// The title bar was double-clicked on a module with a patch inside.
+    Enter,
 }
 
 impl<T> GuiComponent<GuiModuleUpdate> for GuiModuleWrapper<T>
@@ -288,6 +365,15 @@ where
                                 let mut title_rect = self.bounds.flatten().drop_z();
                                 title_rect.size = Pt2::new(title_rect.size.x, TITLE_BAR_HEIGHT + BORDER_SIZE);
                                 if title_rect.intersect(pos) {
+                                    let double_click = self
+                                        .last_click
+                                        .map(|time| event.time - time < DOUBLE_CLICK_TIME)
+                                        .unwrap_or(false);
+                                    if double_click && self.patch.is_some() {
+                                        self.last_click = None;
+                                        return GuiModuleUpdate::Enter;
+                                    }
+                                    self.last_click = Some(event.time);
                                     self.drag = Some(pos - origin);
                                 }
                             }
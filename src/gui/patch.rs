@@ -0,0 +1,353 @@
+//! A patch is the editable view of a graph: its modules and the wires between them
+
+use gui::{component::*, connect::*, event::*, geom::*, module_gui::*, render::*};
+use module::flow;
+use serial;
+
+use futures::channel::mpsc::UnboundedReceiver;
+use futures::executor::ThreadPool;
+use gfx_device_gl as gl;
+
+use std::cmp::Ordering;
+use std::rc::Rc;
+use std::sync::Arc;
+
+pub struct Patch {
+    graph: Arc<flow::Graph>,
+    graph_events: UnboundedReceiver<flow::GraphEvent>,
+    bounds: Box3,
+
+    ctx: RenderContext,
+    modules: Vec<Box<dyn GuiModule>>,
+    jack_ctx: Rc<JackContext<Arc<flow::OpaquePort>>>,
+    executor: ThreadPool,
+}
+
+pub enum PatchUpdate {
+    Unchanged,
+    /// The user asked to edit the patch inside a macro module.
+    Enter(flow::NodeId),
+}
+
+impl Patch {
+    pub fn new(ctx: RenderContext, bounds: Box3, graph: Arc<flow::Graph>, executor: ThreadPool) -> Patch {
+        Patch {
+            graph_events: graph.subscribe(),
+            graph,
+            bounds,
+            modules: Vec::new(),
+            jack_ctx: JackContext::new(bounds),
+            executor,
+
+            ctx,
+        }
+    }
+
+    pub fn graph(&self) -> &Arc<flow::Graph> {
+        &self.graph
+    }
+
+    pub fn new_module(
+        &mut self,
+        factory: &mut dyn GuiModuleFactory,
+        bounds: Box3,
+        node_id: Option<flow::NodeId>,
+    ) -> flow::NodeId {
+        let module = factory.new(GuiModuleConfig {
+            bounds,
+            jack_ctx: Rc::clone(&self.jack_ctx),
+            graph: Arc::clone(&self.graph),
+            ctx: self.ctx.clone(),
+            executor: self.executor.clone(),
+            node_id,
+        });
+        let id = module.node().id();
+        self.modules.push(module);
+        id
+    }
+
+    pub fn module_mut(&mut self, id: flow::NodeId) -> Option<&mut Box<dyn GuiModule>> {
+        self.modules.iter_mut().find(|module| module.node().id() == id)
+    }
+
+    /// Find the port of the jack under a point.
+    pub fn port_at(&self, pos: Pt2) -> Option<Arc<flow::OpaquePort>> {
+        self.jack_ctx.jack_at(pos).map(|jack| jack.backend().clone())
+    }
+
+    fn find_port(&self, node: flow::NodeId, name: &str) -> Option<Arc<flow::OpaquePort>> {
+        self.graph
+            .node(node)
+            .and_then(|node| node.ports().into_iter().find(|port| port.name() == name))
+    }
+
+    /// Bring the wires up to date with any changes made to the graph.
+    fn handle_graph_events(&mut self) {
+        while let Ok(Some(event)) = self.graph_events.try_next() {
+            match event {
+                flow::GraphEvent::Connected(a, b) => self.jack_ctx.connected(a, b),
+                flow::GraphEvent::Disconnected(a, b) => self.jack_ctx.disconnected(a, b),
+                _ => {}
+            }
+        }
+    }
+
+    fn compare_node_z(a: &Box<dyn GuiModule>, b: &Box<dyn GuiModule>) -> Ordering {
+        let a_z = a.bounds().pos.z;
+        let b_z = b.bounds().pos.z;
+        a_z.partial_cmp(&b_z).unwrap()
+    }
+
+    pub fn move_to_front(&mut self, id: flow::NodeId) {
+        self.modules.sort_by(|a, b| {
+            // force given id to front
+            if a.node().id() == id {
+                Ordering::Less
+            } else if b.node().id() == id {
+                Ordering::Greater
+            } else {
+                Self::compare_node_z(a, b)
+            }
+        });
+        let max = self.modules.len() as f32;
+        for (idx, module) in self.modules.iter_mut().enumerate() {
+            let mut bounds = module.bounds();
+            bounds.pos.z = idx as f32 / max;
+            bounds.size.z = 1.0 / max;
+            module.set_bounds(bounds);
+        }
+    }
+
+    /// Save the patch, along with the audio settings that it and every patch inside it run at.
+    pub fn save(&self) -> serial::Root {
+        serial::Root {
+            audio_config: Some(self.graph.audio_config()),
+            ..self.save_patch()
+        }
+    }
+
+    // inner patches run at the settings of the outermost one, so only that one saves them
+    fn save_patch(&self) -> serial::Root {
+        use std::collections::HashSet;
+
+        let mut modules = Vec::new();
+        let mut connections = Vec::new();
+        // keep track of visited edges so we only serialize one direction of each connection
+        let mut visited_edges = HashSet::new();
+        let ids: HashSet<_> = self.modules.iter().map(|module| module.node().id()).collect();
+        for module in &self.modules {
+            let bounds = module.bounds();
+            let node = module.node();
+            let exposed = module
+                .exposed()
+                .into_iter()
+                .map(|(node, port)| serial::Exposed {
+                    node,
+                    port,
+                })
+                .collect();
+            modules.push(serial::Module {
+                bounds,
+                id: node.id(),
+                type_name: module.name().into(),
+                inner: module.inner().map(|patch| patch.save_patch()),
+                exposed,
+                state: module.save_state(),
+            });
+
+            for port in node.ports() {
+                for dst in port.edges() {
+                    // skip the inner ends of exposed ports, which have no module
+                    if !ids.contains(&dst.node_id()) {
+                        continue;
+                    }
+                    let src_key = (port.node_id(), port.id());
+                    let dst_key = (dst.node_id(), dst.id());
+                    if !visited_edges.contains(&(dst_key, src_key)) {
+                        visited_edges.insert((src_key, dst_key));
+                        let connection = serial::Connection {
+                            src_node: node.id(),
+                            src_port: port.name().into(),
+                            dst_node: dst.node_id(),
+                            dst_port: dst.name().into(),
+                            data_type: Some(port.port_type().to_string()),
+                        };
+                        connections.push(connection);
+                    }
+                }
+            }
+        }
+        serial::Root {
+            modules,
+            connections,
+            audio_config: None,
+        }
+    }
+
+    /// Add the modules and connections of a saved patch, creating modules with the given factories.
+    pub fn load(&mut self, factories: &mut [Box<dyn GuiModuleFactory>], root: serial::Root) {
+        if let Some(config) = root.audio_config {
+            self.graph.set_audio_config(config);
+        }
+        self.load_patch(factories, root);
+    }
+
+    // like `load`, but leaves the audio settings to the outermost patch
+    fn load_patch(&mut self, factories: &mut [Box<dyn GuiModuleFactory>], root: serial::Root) {
+        for module in root.modules {
+            let id = match factories.iter_mut().find(|ty| ty.name() == module.type_name) {
+                Some(factory) => self.new_module(factory.as_mut(), module.bounds, Some(module.id)),
+                None => {
+                    println!("Error creating module {:?}", module.type_name);
+                    continue;
+                }
+            };
+            if let Some(ref state) = module.state {
+                self.module_mut(id).unwrap().load_state(state);
+            }
+            if let Some(inner) = module.inner {
+                let gui = self.module_mut(id).unwrap();
+                // the inner patch has to exist before its ports can be exposed
+                let ports: Vec<_> = match gui.inner_mut() {
+                    Some(patch) => {
+                        patch.load_patch(factories, inner);
+                        module
+                            .exposed
+                            .iter()
+                            .filter_map(|exposed| patch.find_port(exposed.node, &exposed.port))
+                            .collect()
+                    }
+                    None => {
+                        println!("Module {:?} has no inner patch", module.type_name);
+                        Vec::new()
+                    }
+                };
+                for port in ports {
+                    gui.toggle_exposed(&port);
+                }
+            }
+        }
+
+        for connection in root.connections {
+            let src_node = self
+                .modules
+                .iter()
+                .find(|module| module.node().id() == connection.src_node);
+            let dst_node = self
+                .modules
+                .iter()
+                .find(|module| module.node().id() == connection.dst_node);
+            // either module may have been skipped above
+            let (src_node, dst_node) = match (src_node, dst_node) {
+                (Some(src_node), Some(dst_node)) => (src_node, dst_node),
+                _ => {
+                    println!(
+                        "Could not find port(s) needed to connect {:?}:{:?} and {:?}:{:?}",
+                        connection.src_node, connection.src_port, connection.dst_node, connection.dst_port
+                    );
+                    continue;
+                }
+            };
+            let src_jack = src_node
+                .jacks()
+                .iter()
+                .find(|jack| jack.name() == connection.src_port);
+            let dst_jack = dst_node
+                .jacks()
+                .iter()
+                .find(|jack| jack.name() == connection.dst_port);
+            if let (Some(src_jack), Some(dst_jack)) = (src_jack, dst_jack) {
+                if let Err(err) = src_jack.connect(dst_jack) {
+                    println!(
+                        "Could not connect {:?}:{:?} and {:?}:{:?}: {}",
+                        src_node.name(),
+                        connection.src_port,
+                        dst_node.name(),
+                        connection.dst_port,
+                        err
+                    );
+                }
+            } else {
+                println!(
+                    "Could not find port(s) needed to connect {:?}:{:?} and {:?}:{:?}",
+                    src_node.name(),
+                    connection.src_port,
+                    dst_node.name(),
+                    connection.dst_port
+                );
+            }
+        }
+    }
+}
+
+impl GuiComponent<PatchUpdate> for Patch {
+    fn set_bounds(&mut self, bounds: Box3) {
+        self.bounds = bounds;
+        self.jack_ctx.set_bounds(bounds);
+    }
+    fn bounds(&self) -> Box3 {
+        self.bounds
+    }
+    fn intersect(&self, pos: Pt2) -> bool {
+        self.bounds.flatten().drop_z().intersect(pos)
+    }
+    fn render(&mut self, device: &mut gl::Device, ctx: &mut RenderContext) {
+        self.handle_graph_events();
+
+        // render nodes
+        for module in &mut self.modules {
+            module.render(device, ctx);
+        }
+
+        // render wires
+        self.jack_ctx.render(device, ctx);
+    }
+    /// Events arriving without focus have already been handled by something in front of the
+    /// patch.
+    fn handle(&mut self, event: &Event) -> PatchUpdate {
+        self.handle_graph_events();
+        match event.data {
+            EventData::Key(_) | EventData::Character(_) => {
+                for module in &mut self.modules {
+                    module.handle(&event.with_focus(true));
+                }
+                PatchUpdate::Unchanged
+            }
+            EventData::MouseMove(pos) | EventData::Click(pos, _, _) => {
+                // march from front to back, if we hit something set this flag so that we only send
+                // one event with focus
+                let mut hit = !event.focus;
+
+                // intersect nodes
+                let mut hit_module = None;
+                for (idx, module) in self.modules.iter_mut().enumerate() {
+                    if !hit && module.intersect(pos) {
+                        hit = true;
+                        hit_module = Some(idx);
+                    } else {
+                        // assume unfocused events are boring
+                        module.handle(&event.with_focus(false));
+                    }
+                }
+                if let Some(idx) = hit_module {
+                    let status = self.modules[idx].handle(&event.with_focus(true));
+                    if let EventData::Click(_, _, _) = event.data {
+                        match status {
+                            GuiModuleUpdate::Closed => {
+                                self.modules.remove(idx);
+                            }
+                            GuiModuleUpdate::Enter => {
+                                return PatchUpdate::Enter(self.modules[idx].node().id());
+                            }
+                            GuiModuleUpdate::Unchanged => {
+                                let id = self.modules[idx].node().id();
+                                self.move_to_front(id);
+                            }
+                        }
+                    }
+                }
+                PatchUpdate::Unchanged
+            }
+        }
+    }
+}
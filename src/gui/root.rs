@@ -1,66 +1,47 @@
 //! Root component that holds the application
 
-use gui::{component::*, connect::*, event::*, geom::*, menu::*, module_gui::*, render::*};
-use module::flow;
+use gui::{component::*, event::*, geom::*, menu::*, module_gui::*, patch::*, render::*};
+use module::{self, flow};
+use serial;
 
 use futures::executor::ThreadPool;
 use gfx_device_gl as gl;
 use ron;
 
-use std::cmp::Ordering;
 use std::fs::File;
-use std::rc::Rc;
-use std::sync::Arc;
 
 pub struct Root {
-    graph: Arc<flow::Graph>,
+    /// the top level patch
+    patch: Patch,
+    /// the macro modules entered to reach the patch being edited, outermost first
+    path: Vec<flow::NodeId>,
     bounds: Box3,
 
     ctx: RenderContext,
-    modules: Vec<Box<dyn GuiModule>>,
     module_types: Vec<Box<dyn GuiModuleFactory>>,
     context_menu: Option<MenuView>,
-    jack_ctx: Rc<JackContext<Arc<flow::OpaquePort>>>,
-    executor: ThreadPool,
 }
 
 impl Root {
     pub fn new(ctx: RenderContext, bounds: Box3) -> Root {
+        let graph = flow::Graph::new();
+        let executor = ThreadPool::new().unwrap();
+        graph.set_executor(executor.clone());
+        module::register_adapters(&graph);
         Root {
-            graph: flow::Graph::new(),
+            patch: Patch::new(ctx.clone(), bounds, graph, executor),
+            path: Vec::new(),
             bounds,
-            modules: Vec::new(),
             module_types: load_metamodules(),
             context_menu: None,
-            jack_ctx: JackContext::new(bounds),
-            executor: ThreadPool::new().unwrap(),
 
             ctx,
         }
     }
 
-    fn new_module(
-        &mut self,
-        name: &str,
-        bounds: Box3,
-        node_id: Option<flow::NodeId>,
-    ) -> Result<flow::NodeId, ()> {
-        // dummy z, overwritten by move_to_front
-        if let Some(factory) = self.module_types.iter_mut().find(|ty| ty.name() == name) {
-            let module = factory.new(GuiModuleConfig {
-                bounds,
-                jack_ctx: Rc::clone(&self.jack_ctx),
-                graph: Arc::clone(&self.graph),
-                ctx: self.ctx.clone(),
-                executor: self.executor.clone(),
-                node_id,
-            });
-            let id = module.node().id();
-            self.modules.push(module);
-            Ok(id)
-        } else {
-            Err(())
-        }
+    /// The patch being edited.
+    fn current(&mut self) -> &mut Patch {
+        resolve(&mut self.patch, &self.path).expect("entered a module without a patch")
     }
 
     fn open_new_module_menu(&mut self, pos: Pt2) {
@@ -77,71 +58,37 @@ impl Root {
         ));
     }
 
-    fn compare_node_z(a: &Box<dyn GuiModule>, b: &Box<dyn GuiModule>) -> Ordering {
-        let a_z = a.bounds().pos.z;
-        let b_z = b.bounds().pos.z;
-        a_z.partial_cmp(&b_z).unwrap()
+    fn enter(&mut self, id: flow::NodeId) {
+        self.path.push(id);
+        self.context_menu = None;
+        let bounds = self.bounds;
+        self.current().set_bounds(bounds);
+    }
+
+    fn leave(&mut self) {
+        self.path.pop();
+        self.context_menu = None;
+        let bounds = self.bounds;
+        self.current().set_bounds(bounds);
     }
 
-    fn move_to_front(&mut self, id: flow::NodeId) {
-        self.modules.sort_by(|a, b| {
-            // force given id to front
-            if a.node().id() == id {
-                Ordering::Less
-            } else if b.node().id() == id {
-                Ordering::Greater
-            } else {
-                Self::compare_node_z(a, b)
+    /// Expose the port under a point as a port of the macro being edited, or stop exposing it.
+    fn toggle_exposed(&mut self, pos: Pt2) {
+        let path = self.path.clone();
+        if let Some((&id, parent_path)) = path.split_last() {
+            if let Some(port) = self.current().port_at(pos) {
+                let parent = resolve(&mut self.patch, parent_path).unwrap();
+                if let Some(module) = parent.module_mut(id) {
+                    module.toggle_exposed(&port);
+                }
             }
-        });
-        let max = self.modules.len() as f32;
-        for (idx, module) in self.modules.iter_mut().enumerate() {
-            let mut bounds = module.bounds();
-            bounds.pos.z = idx as f32 / max;
-            bounds.size.z = 1.0 / max;
-            module.set_bounds(bounds);
         }
     }
 
     fn save(&self, filename: &str) -> Result<(), serial::Error> {
-        use std::collections::HashSet;
         use std::io::prelude::*;
 
-        let mut modules = Vec::new();
-        let mut connections = Vec::new();
-        // keep track of visited ports so we only serialize one end of the connection
-        let mut visited_ports = HashSet::new();
-        for module in &self.modules {
-            let bounds = module.bounds();
-            let node = module.node();
-            let module = serial::Module {
-                bounds,
-                id: node.id(),
-                type_name: module.name().into(),
-            };
-            modules.push(module);
-
-            for port in node.ports() {
-                visited_ports.insert((port.node_id(), port.id()));
-                if let Some(dst) = port.edge() {
-                    if !visited_ports.contains(&(dst.node_id(), dst.id())) {
-                        visited_ports.insert((dst.node_id(), dst.id()));
-                        let connection = serial::Connection {
-                            src_node: node.id(),
-                            src_port: port.name().into(),
-                            dst_node: dst.node_id(),
-                            dst_port: dst.name().into(),
-                        };
-                        connections.push(connection);
-                    }
-                }
-            }
-        }
-        let root = serial::Root {
-            modules,
-            connections,
-        };
-
+        let root = self.patch.save();
         let data = ron::ser::to_string(&root).unwrap();
         let mut file = File::create(filename)?;
         write!(file, "{}", data)?;
@@ -155,94 +102,27 @@ impl Root {
 
         let file = File::open(filename)?;
         let root: serial::Root = ron::de::from_reader(file)?;
-
-        for module in root.modules {
-            if let Err(_) = self.new_module(&module.type_name, module.bounds, Some(module.id)) {
-                println!("Error creating module {:?}", module.type_name);
-            }
-        }
-
-        for connection in root.connections {
-            let src_node = self
-                .modules
-                .iter()
-                .find(|module| module.node().id() == connection.src_node)
-                .unwrap();
-            let dst_node = self
-                .modules
-                .iter()
-                .find(|module| module.node().id() == connection.dst_node)
-                .unwrap();
-            let src_jack = src_node
-                .jacks()
-                .iter()
-                .find(|jack| jack.name() == connection.src_port);
-            let dst_jack = dst_node
-                .jacks()
-                .iter()
-                .find(|jack| jack.name() == connection.dst_port);
-            if let (Some(src_jack), Some(dst_jack)) = (src_jack, dst_jack) {
-                src_jack.connect(dst_jack);
-            } else {
-                println!(
-                    "Could not find port(s) needed to connect {:?}:{:?} and {:?}:{:?}",
-                    src_node.name(),
-                    connection.src_port,
-                    dst_node.name(),
-                    connection.dst_port
-                );
-            }
-        }
+        self.patch.load(&mut self.module_types, root);
 
         Ok(())
     }
 }
 
-mod serial {
-    use gui::geom::*;
-    use module::flow::NodeId;
-    use ron;
-    use std::io;
-
-    #[derive(Debug)]
-    pub enum Error {
-        IO(io::Error),
-        Serialize(ron::ser::Error),
-    }
-    impl From<io::Error> for Error {
-        fn from(e: io::Error) -> Error {
-            Error::IO(e)
-        }
-    }
-    impl From<ron::ser::Error> for Error {
-        fn from(e: ron::ser::Error) -> Error {
-            Error::Serialize(e)
-        }
-    }
-
-    #[derive(Debug, Serialize, Deserialize)]
-    pub struct Root {
-        pub modules: Vec<Module>,
-        pub connections: Vec<Connection>,
-    }
-    #[derive(Debug, Serialize, Deserialize)]
-    pub struct Module {
-        pub bounds: Box3,
-        pub id: NodeId,
-        pub type_name: String,
-    }
-    #[derive(Debug, Serialize, Deserialize)]
-    pub struct Connection {
-        pub src_node: NodeId,
-        pub src_port: String,
-        pub dst_node: NodeId,
-        pub dst_port: String,
+/// Follow a path of macro modules down from a patch.
+fn resolve<'a>(patch: &'a mut Patch, path: &[flow::NodeId]) -> Option<&'a mut Patch> {
+    match path.split_first() {
+        Some((&id, rest)) => patch
+            .module_mut(id)
+            .and_then(|module| module.inner_mut())
+            .and_then(|inner| resolve(inner, rest)),
+        None => Some(patch),
     }
 }
 
 impl GuiComponent for Root {
     fn set_bounds(&mut self, bounds: Box3) {
         self.bounds = bounds;
+        self.current().set_bounds(bounds);
     }
     fn bounds(&self) -> Box3 {
         self.bounds
@@ -251,18 +131,26 @@ impl GuiComponent for Root {
         self.bounds.flatten().drop_z().intersect(pos)
     }
     fn render(&mut self, device: &mut gl::Device, ctx: &mut RenderContext) {
-        // render nodes
-        for module in &mut self.modules {
-            module.render(device, ctx);
-        }
+        // render nodes and wires
+        self.current().render(device, ctx);
 
         // render global widgets
         if let Some(menu) = self.context_menu.as_mut() {
             menu.render(device, ctx);
         }
 
-        // render wires
-        self.jack_ctx.render(device, ctx);
+        // show which macro is being edited
+        if !self.path.is_empty() {
+            let path: Vec<_> = self.path.iter().map(|id| format!("Macro {}", id.0)).collect();
+            ctx.draw_text(
+                &format!(
+                    "{} (Esc to leave, middle click a jack to expose it)",
+                    path.join(" > ")
+                ),
+                Pt3::new(8.0, 8.0, 0.0),
+                [0.6, 0.6, 0.6],
+            );
+        }
     }
     fn handle(&mut self, event: &Event) {
         match event.data {
@@ -292,10 +180,16 @@ impl GuiComponent for Root {
             }) => {
                 println!("Load: {:?}", self.load("project.fsy"));
             }
+            EventData::Key(KeyEvent {
+                code: VirtualKeyCode::Escape,
+                state: ButtonState::Pressed,
+                ..
+            }) if !self.path.is_empty() =>
+            {
+                self.leave();
+            }
             EventData::Key(_) | EventData::Character(_) => {
-                for module in &mut self.modules {
-                    module.handle(&event.with_focus(true));
-                }
+                self.current().handle(event);
             }
             EventData::MouseMove(pos) | EventData::Click(pos, _, _) => {
                 // march from front to back, if we hit something set this flag so that we only send
@@ -311,8 +205,14 @@ impl GuiComponent for Root {
                             MenuUpdate::Select(path) => {
                                 let name: &str = path[0].as_ref();
                                 let bounds = Box3::new(pos.with_z(0.0), Pt2::from(256.0).with_z(0.0));
-                                let id = self.new_module(name, bounds, None).unwrap();
-                                self.move_to_front(id);
+                                let patch = resolve(&mut self.patch, &self.path).unwrap();
+                                let factory = self
+                                    .module_types
+                                    .iter_mut()
+                                    .find(|ty| ty.name() == name)
+                                    .unwrap();
+                                let id = patch.new_module(factory.as_mut(), bounds, None);
+                                patch.move_to_front(id);
                                 self.context_menu = None;
                             }
                             _ => (),
@@ -324,32 +224,16 @@ impl GuiComponent for Root {
                 }
 
                 // intersect nodes
-                let mut hit_module = None;
-                for (idx, module) in self.modules.iter_mut().enumerate() {
-                    if !hit && module.intersect(pos) {
-                        hit = true;
-                        hit_module = Some(idx);
-                    } else {
-                        // assume unfocused events are boring
-                        module.handle(&event.with_focus(false));
-                    }
-                }
-                if let Some(idx) = hit_module {
-                    let status = self.modules[idx].handle(&event.with_focus(true));
-                    if let EventData::Click(_, _, _) = event.data {
-                        match status {
-                            GuiModuleUpdate::Closed => {
-                                self.modules.remove(idx);
-                            }
-                            _ => {
-                                let id = self.modules[idx].node().id();
-                                self.move_to_front(id);
-                            }
-                        }
-                    }
+                if let PatchUpdate::Enter(id) = self.current().handle(&event.with_focus(!hit)) {
+                    self.enter(id);
+                    return;
                 }
 
                 if let EventData::Click(_, button, state) = event.data {
+                    // middle click inside a macro - expose a port
+                    if ButtonState::Pressed == state && MouseButton::Middle == button {
+                        self.toggle_exposed(pos);
+                    }
                     // right click - open menu
                     if ButtonState::Pressed == state && MouseButton::Right == button {
                         self.open_new_module_menu(pos);
@@ -370,13 +254,13 @@ impl GuiComponent for Root {
 }
 
 fn load_metamodules() -> Vec<Box<dyn GuiModuleFactory>> {
-    use module::audio_io::*;
-    use module::debug::*;
-    use module::livecode::*;
-    vec![
-        Box::new(BasicGuiModuleFactory::<Printer<i32>>::new()),
-        Box::new(BasicGuiModuleFactory::<Counter<i32>>::new()),
-        Box::new(BasicGuiModuleFactory::<AudioIO>::new()),
-        Box::new(BasicGuiModuleFactory::<LiveCode>::new()),
-    ]
+    struct Factories(Vec<Box<dyn GuiModuleFactory>>);
+    impl module::ModuleVisitor for Factories {
+        fn visit<T: module::Module + 'static>(&mut self) {
+            self.0.push(Box::new(BasicGuiModuleFactory::<T>::new()));
+        }
+    }
+    let mut factories = Factories(Vec::new());
+    module::visit_modules(&mut factories);
+    factories.0
 }
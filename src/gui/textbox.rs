@@ -21,8 +21,15 @@ impl TextBox {
             focused: false,
         }
     }
+    pub fn content(&self) -> &str {
+        &self.content
+    }
     pub fn set_content(&mut self, content: String) {
         self.content = content;
+        self.cursor = self.cursor.min(self.content.len());
+    }
+    pub fn focused(&self) -> bool {
+        self.focused
     }
     pub fn set_focused(&mut self, focused: bool) {
         self.focused = focused;
@@ -0,0 +1,363 @@
+//! Runs a saved patch without a window
+
+use module::offline::{OfflineConfig, OfflineRender};
+use module::{self, audio_io::AudioIO, flow, subgraph::Subgraph, DynModule, Module, ModuleVisitor};
+use serial;
+
+use futures::executor::ThreadPool;
+use libc;
+use ron;
+
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::fs::File;
+use std::path::PathBuf;
+use std::process;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How long stopping waits for each audio backend to close.
+const STOP_TIMEOUT_MILLIS: u64 = 2000;
+
+type ModuleFactory = Box<dyn Fn(Arc<flow::Interface>) -> Box<dyn DynModule>>;
+
+struct Loaded {
+    graph: Arc<flow::Graph>,
+    id: flow::NodeId,
+    module: Box<dyn DynModule>,
+}
+
+/// A patch that runs without the GUI. Modules are created from the same list as in the GUI.
+pub struct Headless {
+    graph: Arc<flow::Graph>,
+    executor: ThreadPool,
+    factories: HashMap<&'static str, ModuleFactory>,
+    modules: Vec<Loaded>,
+}
+
+impl Headless {
+    pub fn new() -> Headless {
+        struct Factories(HashMap<&'static str, ModuleFactory>);
+        impl ModuleVisitor for Factories {
+            fn visit<T: Module + 'static>(&mut self) {
+                self.0.insert(T::name(), Box::new(module::new_dyn::<T>));
+            }
+        }
+        let mut factories = Factories(HashMap::new());
+        module::visit_modules(&mut factories);
+
+        let graph = flow::Graph::new();
+        let executor = ThreadPool::new().unwrap();
+        graph.set_executor(executor.clone());
+        module::register_adapters(&graph);
+        Headless {
+            graph,
+            executor,
+            factories: factories.0,
+            modules: Vec::new(),
+        }
+    }
+
+    pub fn graph(&self) -> &Arc<flow::Graph> {
+        &self.graph
+    }
+
+    /// Create modules saved under the given type name with `factory` instead of the default.
+    pub fn set_factory<F>(&mut self, name: &'static str, factory: F)
+    where
+        F: Fn(Arc<flow::Interface>) -> Box<dyn DynModule> + 'static,
+    {
+        self.factories.insert(name, Box::new(factory));
+    }
+
+    /// Add the modules and connections of a saved patch.
+    pub fn load(&mut self, root: serial::Root) {
+        let graph = self.graph.clone();
+        if let Some(config) = root.audio_config {
+            graph.set_audio_config(config);
+        }
+        self.load_into(&graph, root);
+    }
+
+    // inner patches run at the audio settings of the outermost one, so theirs are ignored
+    fn load_into(&mut self, graph: &Arc<flow::Graph>, root: serial::Root) {
+        for module in root.modules {
+            let mut instance = match self.factories.get(module.type_name.as_str()) {
+                Some(factory) => factory(graph.add_node_with_id(module.id)),
+                None => {
+                    println!("Error creating module {:?}", module.type_name);
+                    continue;
+                }
+            };
+            if let Some(ref state) = module.state {
+                instance.load_state(state);
+            }
+            if let Some(inner) = module.inner {
+                match instance.as_any_mut().downcast_mut::<Subgraph>() {
+                    Some(subgraph) => {
+                        let inner_graph = subgraph.graph().clone();
+                        // the inner patch has to exist before its ports can be exposed
+                        self.load_into(&inner_graph, inner);
+                        for exposed in module.exposed {
+                            let port = inner_graph
+                                .node(exposed.node)
+                                .and_then(|node| node.find_port(&exposed.port));
+                            match port.map(|port| subgraph.expose(&port)) {
+                                Some(Ok(_)) => {}
+                                Some(Err(err)) => println!("Could not expose {:?}: {:?}", exposed.port, err),
+                                None => println!("Could not find exposed port {:?}", exposed.port),
+                            }
+                        }
+                    }
+                    None => println!("Module {:?} has no inner patch", module.type_name),
+                }
+            }
+            self.modules.push(Loaded {
+                graph: graph.clone(),
+                id: module.id,
+                module: instance,
+            });
+        }
+
+        for connection in root.connections {
+            let src = graph
+                .node(connection.src_node)
+                .and_then(|node| node.find_port(&connection.src_port));
+            let dst = graph
+                .node(connection.dst_node)
+                .and_then(|node| node.find_port(&connection.dst_port));
+            if let (Some(src), Some(dst)) = (src, dst) {
+                if let Err(err) = src.connect(&dst) {
+                    println!(
+                        "Could not connect {:?}:{:?} and {:?}:{:?}: {:?}",
+                        connection.src_node,
+                        connection.src_port,
+                        connection.dst_node,
+                        connection.dst_port,
+                        err
+                    );
+                }
+            } else {
+                println!(
+                    "Could not find port(s) needed to connect {:?}:{:?} and {:?}:{:?}",
+                    connection.src_node, connection.src_port, connection.dst_node, connection.dst_port
+                );
+            }
+        }
+    }
+
+    /// Start every loaded module.
+    pub fn start(&mut self) {
+        for loaded in &mut self.modules {
+            loaded.module.start(self.executor.clone());
+        }
+    }
+
+    /// Stop every loaded module and remove it from its graph. Audio backends get a moment to finish
+    /// what they are writing first.
+    pub fn stop(&mut self) {
+        for loaded in &mut self.modules {
+            loaded.module.stop();
+        }
+        for mut loaded in self.modules.drain(..) {
+            if let Some(audio_io) = loaded.module.as_any_mut().downcast_ref::<AudioIO>() {
+                if !audio_io.wait_stopped(Duration::from_millis(STOP_TIMEOUT_MILLIS)) {
+                    println!(
+                        "{} did not stop in time, so its output may be cut short",
+                        AudioIO::name()
+                    );
+                }
+            }
+            let _ = loaded.graph.remove_node(loaded.id);
+        }
+    }
+}
+
+const USAGE: &str = "usage: flow-synth --headless <file.fsy> [--duration <seconds>]
+       flow-synth --headless <file.fsy> --render <out.wav> [--length <seconds>] [--rate <hz>]
+                  [--block <samples>] [--input <in.wav>]";
+
+/// Entry point for `--headless <file.fsy> [--duration <seconds>]`. Without a duration, the patch runs
+/// until the process is interrupted. Either way, it is stopped before exiting.
+///
+/// With `--render <out.wav>`, every `AudioIO` module is replaced by an `OfflineRender`, and the patch
+/// is rendered as fast as possible for the given length instead.
+pub fn headless_main(args: &[String]) {
+    let usage = || {
+        println!("{}", USAGE);
+        process::exit(1);
+    };
+    let filename = match args.first() {
+        Some(filename) => filename,
+        None => return usage(),
+    };
+    let mut duration = None;
+    let mut render = false;
+    let mut config = OfflineConfig::default();
+    let mut length = 10.0;
+    let mut rest = args[1..].iter();
+    while let Some(flag) = rest.next() {
+        let value = match rest.next() {
+            Some(value) => value,
+            None => return usage(),
+        };
+        let ok = match flag.as_str() {
+            "--duration" => match value.parse::<f64>() {
+                Ok(seconds) if seconds >= 0.0 => {
+                    duration = Some(Duration::from_millis((seconds * 1000.0) as u64));
+                    true
+                }
+                _ => false,
+            },
+            "--render" => {
+                render = true;
+                config.output = PathBuf::from(value);
+                true
+            }
+            "--input" => {
+                config.input = Some(PathBuf::from(value));
+                true
+            }
+            "--length" => match value.parse::<f64>() {
+                Ok(seconds) if seconds >= 0.0 => {
+                    length = seconds;
+                    true
+                }
+                _ => false,
+            },
+            "--rate" => match value.parse::<u32>() {
+                Ok(rate) if rate > 0 => {
+                    config.rate = rate;
+                    true
+                }
+                _ => false,
+            },
+            "--block" => match value.parse::<usize>() {
+                Ok(block_size) if block_size > 0 => {
+                    config.block_size = block_size;
+                    true
+                }
+                _ => false,
+            },
+            _ => false,
+        };
+        if !ok {
+            return usage();
+        }
+    }
+
+    let root: serial::Root = match File::open(filename)
+        .map_err(ron::de::Error::from)
+        .and_then(ron::de::from_reader)
+    {
+        Ok(root) => root,
+        Err(err) => {
+            println!("Could not load {:?}: {:?}", filename, err);
+            process::exit(1);
+        }
+    };
+
+    let mut headless = Headless::new();
+    if render {
+        config.blocks = (length * config.rate as f64 / config.block_size as f64).ceil() as usize;
+        return render_main(headless, root, config);
+    }
+    catch_interrupts();
+    headless.load(root);
+    headless.start();
+    let deadline = duration.map(|duration| Instant::now() + duration);
+    while !INTERRUPTED.load(Ordering::SeqCst) && deadline.map_or(true, |deadline| Instant::now() < deadline) {
+        thread::sleep(Duration::from_millis(50));
+    }
+    // modules such as recording backends only finish their output once stopped
+    headless.stop();
+}
+
+/// Set once SIGINT or SIGTERM arrives, so the patch can be stopped cleanly.
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn interrupt(_: libc::c_int) {
+    INTERRUPTED.store(true, Ordering::SeqCst);
+}
+
+fn catch_interrupts() {
+    unsafe {
+        libc::signal(libc::SIGINT, interrupt as libc::sighandler_t);
+        libc::signal(libc::SIGTERM, interrupt as libc::sighandler_t);
+    }
+}
+
+/// Load a patch with its `AudioIO` modules replaced by renderers, and wait for all of them to finish.
+fn render_main(mut headless: Headless, root: serial::Root, config: OfflineConfig) {
+    let (done_tx, done_rx) = mpsc::channel();
+    let count = Rc::new(Cell::new(0));
+    {
+        let count = count.clone();
+        headless.set_factory(AudioIO::name(), move |ifc| {
+            let mut config = config.clone();
+            // every renderer after the first gets its own file
+            let n = count.get();
+            if n > 0 {
+                let name = match config.output.file_stem() {
+                    Some(stem) => format!("{}-{}.wav", stem.to_string_lossy(), n),
+                    None => format!("render-{}.wav", n),
+                };
+                config.output.set_file_name(name);
+            }
+            count.set(n + 1);
+            println!("Rendering to {:?}", config.output);
+            module::into_dyn(OfflineRender::with_config(ifc, config, Some(done_tx.clone())))
+        });
+    }
+    headless.load(root);
+    if count.get() == 0 {
+        println!("Nothing to render: the patch has no {} modules", AudioIO::name());
+        process::exit(1);
+    }
+    headless.start();
+    let mut failed = false;
+    for _ in 0..count.get() {
+        match done_rx.recv() {
+            Ok(Ok(())) => {}
+            Ok(Err(_)) | Err(_) => failed = true,
+        }
+    }
+    headless.stop();
+    if failed {
+        process::exit(1);
+    }
+}
+
+#[test]
+fn test_load() {
+    let root: serial::Root = ron::de::from_str(
+        r#"(
+            modules: [
+                (
+                    bounds: (pos: (x: 0.0, y: 0.0, z: 0.0), size: (x: 256.0, y: 256.0, z: 0.0)),
+                    id: (0),
+                    type_name: "Counter",
+                ),
+                (
+                    bounds: (pos: (x: 0.0, y: 0.0, z: 0.0), size: (x: 256.0, y: 256.0, z: 0.0)),
+                    id: (1),
+                    type_name: "Printer",
+                ),
+            ],
+            connections: [
+                (src_node: (0), src_port: "Output", dst_node: (1), dst_port: "Input"),
+            ],
+        )"#,
+    ).unwrap();
+    let mut headless = Headless::new();
+    headless.load(root);
+    assert_eq!(headless.graph().nodes().len(), 2);
+    let output = headless.graph().node(flow::NodeId(0)).unwrap().find_port("Output").unwrap();
+    let input = headless.graph().node(flow::NodeId(1)).unwrap().find_port("Input").unwrap();
+    assert!(Arc::ptr_eq(&output.edges()[0], &input));
+    headless.stop();
+    assert!(headless.graph().nodes().is_empty());
+}
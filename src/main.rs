@@ -24,6 +24,8 @@ extern crate gfx_glyph;
 extern crate gfx_window_glutin;
 extern crate glutin;
 extern crate jack;
+extern crate jack_sys;
+extern crate libc;
 extern crate ndarray;
 extern crate nfd;
 extern crate notify;
@@ -33,10 +35,25 @@ extern crate serde;
 #[macro_use]
 extern crate serde_derive;
 
+mod alloc_check;
 mod future_ext;
 mod gui;
+mod headless;
 mod module;
+mod serial;
+mod wav;
+
+use std::env;
+
+#[cfg(feature = "alloc-check")]
+#[global_allocator]
+static ALLOCATOR: alloc_check::CheckedAlloc = alloc_check::CheckedAlloc;
 
 fn main() {
-    gui::gui_main();
+    let args: Vec<String> = env::args().collect();
+    if args.get(1).map(|arg| arg == "--headless").unwrap_or(false) {
+        headless::headless_main(&args[2..]);
+    } else {
+        gui::gui_main();
+    }
 }
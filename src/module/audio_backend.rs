@@ -0,0 +1,356 @@
+//! Where an `AudioIO` module's audio comes from and goes to
+
+use module::audio_io::{AudioIOConfig, DataPath, JackBackend};
+use wav;
+
+use std::fs::File;
+use std::io::{BufReader, BufWriter};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// Moves audio between an `AudioIO` module and the outside world, by calling `DataPath::cycle` once
+/// per block from a thread of its own.
+pub trait AudioBackend: Send {
+    /// Start running. `path` must be called once the sample rate and block size are known, and
+    /// gives the data path to cycle. On failure, the reason is returned for display.
+    fn open(
+        &mut self,
+        config: &AudioIOConfig,
+        path: &mut dyn FnMut(f32, usize) -> DataPath,
+    ) -> Result<(), String>;
+    /// Stop running. The backend may be opened again afterwards.
+    fn close(&mut self);
+}
+
+/// Which backend an `AudioIO` module uses.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum BackendConfig {
+    /// A JACK client, set up by the rest of the `AudioIOConfig`.
+    Jack,
+    /// No audio device at all. Silence is captured and the output is thrown away, one block at a
+    /// time on a timer.
+    Null { rate: u32, block_size: usize },
+    /// Like `Null`, but the inputs are played from a WAV file and the outputs are recorded to another
+    /// one as they are played. Inputs are silent once the file runs out.
+    Wav {
+        input: Option<PathBuf>,
+        output: Option<PathBuf>,
+        rate: u32,
+        block_size: usize,
+    },
+}
+impl Default for BackendConfig {
+    fn default() -> BackendConfig {
+        BackendConfig::Jack
+    }
+}
+
+/// Timing of the timed backends, when not given.
+pub const DEFAULT_RATE: u32 = 48000;
+pub const DEFAULT_BLOCK_SIZE: usize = 256;
+
+/// Create the backend a config asks for. It isn't opened yet.
+pub fn new_backend(config: &BackendConfig) -> Box<dyn AudioBackend> {
+    match *config {
+        BackendConfig::Jack => Box::new(JackBackend::new()),
+        BackendConfig::Null {
+            rate,
+            block_size,
+        } => Box::new(NullBackend {
+            rate,
+            block_size,
+            running: None,
+        }),
+        BackendConfig::Wav {
+            ref input,
+            ref output,
+            rate,
+            block_size,
+        } => Box::new(WavBackend {
+            input: input.clone(),
+            output: output.clone(),
+            rate,
+            block_size,
+            running: None,
+        }),
+    }
+}
+
+/// The thread of a timed backend. It owns some state, which is handed back once it stops.
+struct Running<S> {
+    stop: Arc<AtomicBool>,
+    thread: JoinHandle<S>,
+}
+impl<S: Send + 'static> Running<S> {
+    /// Call `tick` once per block, paced in real time, until stopped. Blocks that start late are
+    /// counted as xruns.
+    fn spawn<F>(rate: u32, block_size: usize, mut path: DataPath, mut state: S, mut tick: F) -> Running<S>
+    where
+        F: FnMut(&mut S, &mut DataPath) + Send + 'static,
+    {
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread = {
+            let stop = stop.clone();
+            thread::spawn(move || {
+                let period = Duration::from_nanos(block_size as u64 * 1_000_000_000 / u64::from(rate));
+                let mut deadline = Instant::now();
+                while !stop.load(Ordering::Relaxed) && !path.stopped() {
+                    tick(&mut state, &mut path);
+                    deadline += period;
+                    let now = Instant::now();
+                    if now < deadline {
+                        thread::sleep(deadline - now);
+                    } else {
+                        path.xrun();
+                        deadline = now;
+                    }
+                }
+                state
+            })
+        };
+        Running {
+            stop,
+            thread,
+        }
+    }
+    fn stop(self) -> Option<S> {
+        self.stop.store(true, Ordering::Relaxed);
+        self.thread.join().ok()
+    }
+}
+
+fn check_timing(rate: u32, block_size: usize) -> Result<(), String> {
+    if rate == 0 || block_size == 0 {
+        Err("the rate and block size must be more than zero".into())
+    } else {
+        Ok(())
+    }
+}
+
+/// Buffers for one block of each channel.
+fn buffers(channels: usize, block_size: usize) -> Vec<Vec<f32>> {
+    vec![vec![0.0; block_size]; channels]
+}
+
+/// Ticks on a timer without any audio device, for running patches where there is no sound card.
+pub struct NullBackend {
+    rate: u32,
+    block_size: usize,
+    running: Option<Running<()>>,
+}
+impl AudioBackend for NullBackend {
+    fn open(
+        &mut self,
+        config: &AudioIOConfig,
+        path: &mut dyn FnMut(f32, usize) -> DataPath,
+    ) -> Result<(), String> {
+        check_timing(self.rate, self.block_size)?;
+        let inputs = buffers(config.n_inputs, self.block_size);
+        let mut outputs = buffers(config.n_outputs, self.block_size);
+        let path = path(self.rate as f32, self.block_size);
+        self.running = Some(Running::spawn(self.rate, self.block_size, path, (), move |_, path| {
+            path.cycle(inputs.iter().map(|x| &x[..]), outputs.iter_mut().map(|x| &mut x[..]));
+        }));
+        Ok(())
+    }
+    fn close(&mut self) {
+        if let Some(running) = self.running.take() {
+            running.stop();
+        }
+    }
+}
+
+/// Ticks on a timer like `NullBackend`, playing and recording WAV files in place of an audio device.
+pub struct WavBackend {
+    input: Option<PathBuf>,
+    output: Option<PathBuf>,
+    rate: u32,
+    block_size: usize,
+    // the thread hands back the recording, if there is one, to be finished
+    running: Option<Running<Option<Recording>>>,
+}
+impl AudioBackend for WavBackend {
+    fn open(
+        &mut self,
+        config: &AudioIOConfig,
+        path: &mut dyn FnMut(f32, usize) -> DataPath,
+    ) -> Result<(), String> {
+        check_timing(self.rate, self.block_size)?;
+        let (file_channels, samples) = match self.input {
+            Some(ref input) => {
+                let (spec, samples) = File::open(input)
+                    .and_then(|file| wav::read(BufReader::new(file)))
+                    .map_err(|err| format!("Could not read {:?}: {}", input, err))?;
+                if spec.rate != self.rate {
+                    println!("{:?} has a sample rate of {}, but playing at {}", input, spec.rate, self.rate);
+                }
+                (spec.channels as usize, samples)
+            }
+            None => (0, Vec::new()),
+        };
+
+        let block_size = self.block_size;
+        let mut inputs = buffers(config.n_inputs, block_size);
+        let mut outputs = buffers(config.n_outputs, block_size);
+        let mut pos = 0;
+        let recording = match self.output {
+            Some(ref output) => {
+                let spec = wav::WavSpec {
+                    channels: config.n_outputs as u16,
+                    rate: self.rate,
+                };
+                let writer = File::create(output)
+                    .and_then(|file| wav::WavWriter::new(BufWriter::new(file), spec))
+                    .map_err(|err| format!("Could not write {:?}: {}", output, err))?;
+                Some(Recording {
+                    path: output.clone(),
+                    writer,
+                    block: Vec::with_capacity(block_size * config.n_outputs),
+                })
+            }
+            None => None,
+        };
+        let path = path(self.rate as f32, block_size);
+        self.running = Some(Running::spawn(self.rate, block_size, path, recording, move |recording, path| {
+            // the file is interleaved, and channels it doesn't have are silent
+            for (c, input) in inputs.iter_mut().enumerate() {
+                for (i, sample) in input.iter_mut().enumerate() {
+                    *sample = match (pos + i) * file_channels + c {
+                        idx if c < file_channels && idx < samples.len() => samples[idx],
+                        _ => 0.0,
+                    };
+                }
+            }
+            pos += block_size;
+            path.cycle(inputs.iter().map(|x| &x[..]), outputs.iter_mut().map(|x| &mut x[..]));
+            // a failed write ends the recording, but not the playback
+            let failed = match *recording {
+                Some(ref mut recording) => {
+                    recording.block.clear();
+                    for i in 0..block_size {
+                        recording.block.extend(outputs.iter().map(|output| output[i]));
+                    }
+                    match recording.writer.write(&recording.block) {
+                        Ok(()) => false,
+                        Err(err) => {
+                            println!("Could not write {:?}: {:?}", recording.path, err);
+                            true
+                        }
+                    }
+                }
+                None => false,
+            };
+            if failed {
+                *recording = None;
+            }
+        }));
+        Ok(())
+    }
+    fn close(&mut self) {
+        if let Some(recording) = self.running.take().and_then(Running::stop).and_then(|x| x) {
+            if let Err(err) = recording.writer.finish() {
+                println!("Could not write {:?}: {:?}", recording.path, err);
+            }
+        }
+    }
+}
+
+/// Where a `WavBackend` records to.
+struct Recording {
+    path: PathBuf,
+    writer: wav::WavWriter<BufWriter<File>>,
+    // one block of interleaved samples, kept to save allocating it every time
+    block: Vec<f32>,
+}
+
+/// How backends are written when edited as text. `-` stands for no file, and paths can't contain
+/// spaces.
+pub const BACKEND_SYNTAX: &str =
+    "jack, null [rate] [block size], or wav <input> <output> [rate] [block size]";
+
+/// Write a backend as text, in the form of `BACKEND_SYNTAX`.
+pub fn backend_text(config: &BackendConfig) -> String {
+    let file = |path: &Option<PathBuf>| match *path {
+        Some(ref path) => path.to_string_lossy().into_owned(),
+        None => "-".into(),
+    };
+    match *config {
+        BackendConfig::Jack => "jack".into(),
+        BackendConfig::Null {
+            rate,
+            block_size,
+        } => format!("null {} {}", rate, block_size),
+        BackendConfig::Wav {
+            ref input,
+            ref output,
+            rate,
+            block_size,
+        } => format!("wav {} {} {} {}", file(input), file(output), rate, block_size),
+    }
+}
+/// Read a backend written as text. Returns `None` if it doesn't follow `BACKEND_SYNTAX`.
+pub fn parse_backend(text: &str) -> Option<BackendConfig> {
+    let words: Vec<&str> = text.split_whitespace().collect();
+    let file = |word: &str| match word {
+        "-" => None,
+        path => Some(PathBuf::from(path)),
+    };
+    // the timing comes last, and may be left out
+    let timing = |words: &[&str]| -> Option<(u32, usize)> {
+        let rate = words.get(0).map_or(Some(DEFAULT_RATE), |word| word.parse().ok())?;
+        let block_size = words.get(1).map_or(Some(DEFAULT_BLOCK_SIZE), |word| word.parse().ok())?;
+        if words.len() > 2 {
+            return None;
+        }
+        Some((rate, block_size))
+    };
+    match words.split_first() {
+        Some((&"jack", [])) => Some(BackendConfig::Jack),
+        Some((&"null", rest)) => timing(rest).map(|(rate, block_size)| BackendConfig::Null {
+            rate,
+            block_size,
+        }),
+        Some((&"wav", rest)) if rest.len() >= 2 => timing(&rest[2..]).map(|(rate, block_size)| {
+            BackendConfig::Wav {
+                input: file(rest[0]),
+                output: file(rest[1]),
+                rate,
+                block_size,
+            }
+        }),
+        _ => None,
+    }
+}
+
+#[test]
+fn test_backend_text() {
+    let configs = [
+        BackendConfig::Jack,
+        BackendConfig::Null {
+            rate: 44100,
+            block_size: 64,
+        },
+        BackendConfig::Wav {
+            input: None,
+            output: Some("out.wav".into()),
+            rate: 8000,
+            block_size: 16,
+        },
+    ];
+    for config in &configs {
+        assert_eq!(parse_backend(&backend_text(config)).as_ref(), Some(config));
+    }
+    assert_eq!(
+        parse_backend(" null "),
+        Some(BackendConfig::Null {
+            rate: DEFAULT_RATE,
+            block_size: DEFAULT_BLOCK_SIZE,
+        })
+    );
+    assert_eq!(parse_backend("null fast"), None);
+    assert_eq!(parse_backend("wav in.wav"), None);
+    assert_eq!(parse_backend("pulse"), None);
+}
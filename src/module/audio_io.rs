@@ -1,37 +1,290 @@
-use futures::channel::mpsc;
+use futures::channel::mpsc::{self, UnboundedReceiver, UnboundedSender};
 use futures::executor;
 use futures::future;
 use futures::prelude::*;
 use futures::task;
 
-use future_ext::{Breaker, FutureWrapExt};
+use alloc_check;
+use future_ext::{Breaker, FutureWrapExt, RemoteWaker};
+use module::audio_backend::{self, backend_text, parse_backend, AudioBackend, BackendConfig};
 use module::{flow, Module};
 
+use crossbeam::queue::ArrayQueue;
 use jack::*;
 
-use ndarray::{Array, Array2, Axis};
+use ndarray::Array2;
+use ron;
 
-use std::sync::Arc;
+use std::fmt;
+use std::mem;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, Thread};
+use std::time::{Duration, Instant};
 
+#[derive(Clone)]
 pub struct Frame {
     pub rate: f32,
     pub data: Array2<f32>,
 }
+impl Frame {
+    /// Check that the frame has the shape and rate the audio settings call for. A rate of 0 means it
+    /// isn't known, and is taken to match.
+    pub fn check(&self, config: &flow::AudioConfig) -> Result<(), FrameMismatch> {
+        let (length, channels) = (self.data.shape()[0], self.data.shape()[1]);
+        if self.rate != 0.0 && self.rate != config.rate as f32 {
+            Err(FrameMismatch::Rate {
+                expected: config.rate,
+                found: self.rate,
+            })
+        } else if length != config.block_size {
+            Err(FrameMismatch::Length {
+                expected: config.block_size,
+                found: length,
+            })
+        } else if channels != config.channels {
+            Err(FrameMismatch::Channels {
+                expected: config.channels,
+                found: channels,
+            })
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// How a `Frame` differs from the audio settings.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum FrameMismatch {
+    Rate { expected: u32, found: f32 },
+    Length { expected: usize, found: usize },
+    Channels { expected: usize, found: usize },
+}
+impl FrameMismatch {
+    /// Whether the frame can still be used, with missing channels left silent and extra ones dropped.
+    /// Frames of the wrong rate or length can't be, since they would come out at the wrong speed or
+    /// leave gaps.
+    pub fn is_playable(&self) -> bool {
+        match *self {
+            FrameMismatch::Channels { .. } => true,
+            _ => false,
+        }
+    }
+}
+impl fmt::Display for FrameMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            FrameMismatch::Rate {
+                expected,
+                found,
+            } => write!(f, "rate {}, expected {}", found, expected),
+            FrameMismatch::Length {
+                expected,
+                found,
+            } => write!(f, "length {}, expected {}", found, expected),
+            FrameMismatch::Channels {
+                expected,
+                found,
+            } => write!(f, "{} channels, expected {}", found, expected),
+        }
+    }
+}
+
+/// Register conversions from audio frames to plain samples. There is none the other way, since a
+/// lone sample can't fill a frame of the graph's block size.
+pub fn register_adapters(graph: &flow::Graph) {
+    // a frame becomes its peak level across all channels
+    graph.register_adapter(|frame: Frame| frame.data.iter().fold(0.0f32, |peak, x| peak.max(x.abs())));
+}
+
+/// Settings for the audio backend of an `AudioIO` module. Apart from the channel counts, they only
+/// apply to JACK.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AudioIOConfig {
+    pub backend: BackendConfig,
+    pub client_name: String,
+    pub n_inputs: usize,
+    pub n_outputs: usize,
+    /// Where the inputs are connected from once the client is activated.
+    pub connect_inputs: AutoConnect,
+    /// Where the outputs are connected to once the client is activated.
+    pub connect_outputs: AutoConnect,
+}
+impl Default for AudioIOConfig {
+    fn default() -> AudioIOConfig {
+        AudioIOConfig {
+            backend: BackendConfig::Jack,
+            client_name: "flow-synth".into(),
+            n_inputs: 2,
+            n_outputs: 2,
+            connect_inputs: AutoConnect::Physical,
+            connect_outputs: AutoConnect::Physical,
+        }
+    }
+}
+
+/// Which JACK ports to connect a group of ports to. The nth port is connected to the nth match.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum AutoConnect {
+    /// Leave the ports for the user to connect.
+    Off,
+    /// The physical ports of the sound card.
+    Physical,
+    /// The audio ports whose full names (`client:port`) match a regular expression.
+    Pattern(String),
+}
+impl AutoConnect {
+    /// Find the audio ports of other clients to connect to. `flags` gives the direction of the ports
+    /// to look for.
+    fn find_ports(&self, client: &Client, flags: PortFlags) -> Vec<String> {
+        let ports = match *self {
+            AutoConnect::Off => return Vec::new(),
+            AutoConnect::Physical => client.ports(None, Some("audio"), flags | PortFlags::IS_PHYSICAL),
+            AutoConnect::Pattern(ref pattern) => client.ports(Some(pattern.as_str()), Some("audio"), flags),
+        };
+        let own = format!("{}:", client.name());
+        ports.into_iter().filter(|port| !port.starts_with(&own)).collect()
+    }
+}
+
+/// What the backend of an `AudioIO` module is up to.
+#[derive(Clone, Debug, PartialEq)]
+pub enum AudioStatus {
+    /// The module hasn't started yet.
+    Idle,
+    Running,
+    /// The backend couldn't be opened, such as when no JACK server is running. Another attempt is made
+    /// every few seconds.
+    Failed(String),
+    /// The module was stopped, and its backend is closed.
+    Stopped,
+}
+
+const RETRY_INTERVAL_SECS: u64 = 5;
+
+/// A snapshot of the counters kept by an `AudioIO` module's backend, for finding out whether the
+/// patch keeps up. Counts start from zero whenever the backend is rebuilt.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct AudioStats {
+    /// Cycles that overran their deadline.
+    pub xruns: usize,
+    /// Input frames dropped because the graph hadn't taken the previous one yet.
+    pub dropped_inputs: usize,
+    /// Cycles where the graph had no output frame ready, so silence was played instead.
+    pub missing_outputs: usize,
+    /// Output frames that didn't match the rate, length or channel count of the backend. Frames with
+    /// only the wrong number of channels are still played.
+    pub mismatched_outputs: usize,
+    /// How the most recent mismatched output frame was wrong.
+    pub last_mismatch: Option<FrameMismatch>,
+    /// Samples between capturing an input frame and playing the output frame made from it. This is
+    /// estimated from the number of frames in flight through the graph.
+    pub latency: usize,
+}
+
+/// The live version of `AudioStats`, written from the backend's threads.
+#[derive(Default)]
+struct Counters {
+    xruns: AtomicUsize,
+    dropped_inputs: AtomicUsize,
+    missing_outputs: AtomicUsize,
+    mismatched_outputs: AtomicUsize,
+    latency: AtomicUsize,
+    // only written from the graph side
+    last_mismatch: Mutex<Option<FrameMismatch>>,
+}
+impl Counters {
+    fn snapshot(&self) -> AudioStats {
+        AudioStats {
+            xruns: self.xruns.load(Ordering::Relaxed),
+            dropped_inputs: self.dropped_inputs.load(Ordering::Relaxed),
+            missing_outputs: self.missing_outputs.load(Ordering::Relaxed),
+            mismatched_outputs: self.mismatched_outputs.load(Ordering::Relaxed),
+            latency: self.latency.load(Ordering::Relaxed),
+            last_mismatch: *self.last_mismatch.lock().unwrap(),
+        }
+    }
+    fn reset(&self) {
+        for counter in &[
+            &self.xruns,
+            &self.dropped_inputs,
+            &self.missing_outputs,
+            &self.mismatched_outputs,
+            &self.latency,
+        ] {
+            counter.store(0, Ordering::Relaxed);
+        }
+        *self.last_mismatch.lock().unwrap() = None;
+    }
+}
+
+enum Command {
+    /// Rebuild the backend with new settings.
+    Configure(AudioIOConfig),
+    /// Rebuild the backend with the same settings.
+    Retry,
+    /// Close the backend for good.
+    Stop,
+}
+
+/// The state of an `AudioIO` module that is shared with its backend and its GUI.
+#[derive(Clone)]
+struct Shared {
+    config: Arc<Mutex<AudioIOConfig>>,
+    status: Arc<Mutex<AudioStatus>>,
+    counters: Arc<Counters>,
+    cmd_tx: UnboundedSender<Command>,
+}
+impl Shared {
+    fn config(&self) -> AudioIOConfig {
+        self.config.lock().unwrap().clone()
+    }
+    fn set_config(&self, config: AudioIOConfig) {
+        *self.config.lock().unwrap() = config.clone();
+        self.send(Command::Configure(config));
+    }
+    fn status(&self) -> AudioStatus {
+        self.status.lock().unwrap().clone()
+    }
+    fn set_status(&self, status: AudioStatus) {
+        *self.status.lock().unwrap() = status;
+    }
+    fn send(&self, cmd: Command) {
+        // fails only once the backend is gone for good, when there is nothing left to rebuild
+        let _ = self.cmd_tx.unbounded_send(cmd);
+    }
+}
+
 pub struct AudioIO {
     ifc: Arc<flow::Interface>,
     in_port: Option<Arc<flow::Port<Frame, ()>>>,
     out_port: Option<Arc<flow::Port<(), Frame>>>,
+    shared: Shared,
+    cmd_rx: Option<UnboundedReceiver<Command>>,
     breaker: Breaker,
 }
 impl Module for AudioIO {
     fn new(ifc: Arc<flow::Interface>) -> AudioIO {
-        let in_port = Some(ifc.get_or_create_port("Input".into()));
-        let out_port = Some(ifc.get_or_create_port("Output".into()));
+        let breaker = ifc.breaker();
+        // frames that pile up faster than JACK consumes them are stale, so only keep the newest
+        let in_port = ifc.get_or_create_bounded_port("Input".into(), 2, flow::Overflow::DropOldest);
+        let out_port = ifc.get_or_create_port("Output".into());
+        in_port.set_tag("audio");
+        out_port.set_tag("audio");
+        let (cmd_tx, cmd_rx) = mpsc::unbounded();
         AudioIO {
             ifc,
-            in_port,
-            out_port,
-            breaker: Breaker::new(),
+            in_port: Some(in_port),
+            out_port: Some(out_port),
+            shared: Shared {
+                config: Arc::default(),
+                status: Arc::new(Mutex::new(AudioStatus::Idle)),
+                counters: Arc::default(),
+                cmd_tx,
+            },
+            cmd_rx: Some(cmd_rx),
+            breaker,
         }
     }
     fn name() -> &'static str {
@@ -42,173 +295,943 @@ impl Module for AudioIO {
     }
     fn stop(&mut self) {
         self.breaker.brake();
+        self.shared.send(Command::Stop);
     }
     fn ports(&self) -> Vec<Arc<flow::OpaquePort>> {
         self.ifc.ports()
     }
+    fn save_state(&self) -> Option<String> {
+        ron::ser::to_string(&self.config()).ok()
+    }
+    fn load_state(&mut self, state: &str) {
+        match ron::de::from_str(state) {
+            Ok(config) => self.set_config(config),
+            Err(err) => println!("Could not load AudioIO settings: {:?}", err),
+        }
+    }
+}
+
+impl AudioIO {
+    pub fn config(&self) -> AudioIOConfig {
+        self.shared.config()
+    }
+    /// Change the settings of the backend. If the module has started, the backend is rebuilt with the
+    /// new settings.
+    pub fn set_config(&mut self, config: AudioIOConfig) {
+        self.shared.set_config(config);
+    }
+    pub fn status(&self) -> AudioStatus {
+        self.shared.status()
+    }
+    /// Wait up to `timeout` for the backend to close after `stop`, such as for a recording to be
+    /// written out. Returns false if it is still running.
+    pub fn wait_stopped(&self, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        while self.shared.status() == AudioStatus::Running {
+            if Instant::now() >= deadline {
+                return false;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        true
+    }
+    /// Rebuild the backend now, rather than waiting for the next periodic attempt.
+    pub fn retry(&self) {
+        self.shared.send(Command::Retry);
+    }
+    pub fn stats(&self) -> AudioStats {
+        self.shared.counters.snapshot()
+    }
+    pub fn reset_stats(&self) {
+        self.shared.counters.reset();
+    }
+}
+
+/// The number of frame buffers in flight in each direction. Captured frames that the graph hasn't taken
+/// yet are stale, so this is kept small.
+const POOL_SIZE: usize = 2;
+
+/// Preallocated frame buffers for one backend, recycled between the backend's thread and the graph.
+/// That thread only ever moves buffers between these queues, so that it never allocates or frees
+/// memory. Copies are made on the graph side instead.
+struct FramePool {
+    /// Empty buffers for the backend to capture into.
+    free_inputs: ArrayQueue<Frame>,
+    /// Captured buffers waiting for the graph.
+    captured: ArrayQueue<Frame>,
+    /// Empty buffers for the graph to copy output frames into.
+    free_outputs: ArrayQueue<Frame>,
+    /// Output buffers waiting to be played.
+    playback: ArrayQueue<Frame>,
+    /// The settings that output frames have to match.
+    audio: flow::AudioConfig,
+    // parked until the backend has moved some buffers. Unparking doesn't allocate, unlike waking a
+    // task.
+    waker: Thread,
+}
+impl FramePool {
+    fn new(rate: f32, buffer_size: usize, n_inputs: usize, n_outputs: usize, waker: Thread) -> FramePool {
+        let pool = FramePool {
+            free_inputs: ArrayQueue::new(POOL_SIZE),
+            captured: ArrayQueue::new(POOL_SIZE),
+            free_outputs: ArrayQueue::new(POOL_SIZE),
+            playback: ArrayQueue::new(POOL_SIZE),
+            audio: flow::AudioConfig {
+                rate: rate as u32,
+                block_size: buffer_size,
+                channels: n_outputs,
+            },
+            waker,
+        };
+        let buffer = |channels| Frame {
+            rate,
+            data: Array2::zeros((buffer_size, channels)),
+        };
+        for _ in 0..POOL_SIZE {
+            let _ = pool.free_inputs.push(buffer(n_inputs));
+            let _ = pool.free_outputs.push(buffer(n_outputs));
+        }
+        pool
+    }
+}
+
+/// The graph side of the data path, shared by the futures that feed frames to and from the graph.
+struct Link {
+    /// The buffers of the current backend, if there is one.
+    pool: Mutex<Option<Arc<FramePool>>>,
+    // woken by the backend once it has moved some buffers
+    waker: RemoteWaker,
+    counters: Arc<Counters>,
+}
+impl Link {
+    fn pool(&self) -> Option<Arc<FramePool>> {
+        self.pool.lock().unwrap().clone()
+    }
+    fn register(&self, cx: &mut task::Context) {
+        self.waker.register(cx);
+    }
+    /// Take the next captured frame.
+    fn poll_captured(&self, cx: &mut task::Context) -> Async<Frame> {
+        self.register(cx);
+        if let Some(pool) = self.pool() {
+            if let Ok(buffer) = pool.captured.pop() {
+                // the graph gets its own copy, so that the buffer can go straight back to the backend
+                let frame = buffer.clone();
+                let _ = pool.free_inputs.push(buffer);
+                return Async::Ready(frame);
+            }
+        }
+        Async::Pending
+    }
+    /// Queue a frame to be played, once there is a buffer to copy it into.
+    fn poll_play(&self, cx: &mut task::Context, frame: &Frame) -> Async<()> {
+        self.register(cx);
+        let pool = match self.pool() {
+            Some(pool) => pool,
+            None => return Async::Pending,
+        };
+        let mut buffer = match pool.free_outputs.pop() {
+            Ok(buffer) => buffer,
+            Err(_) => return Async::Pending,
+        };
+        let playable = match frame.check(&pool.audio) {
+            Ok(()) => true,
+            Err(mismatch) => {
+                self.counters.mismatched_outputs.fetch_add(1, Ordering::Relaxed);
+                *self.counters.last_mismatch.lock().unwrap() = Some(mismatch);
+                mismatch.is_playable()
+            }
+        };
+        // play as many channels as there are. Missing channels are silent. Frames that can't be played
+        // are left out, so that silence is played in their place.
+        if playable {
+            buffer.data.fill(0.0);
+            for channel in 0..pool.audio.channels.min(frame.data.shape()[1]) {
+                buffer.data.column_mut(channel).assign(&frame.data.column(channel));
+            }
+            let _ = pool.playback.push(buffer);
+        } else {
+            let _ = pool.free_outputs.push(buffer);
+        }
+        Async::Ready(())
+    }
 }
 
 struct AudioIOFuture {
-    client: Option<AsyncClient<(), Processor>>,
+    ifc: Arc<flow::Interface>,
+    backend: Option<Box<dyn AudioBackend>>,
+    config: AudioIOConfig,
+    // set when the backend needs to be (re)built with the current config
+    stale: bool,
+    shared: Shared,
+    cmd_rx: UnboundedReceiver<Command>,
     future: Box<dyn Future<Item = (), Error = Never> + Send>,
-    output_rx: Option<mpsc::Receiver<Frame>>,
-    input_tx: Option<mpsc::Sender<Frame>>,
+    link: Arc<Link>,
     breaker: Breaker,
+    // held while the backend runs, so other `AudioIO` modules can't change the settings under it
+    claim: Option<flow::AudioClaim>,
+}
+
+impl Drop for AudioIOFuture {
+    fn drop(&mut self) {
+        self.close();
+    }
 }
 
 impl AudioIOFuture {
     fn new(base: &mut AudioIO) -> AudioIOFuture {
-        let (input_tx, input_rx) = mpsc::channel(1);
-        let (output_tx, output_rx) = mpsc::channel(1);
+        let link = Arc::new(Link {
+            pool: Mutex::new(None),
+            waker: RemoteWaker::new(),
+            counters: base.shared.counters.clone(),
+        });
         let in_port = base.in_port.take().unwrap();
         let out_port = base.out_port.take().unwrap();
         let in_future = future::loop_fn(
-            (input_rx, out_port, base.breaker.clone()),
-            |(recv, port, breaker)| {
+            (link.clone(), out_port, base.breaker.clone()),
+            |(link, port, breaker)| {
                 port.read1()
-                    .wrap(recv)
-                    .map_err(|(recv, (port, err))| (recv, port, format!("read1 {:?}", err)))
-                    .and_then(|(recv, (port, _req))| {
-                        recv.next()
-                            .map(|(frame, recv)| (frame.unwrap(), recv, port))
-                            .map_err(|(_err, _recv)| panic!()) // error: Never, panic impossible!
+                    .wrap(link)
+                    .map_err(|(link, (port, err))| (link, port, format!("read1 {:?}", err)))
+                    .and_then(|(link, (port, _req))| {
+                        let captured = link.clone();
+                        future::poll_fn(move |cx| Ok(captured.poll_captured(cx)))
+                            .map(move |frame| (frame, link, port))
                     })
-                    .and_then(|(frame, recv, port)| {
+                    .and_then(|(frame, link, port)| {
                         port.write1(frame)
-                            .wrap(recv)
-                            .map_err(|(recv, (port, err))| (recv, port, format!("write1 {:?}", err)))
+                            .wrap(link)
+                            .map_err(|(link, (port, err))| (link, port, format!("write1 {:?}", err)))
                     })
-                    .recover(|(recv, port, err)| {
+                    .recover(|(link, port, err)| {
                         println!("In err: {}", err);
-                        (recv, port)
+                        (link, port)
                     })
-                    .map(|(recv, port)| {
+                    .map(|(link, port)| {
                         if breaker.test() {
                             future::Loop::Break(())
                         } else {
-                            future::Loop::Continue((recv, port, breaker))
+                            future::Loop::Continue((link, port, breaker))
                         }
                     })
             },
         );
         let out_future = future::loop_fn(
-            (output_tx, in_port, base.breaker.clone()),
-            |(tx, port, breaker)| {
+            (link.clone(), in_port, base.breaker.clone()),
+            |(link, port, breaker)| {
                 port.write1(())
-                    .wrap(tx)
-                    .map_err(|(tx, (port, err))| (tx, port, format!("write1 {:?}", err)))
-                    .and_then(|(tx, port)| {
+                    .wrap(link)
+                    .map_err(|(link, (port, err))| (link, port, format!("write1 {:?}", err)))
+                    .and_then(|(link, port)| {
                         port.read1()
-                            .wrap(tx)
-                            .map_err(|(tx, (port, err))| (tx, port, format!("read1 {:?}", err)))
+                            .wrap(link)
+                            .map_err(|(link, (port, err))| (link, port, format!("read1 {:?}", err)))
                     })
-                    .and_then(|(tx, (port, frame))| {
-                        tx.send(frame).map(|tx| (tx, port)).map_err(|err| panic!())
-                        // error: Never, panic impossible!
+                    .and_then(|(link, (port, frame))| {
+                        let playback = link.clone();
+                        future::poll_fn(move |cx| Ok(playback.poll_play(cx, &frame)))
+                            .map(move |()| (link, port))
                     })
-                    .recover(|(tx, port, err)| {
+                    .recover(|(link, port, err)| {
                         println!("Out err: {}", err);
-                        (tx, port)
+                        (link, port)
                     })
-                    .map(|(tx, port)| {
+                    .map(|(link, port)| {
                         if breaker.test() {
                             future::Loop::Break(())
                         } else {
-                            future::Loop::Continue((tx, port, breaker))
+                            future::Loop::Continue((link, port, breaker))
                         }
                     })
             },
         );
         AudioIOFuture {
-            client: None,
-            input_tx: Some(input_tx),
-            output_rx: Some(output_rx),
+            ifc: base.ifc.clone(),
+            backend: None,
+            config: base.shared.config(),
+            stale: true,
+            shared: base.shared.clone(),
+            cmd_rx: base.cmd_rx.take().unwrap(),
             future: Box::new(in_future.join(out_future).map(|((), ())| ())),
+            link,
             breaker: base.breaker.clone(),
+            claim: None,
         }
     }
     fn initialize(&mut self) {
-        // setup jack
-        if self.client.is_none() {
-            let n_inputs = 2;
-            let n_outputs = 2;
-            let (client, _status) = Client::new("flow-synth", ClientOptions::NO_START_SERVER).unwrap();
-            // create ports
-            let inputs: Vec<_> = (0..n_inputs)
-                .map(|i| {
-                    client
-                        .register_port(&format!("in-{}", i), AudioIn::default())
-                        .unwrap()
-                })
-                .collect();
-            let outputs: Vec<_> = (0..n_outputs)
-                .map(|i| {
-                    client
-                        .register_port(&format!("out-{}", i), AudioOut::default())
-                        .unwrap()
-                })
-                .collect();
-
-            // activate the client
-            let processor = Processor {
-                inputs,
-                outputs,
-                input_tx: self.input_tx.take().unwrap(),
-                output_rx: self.output_rx.take().unwrap(),
-                breaker: self.breaker.clone(),
-            };
-            self.client = Some(AsyncClient::new(client, (), processor).unwrap());
+        if !self.stale {
+            return;
+        }
+        self.stale = false;
+        match self.open() {
+            Ok(backend) => {
+                self.backend = Some(backend);
+                self.shared.set_status(AudioStatus::Running);
+            }
+            Err(err) => {
+                println!("Could not open audio backend {:?}: {}", backend_text(&self.config.backend), err);
+                self.shared.set_status(AudioStatus::Failed(err));
+                let shared = self.shared.clone();
+                thread::spawn(move || {
+                    thread::sleep(Duration::from_secs(RETRY_INTERVAL_SECS));
+                    // don't bother if the backend was rebuilt in the meantime
+                    if let AudioStatus::Failed(_) = shared.status() {
+                        shared.send(Command::Retry);
+                    }
+                });
+            }
         }
     }
+    fn open(&mut self) -> Result<Box<dyn AudioBackend>, String> {
+        let mut backend = audio_backend::new_backend(&self.config.backend);
+        let mut pool = None;
+        self.shared.counters.reset();
+        {
+            let (config, link, counters) = (&self.config, &self.link, &self.shared.counters);
+            let breaker = &self.breaker;
+            backend.open(config, &mut |rate, buffer_size| {
+                let frames = Arc::new(FramePool::new(
+                    rate,
+                    buffer_size,
+                    config.n_inputs,
+                    config.n_outputs,
+                    link.waker.thread(),
+                ));
+                pool = Some(frames.clone());
+                DataPath::new(frames, counters.clone(), breaker.clone())
+            })?;
+        }
+        // the rest of the graph has to keep up with the backend, which can't also keep up with another
+        // one that runs differently
+        if let (Some(pool), Some(graph)) = (pool.as_ref(), self.ifc.graph()) {
+            match graph.claim_audio_config(pool.audio) {
+                Ok(claim) => self.claim = Some(claim),
+                Err(claimed) => {
+                    backend.close();
+                    return Err(format!(
+                        "another AudioIO runs the patch at {} Hz with blocks of {} and {} outputs, \
+                         but this one would run at {} Hz with blocks of {} and {} outputs",
+                        claimed.rate,
+                        claimed.block_size,
+                        claimed.channels,
+                        pool.audio.rate,
+                        pool.audio.block_size,
+                        pool.audio.channels
+                    ));
+                }
+            }
+        }
+        *self.link.pool.lock().unwrap() = pool;
+        Ok(backend)
+    }
+    /// Shut down the backend, along with its buffers.
+    fn close(&mut self) {
+        *self.link.pool.lock().unwrap() = None;
+        if let Some(mut backend) = self.backend.take() {
+            backend.close();
+        }
+        self.claim = None;
+    }
 }
 impl Future for AudioIOFuture {
     type Item = ();
     type Error = Never;
     fn poll(&mut self, cx: &mut task::Context) -> Poll<Self::Item, Self::Error> {
+        while let Ok(Async::Ready(Some(cmd))) = self.cmd_rx.poll_next(cx) {
+            match cmd {
+                Command::Configure(ref config) if *config == self.config => {}
+                Command::Configure(config) => {
+                    self.close();
+                    self.config = config;
+                    self.stale = true;
+                }
+                Command::Retry => {
+                    self.close();
+                    self.stale = true;
+                }
+                Command::Stop => {
+                    self.close();
+                    self.shared.set_status(AudioStatus::Stopped);
+                    return Ok(Async::Ready(()));
+                }
+            }
+        }
         self.initialize();
         self.future.poll(cx)
     }
 }
 
+/// Runs an `AudioIO` module as a JACK client.
+pub struct JackBackend {
+    client: Option<AsyncClient<Notifications, Processor>>,
+}
+impl JackBackend {
+    pub fn new() -> JackBackend {
+        JackBackend {
+            client: None,
+        }
+    }
+    fn open_client(
+        config: &AudioIOConfig,
+        path: &mut dyn FnMut(f32, usize) -> DataPath,
+    ) -> Result<AsyncClient<Notifications, Processor>, Error> {
+        let (client, _status) = Client::new(&config.client_name, ClientOptions::NO_START_SERVER)?;
+        // create ports
+        let inputs = (0..config.n_inputs)
+            .map(|i| client.register_port(&format!("in-{}", i), AudioIn::default()))
+            .collect::<Result<Vec<_>, _>>()?;
+        let outputs = (0..config.n_outputs)
+            .map(|i| client.register_port(&format!("out-{}", i), AudioOut::default()))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        // activate the client
+        let path = path(client.sample_rate() as f32, client.buffer_size() as usize);
+        let notifications = Notifications {
+            counters: path.counters.clone(),
+        };
+        let processor = Processor {
+            inputs,
+            outputs,
+            path,
+        };
+        let client = AsyncClient::new(client, notifications, processor)?;
+        Self::auto_connect(config, client.as_client());
+        Ok(client)
+    }
+    /// Connect the client's ports as the config asks. Ports that can't be connected are reported and
+    /// left alone.
+    fn auto_connect(config: &AudioIOConfig, client: &Client) {
+        let own = |prefix: &str, n| {
+            (0..n)
+                .map(|i| format!("{}:{}-{}", client.name(), prefix, i))
+                .collect::<Vec<_>>()
+        };
+        // our inputs are fed by the outputs of other clients, and vice versa
+        let sources = config.connect_inputs.find_ports(client, PortFlags::IS_OUTPUT);
+        let sinks = config.connect_outputs.find_ports(client, PortFlags::IS_INPUT);
+        let inputs = sources.into_iter().zip(own("in", config.n_inputs));
+        let outputs = own("out", config.n_outputs).into_iter().zip(sinks);
+        for (src, dst) in inputs.chain(outputs) {
+            if let Err(err) = client.connect_ports_by_name(&src, &dst) {
+                println!("Could not connect {} to {}: {:?}", src, dst, err);
+            }
+        }
+    }
+}
+impl AudioBackend for JackBackend {
+    fn open(
+        &mut self,
+        config: &AudioIOConfig,
+        path: &mut dyn FnMut(f32, usize) -> DataPath,
+    ) -> Result<(), String> {
+        let client = Self::open_client(config, path)
+            .map_err(|err| format!("{:?}", err))?;
+        self.client = Some(client);
+        Ok(())
+    }
+    fn close(&mut self) {
+        if let Some(client) = self.client.take() {
+            if let Err(err) = client.deactivate() {
+                println!("Error closing JACK client: {:?}", err);
+            }
+        }
+    }
+}
+
 struct Processor {
     inputs: Vec<Port<AudioIn>>,
     outputs: Vec<Port<AudioOut>>,
-    input_tx: mpsc::Sender<Frame>,
-    output_rx: mpsc::Receiver<Frame>,
-    breaker: Breaker,
+    path: DataPath,
 }
 impl ProcessHandler for Processor {
     fn process(&mut self, client: &Client, ps: &ProcessScope) -> Control {
-        let in_frame = Frame {
-            rate: client.sample_rate() as f32,
-            data: Array::from_iter(self.inputs.iter().flat_map(|input| input.as_slice(ps).to_vec()))
-                .into_shape((self.inputs.len(), client.buffer_size() as usize))
-                .unwrap()
-                .reversed_axes(),
-        };
+        alloc_check::forbid(|| {
+            self.path.cycle(
+                self.inputs.iter().map(|input| input.as_slice(ps)),
+                self.outputs.iter_mut().map(|output| output.as_mut_slice(ps)),
+            )
+        });
+
+        if self.path.stopped() {
+            Control::Quit
+        } else {
+            Control::Continue
+        }
+    }
+}
 
-        // ignore errors, prefer to drop the frame
-        if let Ok(Some(frame)) = self.output_rx.try_next() {
-            assert!(frame.rate == in_frame.rate);
-            assert!(frame.data.shape() == in_frame.data.shape());
-            for (output, buffer) in self.outputs.iter_mut().zip(frame.data.axis_iter(Axis(1))) {
-                for (sample_out, sample) in output.as_mut_slice(ps).iter_mut().zip(buffer.iter()) {
-                    *sample_out = *sample;
+/// The real-time half of the data path, which a backend drives once per block. It never allocates.
+pub struct DataPath {
+    pool: Arc<FramePool>,
+    counters: Arc<Counters>,
+    // frames sent to and received from the graph, for estimating latency
+    captured: usize,
+    played: usize,
+    breaker: Breaker,
+}
+impl DataPath {
+    fn new(pool: Arc<FramePool>, counters: Arc<Counters>, breaker: Breaker) -> DataPath {
+        DataPath {
+            pool,
+            counters,
+            captured: 0,
+            played: 0,
+            breaker,
+        }
+    }
+    /// Whether the module has stopped, so the backend can stop too.
+    pub fn stopped(&self) -> bool {
+        self.breaker.test()
+    }
+    /// Count a cycle that overran its deadline.
+    pub fn xrun(&self) {
+        self.counters.xruns.fetch_add(1, Ordering::Relaxed);
+    }
+    /// Play the next output frame and capture the next input frame, one buffer per channel.
+    pub fn cycle<'a, I, O>(&mut self, inputs: I, outputs: O)
+    where
+        I: Iterator<Item = &'a [f32]>,
+        O: Iterator<Item = &'a mut [f32]>,
+    {
+        match self.pool.playback.pop() {
+            Ok(frame) => {
+                self.played += 1;
+                for (channel, output) in outputs.enumerate() {
+                    for (sample_out, sample) in output.iter_mut().zip(frame.data.column(channel)) {
+                        *sample_out = *sample;
+                    }
                 }
+                // there is always room, since every buffer came from here
+                let _ = self.pool.free_outputs.push(frame).map_err(mem::forget);
             }
-        } else {
-            for output in &mut self.outputs {
-                for sample in output.as_mut_slice(ps) {
-                    *sample = 0.0;
+            Err(_) => {
+                if self.played > 0 {
+                    // only once the graph has started producing, or an unconnected module would count
+                    // every cycle
+                    self.counters.missing_outputs.fetch_add(1, Ordering::Relaxed);
+                }
+                for output in outputs {
+                    for sample in output {
+                        *sample = 0.0;
+                    }
                 }
             }
         }
-        let _ = self.input_tx.try_send(in_frame);
 
-        if self.breaker.test() {
-            Control::Quit
+        // the buffers are allocated when the client opens, so they all have the same length
+        let mut buffer_size = 0;
+        match self.pool.free_inputs.pop() {
+            Ok(mut frame) => {
+                buffer_size = frame.data.shape()[0];
+                for (channel, input) in inputs.enumerate() {
+                    for (sample_out, sample) in frame.data.column_mut(channel).iter_mut().zip(input) {
+                        *sample_out = *sample;
+                    }
+                }
+                let _ = self.pool.captured.push(frame).map_err(mem::forget);
+                self.captured += 1;
+            }
+            Err(_) => {
+                self.counters.dropped_inputs.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        if self.played > 0 && buffer_size > 0 {
+            let in_flight = self.captured.saturating_sub(self.played);
+            self.counters.latency.store(in_flight * buffer_size, Ordering::Relaxed);
+        }
+        self.pool.waker.unpark();
+    }
+}
+
+struct Notifications {
+    counters: Arc<Counters>,
+}
+impl NotificationHandler for Notifications {
+    fn xrun(&mut self, _: &Client) -> Control {
+        self.counters.xruns.fetch_add(1, Ordering::Relaxed);
+        Control::Continue
+    }
+}
+
+use gfx_device_gl as gl;
+use gui::{button::*, component::*, event::*, geom::*, module_gui::*, render::*, textbox::*};
+struct AudioIOGui {
+    bounds: Box3,
+    shared: Shared,
+    backend_box: TextBox,
+    name_box: TextBox,
+    inputs_box: TextBox,
+    outputs_box: TextBox,
+    connect_inputs_box: TextBox,
+    connect_outputs_box: TextBox,
+    apply_button: Button,
+    retry_button: Button,
+    stats_button: Button,
+    // whether the stats are shown in place of the settings
+    show_stats: bool,
+    // the status and stats as of the last render
+    shown: (AudioStatus, AudioStats),
+}
+const PADDING: f32 = 4.0;
+const ROW_HEIGHT: f32 = 20.0;
+const LABEL_WIDTH: f32 = 72.0;
+const LABELS: [&str; 6] = ["Backend", "Client", "Inputs", "Outputs", "From", "To"];
+impl ModuleGui for AudioIO {
+    fn new_body(&mut self, ctx: &mut RenderContext, bounds: Box3) -> Box<dyn GuiComponent<BodyUpdate>> {
+        let config = self.config();
+        let connect_inputs = auto_connect_text(&config.connect_inputs);
+        let connect_outputs = auto_connect_text(&config.connect_outputs);
+        let field = |row: usize| Box3 {
+            pos: bounds.pos + Pt3::new(LABEL_WIDTH, PADDING + row as f32 * (ROW_HEIGHT + PADDING), 0.0),
+            size: Pt3::new(bounds.size.x - LABEL_WIDTH - PADDING, ROW_HEIGHT, 0.0),
+        };
+        // the buttons share the row below the fields
+        let button_width = (bounds.size.x - PADDING * 4.0) / 3.0;
+        let button = |col: usize| Box3 {
+            pos: bounds.pos + Pt3::new(
+                PADDING + col as f32 * (button_width + PADDING),
+                PADDING + LABELS.len() as f32 * (ROW_HEIGHT + PADDING),
+                0.0,
+            ),
+            size: Pt3::new(button_width, ROW_HEIGHT, 0.0),
+        };
+        Box::new(AudioIOGui {
+            bounds,
+            shared: self.shared.clone(),
+            backend_box: TextBox::new(ctx.clone(), backend_text(&config.backend), field(0)),
+            name_box: TextBox::new(ctx.clone(), config.client_name, field(1)),
+            inputs_box: TextBox::new(ctx.clone(), config.n_inputs.to_string(), field(2)),
+            outputs_box: TextBox::new(ctx.clone(), config.n_outputs.to_string(), field(3)),
+            connect_inputs_box: TextBox::new(ctx.clone(), connect_inputs, field(4)),
+            connect_outputs_box: TextBox::new(ctx.clone(), connect_outputs, field(5)),
+            apply_button: Button::new(ctx.clone(), "Apply".into(), button(0)),
+            retry_button: Button::new(ctx.clone(), "Retry".into(), button(1)),
+            stats_button: Button::new(ctx.clone(), "Stats".into(), button(2)),
+            show_stats: false,
+            shown: (AudioStatus::Idle, AudioStats::default()),
+        })
+    }
+}
+/// Auto-connect settings are edited as text: nothing for `Off`, `physical` for `Physical`, or else a
+/// pattern.
+fn auto_connect_text(auto: &AutoConnect) -> String {
+    match *auto {
+        AutoConnect::Off => String::new(),
+        AutoConnect::Physical => "physical".into(),
+        AutoConnect::Pattern(ref pattern) => pattern.clone(),
+    }
+}
+fn parse_auto_connect(text: &str) -> AutoConnect {
+    match text.trim() {
+        "" => AutoConnect::Off,
+        "physical" => AutoConnect::Physical,
+        pattern => AutoConnect::Pattern(pattern.into()),
+    }
+}
+impl AudioIOGui {
+    fn text_boxes(&mut self) -> [&mut TextBox; 6] {
+        [
+            &mut self.backend_box,
+            &mut self.name_box,
+            &mut self.inputs_box,
+            &mut self.outputs_box,
+            &mut self.connect_inputs_box,
+            &mut self.connect_outputs_box,
+        ]
+    }
+    fn apply(&mut self) {
+        let backend = match parse_backend(self.backend_box.content()) {
+            Some(backend) => backend,
+            None => {
+                println!("The backend must be {}", audio_backend::BACKEND_SYNTAX);
+                return;
+            }
+        };
+        match (self.inputs_box.content().parse(), self.outputs_box.content().parse()) {
+            (Ok(n_inputs), Ok(n_outputs)) => {
+                self.shared.set_config(AudioIOConfig {
+                    backend,
+                    client_name: self.name_box.content().into(),
+                    n_inputs,
+                    n_outputs,
+                    connect_inputs: parse_auto_connect(self.connect_inputs_box.content()),
+                    connect_outputs: parse_auto_connect(self.connect_outputs_box.content()),
+                });
+                for text_box in &mut self.text_boxes() {
+                    text_box.set_focused(false);
+                }
+            }
+            _ => println!("Channel counts must be whole numbers"),
+        }
+    }
+    fn current(&self) -> (AudioStatus, AudioStats) {
+        (self.shared.status(), self.shared.counters.snapshot())
+    }
+    fn render_stats(&self, ctx: &mut RenderContext) {
+        let stats = self.shown.1;
+        let lines = [
+            format!("Xruns: {}", stats.xruns),
+            format!("Dropped in: {}", stats.dropped_inputs),
+            format!("Missing out: {}", stats.missing_outputs),
+            format!("Bad frames: {}", stats.mismatched_outputs),
+            format!("Latency: {} samples", stats.latency),
+            match stats.last_mismatch {
+                Some(mismatch) => format!("Last bad: {}", mismatch),
+                None => String::new(),
+            },
+        ];
+        for (row, line) in lines.iter().enumerate() {
+            let pos = Pt3::new(PADDING, PADDING + row as f32 * (ROW_HEIGHT + PADDING) + 4.0, 0.0);
+            ctx.draw_text(line, self.bounds.pos + pos, [1.0; 3]);
+        }
+    }
+}
+impl GuiComponent<bool> for AudioIOGui {
+    fn set_bounds(&mut self, bounds: Box3) {
+        self.bounds = bounds;
+    }
+    fn bounds(&self) -> Box3 {
+        self.bounds
+    }
+    fn render(&mut self, device: &mut gl::Device, ctx: &mut RenderContext) {
+        // show the current settings, unless they are being edited
+        if !self.text_boxes().iter().any(|text_box| text_box.focused()) {
+            let config = self.shared.config();
+            self.backend_box.set_content(backend_text(&config.backend));
+            self.name_box.set_content(config.client_name);
+            self.inputs_box.set_content(config.n_inputs.to_string());
+            self.outputs_box.set_content(config.n_outputs.to_string());
+            self.connect_inputs_box.set_content(auto_connect_text(&config.connect_inputs));
+            self.connect_outputs_box.set_content(auto_connect_text(&config.connect_outputs));
+        }
+        self.shown = self.current();
+        if self.show_stats {
+            self.render_stats(ctx);
         } else {
-            Control::Continue
+            let label_x = self.bounds.pos.x + PADDING;
+            for (label, text_box) in LABELS.iter().zip(&mut self.text_boxes()) {
+                let pos = text_box.bounds().pos;
+                ctx.draw_text(label, Pt3::new(label_x, pos.y + 4.0, pos.z), [1.0; 3]);
+                text_box.render(device, ctx);
+            }
         }
+        self.apply_button.render(device, ctx);
+        self.retry_button.render(device, ctx);
+        self.stats_button.render(device, ctx);
+
+        let (ref status, stats) = self.shown;
+        let (mut text, mut color) = match *status {
+            AudioStatus::Idle => ("Not started".to_string(), [0.6; 3]),
+            AudioStatus::Running => ("Running".to_string(), [0.2, 1.0, 0.2]),
+            AudioStatus::Failed(ref err) => (format!("Failed: {}", err), [1.0, 0.2, 0.2]),
+            AudioStatus::Stopped => ("Stopped".to_string(), [0.6; 3]),
+        };
+        if stats.mismatched_outputs > 0 {
+            text = format!("{} ({} bad frames)", text, stats.mismatched_outputs);
+            color = [1.0, 0.2, 0.2];
+        }
+        let row = LABELS.len() as f32 + 1.0;
+        let pos = self.bounds.pos + Pt3::new(PADDING, PADDING + row * (ROW_HEIGHT + PADDING), 0.0);
+        ctx.draw_text(&text, pos, color);
     }
+    fn handle(&mut self, event: &Event) -> BodyUpdate {
+        if let EventData::Character('\r') = event.data {
+            if self.text_boxes().iter().any(|text_box| text_box.focused()) {
+                self.apply();
+                return true;
+            }
+        }
+        let mut update = false;
+        match self.stats_button.handle(event) {
+            ButtonUpdate::Unchanged => {}
+            ButtonUpdate::NeedRender => update = true,
+            ButtonUpdate::Clicked => {
+                self.show_stats = !self.show_stats;
+                let label = if self.show_stats { "Config" } else { "Stats" };
+                self.stats_button.set_label(label.into());
+                for text_box in &mut self.text_boxes() {
+                    text_box.set_focused(false);
+                }
+                update = true;
+            }
+        }
+        if !self.show_stats {
+            for text_box in &mut self.text_boxes() {
+                // only the text box under the cursor should take focus
+                let focus = match event.data {
+                    EventData::Click(pos, _, _) => event.focus && text_box.intersect(pos),
+                    _ => event.focus,
+                };
+                update |= text_box.handle(&event.with_focus(focus)) != TextBoxUpdate::Unchanged;
+            }
+        }
+        match self.retry_button.handle(event) {
+            ButtonUpdate::Unchanged => {}
+            ButtonUpdate::NeedRender => update = true,
+            ButtonUpdate::Clicked => {
+                self.shared.send(Command::Retry);
+                update = true;
+            }
+        }
+        match self.apply_button.handle(event) {
+            ButtonUpdate::Unchanged => {}
+            ButtonUpdate::NeedRender => update = true,
+            ButtonUpdate::Clicked => {
+                self.apply();
+                update = true;
+            }
+        }
+        // the client reports back from other threads, so check whether there is something new to show
+        update || self.current() != self.shown
+    }
+}
+
+#[test]
+fn test_state() {
+    let graph = flow::Graph::new();
+    let mut audio = AudioIO::new(graph.add_node());
+    let config = AudioIOConfig {
+        backend: BackendConfig::Null {
+            rate: 44100,
+            block_size: 128,
+        },
+        client_name: "flow-synth-2".into(),
+        n_inputs: 4,
+        n_outputs: 6,
+        connect_inputs: AutoConnect::Off,
+        connect_outputs: AutoConnect::Pattern("system:playback_[12]".into()),
+    };
+    audio.set_config(config.clone());
+    let state = audio.save_state().unwrap();
+    let mut loaded = AudioIO::new(graph.add_node());
+    assert_eq!(loaded.config(), AudioIOConfig::default());
+    loaded.load_state(&state);
+    assert_eq!(loaded.config(), config);
+
+    // settings saved before auto-connect existed get the defaults
+    loaded.load_state(r#"(client_name: "old", n_inputs: 1, n_outputs: 1)"#);
+    assert_eq!(loaded.config().connect_outputs, AutoConnect::Physical);
+    let text = auto_connect_text(&config.connect_outputs);
+    assert_eq!(parse_auto_connect(&text), config.connect_outputs);
+}
+
+#[test]
+fn test_data_path() {
+    use futures::executor::block_on;
+    use std::iter;
+
+    let waker = RemoteWaker::new();
+    let pool = Arc::new(FramePool::new(48000.0, 4, 1, 2, waker.thread()));
+    let counters = Arc::new(Counters::default());
+    let link = Link {
+        pool: Mutex::new(Some(pool.clone())),
+        waker,
+        counters: counters.clone(),
+    };
+    let mut path = DataPath::new(pool, counters.clone(), Breaker::new());
+    let input = [1.0, 2.0, 3.0, 4.0];
+    let mut outputs = [[9.0; 4]; 2];
+    // allocations are only counted with the feature on
+    #[cfg(feature = "alloc-check")]
+    let before = alloc_check::violations();
+
+    // nothing to play yet, so the outputs are silent
+    alloc_check::forbid(|| path.cycle(iter::once(&input[..]), outputs.iter_mut().map(|x| &mut x[..])));
+    assert_eq!(outputs, [[0.0; 4]; 2]);
+    let captured = block_on(future::poll_fn(|cx| Ok::<_, Never>(link.poll_captured(cx)))).unwrap();
+    assert_eq!(captured.data.column(0).to_vec(), input.to_vec());
+
+    // a mono frame is played on the first channel only
+    block_on(future::poll_fn(|cx| Ok::<_, Never>(link.poll_play(cx, &captured)))).unwrap();
+    alloc_check::forbid(|| path.cycle(iter::once(&input[..]), outputs.iter_mut().map(|x| &mut x[..])));
+    assert_eq!(outputs, [input, [0.0; 4]]);
+    assert_eq!(counters.snapshot().mismatched_outputs, 1);
+
+    // a frame at the wrong rate is left out, so silence is played instead
+    let wrong_rate = Frame {
+        rate: 44100.0,
+        data: Array2::ones((4, 2)),
+    };
+    block_on(future::poll_fn(|cx| Ok::<_, Never>(link.poll_play(cx, &wrong_rate)))).unwrap();
+    alloc_check::forbid(|| path.cycle(iter::once(&input[..]), outputs.iter_mut().map(|x| &mut x[..])));
+    assert_eq!(outputs, [[0.0; 4]; 2]);
+    let stats = counters.snapshot();
+    assert_eq!(stats.mismatched_outputs, 2);
+    assert_eq!(
+        stats.last_mismatch,
+        Some(FrameMismatch::Rate {
+            expected: 48000,
+            found: 44100.0,
+        })
+    );
+    #[cfg(feature = "alloc-check")]
+    assert_eq!(alloc_check::violations(), before);
+}
+
+#[test]
+fn test_wav_backend() {
+    use futures::executor::block_on;
+    use std::env;
+    use std::fs::File;
+    use wav;
+
+    let dir = env::temp_dir();
+    let input = dir.join(format!("flow-synth-backend-in-{}.wav", std::process::id()));
+    let output = dir.join(format!("flow-synth-backend-out-{}.wav", std::process::id()));
+    let spec = wav::WavSpec {
+        channels: 2,
+        rate: 8000,
+    };
+    let samples: Vec<f32> = (0..64).map(|x| x as f32 / 64.0).collect();
+    wav::write(File::create(&input).unwrap(), spec, &samples).unwrap();
+
+    let config = AudioIOConfig {
+        backend: BackendConfig::Wav {
+            input: Some(input.clone()),
+            output: Some(output.clone()),
+            rate: 8000,
+            block_size: 16,
+        },
+        n_inputs: 3,
+        n_outputs: 1,
+        ..AudioIOConfig::default()
+    };
+    let waker = RemoteWaker::new();
+    let counters = Arc::new(Counters::default());
+    let mut pool = None;
+    let mut backend = audio_backend::new_backend(&config.backend);
+    backend
+        .open(&config, &mut |rate, buffer_size| {
+            let frames = Arc::new(FramePool::new(rate, buffer_size, 3, 1, waker.thread()));
+            pool = Some(frames.clone());
+            DataPath::new(frames, counters.clone(), Breaker::new())
+        }).unwrap();
+    let link = Link {
+        pool: Mutex::new(pool),
+        waker,
+        counters,
+    };
+
+    // the first block of the file, with the channel it doesn't have left silent
+    let frame = block_on(future::poll_fn(|cx| Ok::<_, Never>(link.poll_captured(cx)))).unwrap();
+    assert_eq!(frame.rate, 8000.0);
+    assert_eq!(frame.data.shape(), &[16, 3]);
+    for i in 0..16 {
+        assert_eq!(frame.data.row(i).to_vec(), vec![samples[i * 2], samples[i * 2 + 1], 0.0]);
+    }
+
+    // nothing was played, so the recording is silent
+    backend.close();
+    let (out_spec, recorded) = wav::read(File::open(&output).unwrap()).unwrap();
+    assert_eq!(
+        out_spec,
+        wav::WavSpec {
+            channels: 1,
+            rate: 8000,
+        }
+    );
+    assert!(!recorded.is_empty() && recorded.len() % 16 == 0);
+    assert!(recorded.iter().all(|&x| x == 0.0));
+    let _ = std::fs::remove_file(input);
+    let _ = std::fs::remove_file(output);
 }
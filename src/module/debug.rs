@@ -12,24 +12,55 @@ use std::fmt::Debug;
 use std::marker::PhantomData;
 use std::sync::Arc;
 
+/// Register lossless numeric conversions, such as widening an `i32` to an `i64`.
+pub fn register_adapters(graph: &flow::Graph) {
+    macro_rules! widen {
+        ($from:ty => $($to:ty),*) => {
+            $(graph.register_adapter(|x: $from| x as $to);)*
+        };
+    }
+    widen!(i8 => i16, i32, i64, f32, f64);
+    widen!(i16 => i32, i64, f32, f64);
+    widen!(i32 => i64, f64);
+    widen!(u8 => u16, u32, u64, usize, i16, i32, i64, f32, f64);
+    widen!(u16 => u32, u64, usize, i32, i64, f32, f64);
+    widen!(u32 => u64, i64, f64);
+    widen!(f32 => f64);
+}
+
+/// Types a `Printer` can be made for, each with a name of its own so that saved patches can tell the
+/// printers apart.
+pub trait PrinterType: Debug + Send + Sync + 'static {
+    const NAME: &'static str;
+}
+impl PrinterType for i32 {
+    // kept from when this was the only printer
+    const NAME: &'static str = "Printer";
+}
+impl PrinterType for i64 {
+    const NAME: &'static str = "Printer (i64)";
+}
+
 pub struct Printer<T: Debug + Send + Sync + 'static> {
     ifc: Arc<flow::Interface>,
     port: Arc<flow::Port<T, usize>>,
     breaker: Breaker,
     _t: PhantomData<T>,
 }
-impl<T: Debug + Send + Sync + 'static> Module for Printer<T> {
+impl<T: PrinterType> Module for Printer<T> {
     fn new(ifc: Arc<flow::Interface>) -> Printer<T> {
+        let breaker = ifc.breaker();
         let port = ifc.get_or_create_port::<T, usize>("Input".into());
+        port.set_tag("control");
         Printer {
             ifc,
             port,
-            breaker: Breaker::new(),
+            breaker,
             _t: PhantomData,
         }
     }
     fn name() -> &'static str {
-        "Printer"
+        T::NAME
     }
     fn start<Ex: executor::Executor>(&mut self, mut exec: Ex) {
         exec.spawn(Box::new(future::loop_fn(
@@ -71,11 +102,13 @@ pub struct Counter<T: Copy + One + Zero + Add + Send + 'static> {
 }
 impl<T: Copy + One + Zero + Add + Send + 'static> Module for Counter<T> {
     fn new(ifc: Arc<flow::Interface>) -> Counter<T> {
+        let breaker = ifc.breaker();
         let port = ifc.get_or_create_port::<usize, T>("Output".into());
+        port.set_tag("control");
         Counter {
             ifc,
             port,
-            breaker: Breaker::new(),
+            breaker,
             _t: PhantomData,
         }
     }
@@ -119,3 +152,41 @@ impl<T: Copy + One + Zero + Add + Send + 'static> Module for Counter<T> {
         self.ifc.ports()
     }
 }
+
+#[test]
+fn test_remove_node_stops_module() {
+    use futures::executor::{block_on, SpawnError};
+    use futures::never::Never;
+    use std::sync::{mpsc, Mutex};
+    use std::thread;
+    use std::time::Duration;
+
+    type Task = Box<dyn Future<Item = (), Error = Never> + Send>;
+    // keeps what the module spawns, so that the test can tell when it finishes
+    struct Capture(Arc<Mutex<Vec<Task>>>);
+    impl executor::Executor for Capture {
+        fn spawn(&mut self, task: Task) -> Result<(), SpawnError> {
+            self.0.lock().unwrap().push(task);
+            Ok(())
+        }
+    }
+
+    let graph = flow::Graph::new();
+    let ifc = graph.add_node();
+    let id = ifc.id();
+    let mut counter = Counter::<i32>::new(ifc);
+    let tasks = Arc::new(Mutex::new(Vec::new()));
+    counter.start(Capture(tasks.clone()));
+    let task = tasks.lock().unwrap().pop().unwrap();
+    let (done_tx, done_rx) = mpsc::channel();
+    thread::spawn(move || {
+        let _ = block_on(task);
+        let _ = done_tx.send(());
+    });
+
+    // the counter waits for a request that never comes, until its node is removed without `stop`
+    thread::sleep(Duration::from_millis(50));
+    assert!(done_rx.try_recv().is_err());
+    graph.remove_node(id).unwrap();
+    assert!(done_rx.recv_timeout(Duration::from_secs(1)).is_ok());
+}
@@ -3,18 +3,20 @@
  * become something completely different in the end.
  */
 
-use future_ext::Lock;
+use future_ext::{Breaker, FutureWrapExt, Lock};
 
+use futures::channel::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use futures::executor::Executor;
+use futures::future;
+use futures::never::Never;
 use futures::prelude::*;
 use futures::task::Context;
 
-use std::any::TypeId;
+use std::any::{self, Any, TypeId};
 use std::collections::{BTreeMap, HashMap, VecDeque};
-use std::marker::PhantomData;
-use std::mem;
-use std::slice;
+use std::fmt;
 use std::sync::atomic::{AtomicUsize, Ordering};
-use std::sync::{Arc, RwLock, Weak};
+use std::sync::{Arc, Mutex, RwLock, Weak};
 
 /// A lightweight persistent identifier for a node.
 #[derive(Copy, Clone, PartialEq, Eq, Hash, Debug, PartialOrd, Ord, Serialize, Deserialize)]
@@ -25,11 +27,103 @@ pub struct NodeId(pub usize);
 #[derive(Copy, Clone, PartialEq, Eq, Hash, Debug, PartialOrd, Ord, Serialize, Deserialize)]
 pub struct PortId(pub usize);
 
+/// A human readable description of what flows through a port.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct PortType {
+    /// Name of the type of data flowing in.
+    pub input: &'static str,
+    /// Name of the type of data flowing out.
+    pub output: &'static str,
+    /// An optional semantic tag, such as "audio", "control", "midi" or "request".
+    pub tag: Option<&'static str>,
+}
+
+impl fmt::Display for PortType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "in: {}, out: {}", self.input, self.output)?;
+        if let Some(tag) = self.tag {
+            write!(f, " ({})", tag)?;
+        }
+        Ok(())
+    }
+}
+
+/// A change to the topology of a `Graph`. Ports are identified by their node and port IDs.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum GraphEvent {
+    NodeAdded(NodeId),
+    NodeRemoved(NodeId),
+    PortAdded(NodeId, PortId),
+    PortRemoved(NodeId, PortId),
+    Connected((NodeId, PortId), (NodeId, PortId)),
+    Disconnected((NodeId, PortId), (NodeId, PortId)),
+}
+
+/// Audio settings shared by every node of a graph, so that connected modules agree on the shape of
+/// the audio frames they exchange.
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub struct AudioConfig {
+    /// Samples per second.
+    pub rate: u32,
+    /// Samples in each frame.
+    pub block_size: usize,
+    /// Channels in each frame.
+    pub channels: usize,
+}
+
+impl Default for AudioConfig {
+    fn default() -> AudioConfig {
+        AudioConfig {
+            rate: 48000,
+            block_size: 256,
+            channels: 2,
+        }
+    }
+}
+
+/// The audio settings of a graph and its children, along with everyone watching for changes.
+#[derive(Default)]
+struct AudioSettings {
+    config: RwLock<AudioConfig>,
+    watchers: Mutex<Vec<UnboundedSender<AudioConfig>>>,
+    // the settings each outstanding `AudioClaim` asked for, by claim id
+    claims: Mutex<Vec<(usize, AudioConfig)>>,
+    claim_counter: AtomicUsize,
+}
+
+/// A hold on the audio settings of a graph, taken with `Graph::claim_audio_config`. Nobody can claim
+/// different settings until it is dropped.
+pub struct AudioClaim {
+    audio: Arc<AudioSettings>,
+    id: usize,
+}
+impl Drop for AudioClaim {
+    fn drop(&mut self) {
+        self.audio.claims.lock().unwrap().retain(|&(id, _)| id != self.id);
+    }
+}
+
+/// A type-erased conversion between the items of two ports, as registered with
+/// `Graph::register_adapter`.
+type Convert = dyn Fn(Box<dyn Any + Send>) -> Box<dyn Any + Send> + Send + Sync;
+
 /// A graph holds a collection of Nodes. Nodes have a collection of Ports. Ports can be connected
-/// to each other one-to-one.
+/// to any number of other ports.
+///
+/// Ports of mismatched types can still be connected if the graph knows how to convert between
+/// them. In that case a hidden adapter node is inserted between the two ports, which forwards and
+/// converts data in both directions. Adapter nodes don't show up in `GraphEvent`s, and
+/// `OpaquePort::edges` reports the ports on the far side of them instead.
 pub struct Graph {
     nodes: RwLock<HashMap<NodeId, Arc<Node>>>,
     id_counter: AtomicUsize,
+    subscribers: Mutex<Vec<UnboundedSender<GraphEvent>>>,
+    // shared with child graphs
+    executor: Arc<Mutex<Option<Box<dyn Executor + Send>>>>,
+    conversions: Arc<RwLock<HashMap<(TypeId, TypeId), Arc<Convert>>>>,
+    audio: Arc<AudioSettings>,
+    // hidden adapter nodes, and the two ports each of them joins
+    adapters: Mutex<HashMap<NodeId, ((NodeId, PortId), (NodeId, PortId))>>,
 }
 
 impl Graph {
@@ -38,6 +132,24 @@ impl Graph {
         Arc::new(Graph {
             nodes: RwLock::new(HashMap::new()),
             id_counter: 0.into(),
+            subscribers: Mutex::new(Vec::new()),
+            executor: Arc::new(Mutex::new(None)),
+            conversions: Arc::new(RwLock::new(HashMap::new())),
+            audio: Arc::default(),
+            adapters: Mutex::new(HashMap::new()),
+        })
+    }
+    /// Make a new empty graph which shares its executor, adapters and audio settings with this one,
+    /// such as the patch inside a macro module.
+    pub fn new_child(&self) -> Arc<Graph> {
+        Arc::new(Graph {
+            nodes: RwLock::new(HashMap::new()),
+            id_counter: 0.into(),
+            subscribers: Mutex::new(Vec::new()),
+            executor: Arc::clone(&self.executor),
+            conversions: Arc::clone(&self.conversions),
+            audio: Arc::clone(&self.audio),
+            adapters: Mutex::new(HashMap::new()),
         })
     }
     /// Construct a new node from the given metadata and argument.
@@ -52,15 +164,24 @@ impl Graph {
             ifc: ifc.clone(),
         });
         self.nodes.write().unwrap().insert(node.id(), node);
+        self.notify(GraphEvent::NodeAdded(id));
         ifc
     }
-    /// Delete a node by id.
+    /// Delete a node by id. Its module is stopped through `Interface::breaker`, and all of its ports
+    /// are disconnected and closed: any reads or writes pending on them fail with `Error::Closed`,
+    /// as will any started later.
     pub fn remove_node(&self, node: NodeId) -> Result<Arc<Node>, Error> {
-        self.nodes
+        let node = self
+            .nodes
             .write()
             .unwrap()
             .remove(&node)
-            .ok_or(Error::InvalidNode)
+            .ok_or(Error::InvalidNode)?;
+        // the module's tasks see this once their pending reads and writes fail
+        node.ifc.breaker.brake();
+        node.ifc.close();
+        self.notify(GraphEvent::NodeRemoved(node.id()));
+        Ok(node)
     }
     /// Returns a vector containing references to all nodes active at the time of the call.
     pub fn nodes(&self) -> Vec<Arc<Node>> {
@@ -74,6 +195,214 @@ impl Graph {
     pub fn node(&self, id: NodeId) -> Option<Arc<Node>> {
         self.nodes.read().unwrap().get(&id).cloned()
     }
+    /// Returns a `Stream` of every change made to the graph from now on. The stream ends when the
+    /// graph is dropped.
+    pub fn subscribe(&self) -> UnboundedReceiver<GraphEvent> {
+        let (tx, rx) = mpsc::unbounded();
+        self.subscribers.lock().unwrap().push(tx);
+        rx
+    }
+
+    /// Get the audio settings of the graph.
+    pub fn audio_config(&self) -> AudioConfig {
+        *self.audio.config.read().unwrap()
+    }
+    /// Change the audio settings of the graph and its children. Everyone watching for changes is told
+    /// about the new settings, unless they are the same as before.
+    pub fn set_audio_config(&self, config: AudioConfig) {
+        {
+            let mut current = self.audio.config.write().unwrap();
+            if *current == config {
+                return;
+            }
+            *current = config;
+        }
+        // forget about watchers that have gone away
+        self.audio
+            .watchers
+            .lock()
+            .unwrap()
+            .retain(|tx| tx.unbounded_send(config).is_ok());
+    }
+    /// Change the audio settings like `set_audio_config`, on behalf of something that can't work with
+    /// any others, such as an audio device. If someone else holds a claim on different settings,
+    /// nothing changes and those settings are returned instead.
+    pub fn claim_audio_config(&self, config: AudioConfig) -> Result<AudioClaim, AudioConfig> {
+        let mut claims = self.audio.claims.lock().unwrap();
+        if let Some(&(_, claimed)) = claims.iter().find(|&&(_, claimed)| claimed != config) {
+            return Err(claimed);
+        }
+        let id = self.audio.claim_counter.fetch_add(1, Ordering::Relaxed);
+        claims.push((id, config));
+        self.set_audio_config(config);
+        Ok(AudioClaim {
+            audio: self.audio.clone(),
+            id,
+        })
+    }
+    /// Returns a `Stream` of the audio settings of the graph, each time they change from now on.
+    pub fn watch_audio_config(&self) -> UnboundedReceiver<AudioConfig> {
+        let (tx, rx) = mpsc::unbounded();
+        self.audio.watchers.lock().unwrap().push(tx);
+        rx
+    }
+
+    /// Set the executor used to run the graph's own tasks, such as adapter nodes. Until this is
+    /// called, ports of mismatched types can't be connected.
+    pub fn set_executor<Ex: Executor + Send + 'static>(&self, exec: Ex) {
+        *self.executor.lock().unwrap() = Some(Box::new(exec));
+    }
+    /// Spawn a task on the graph's executor. Fails with `Error::NotAvailable` if no executor has
+    /// been set.
+    pub fn spawn(&self, task: Box<dyn Future<Item = (), Error = Never> + Send>) -> Result<(), Error> {
+        match *self.executor.lock().unwrap() {
+            Some(ref mut exec) => exec.spawn(task).map_err(|_| Error::NotAvailable),
+            None => Err(Error::NotAvailable),
+        }
+    }
+    /// Register a conversion from items of type `A` to items of type `B`. From now on, a port
+    /// producing `A`s can be connected to a port consuming `B`s, as long as a conversion for the
+    /// opposite direction is also known. Conversions between identical types are implicit.
+    pub fn register_adapter<A, B, F>(&self, convert: F)
+    where
+        A: Send + 'static,
+        B: Send + 'static,
+        F: Fn(A) -> B + Send + Sync + 'static,
+    {
+        let convert = move |item: Box<dyn Any + Send>| -> Box<dyn Any + Send> {
+            Box::new(convert(*item.downcast::<A>().unwrap()))
+        };
+        self.conversions
+            .write()
+            .unwrap()
+            .insert((TypeId::of::<A>(), TypeId::of::<B>()), Arc::new(convert));
+    }
+    /// Determines if data of type `from` can be converted to data of type `to`.
+    pub fn can_adapt(&self, from: TypeId, to: TypeId) -> bool {
+        self.conversion(from, to).is_some()
+    }
+    fn conversion(&self, from: TypeId, to: TypeId) -> Option<Arc<Convert>> {
+        if from == to {
+            return Some(Arc::new(|item| item));
+        }
+        self.conversions.read().unwrap().get(&(from, to)).cloned()
+    }
+
+    /// Join two ports of mismatched types with a hidden adapter node.
+    fn connect_adapted(
+        self: &Arc<Graph>,
+        a: &Arc<OpaquePort>,
+        b: &Arc<OpaquePort>,
+    ) -> Result<(), ConnectError> {
+        let mismatch = || ConnectError::TypeMismatch(a.port_type(), b.port_type());
+        let a_to_b = self.conversion(a.out_type(), b.in_type()).ok_or_else(mismatch)?;
+        let b_to_a = self.conversion(b.out_type(), a.in_type()).ok_or_else(mismatch)?;
+        let link = ((a.node_id(), a.id()), (b.node_id(), b.id()));
+        if self.find_adapter(link.0, link.1).is_some() {
+            return Err(ConnectError::AlreadyConnected);
+        }
+        if self.executor.lock().unwrap().is_none() {
+            // nothing could run the adapter
+            return Err(mismatch());
+        }
+
+        // register the node before adding it, so that no events are sent for it
+        let id = NodeId(self.generate_id());
+        self.adapters.lock().unwrap().insert(id, link);
+        let ifc = self.add_node_with_id(id);
+        let side_a = a.mirror(&ifc, "A".into());
+        let side_b = b.mirror(&ifc, "B".into());
+        let result = Arc::clone(a)
+            .connect_opaque(&side_a)
+            .and_then(|_| Arc::clone(b).connect_opaque(&side_b))
+            .and_then(|_| {
+                let graph = Arc::downgrade(self);
+                let unplugged: Arc<dyn Fn() + Send + Sync> = Arc::new(move || {
+                    // one side of the adapter was disconnected, so it has no purpose anymore
+                    if let Some(graph) = graph.upgrade() {
+                        graph.remove_adapter(id);
+                    }
+                });
+                let a_to_b = forward(side_a.clone(), side_b.clone(), a_to_b, unplugged.clone());
+                let b_to_a = forward(side_b, side_a, b_to_a, unplugged);
+                self.spawn(a_to_b)
+                    .and_then(|_| self.spawn(b_to_a))
+                    .map_err(|_| mismatch())
+            });
+        match result {
+            Ok(()) => {
+                self.notify(GraphEvent::Connected(link.0, link.1));
+                Ok(())
+            }
+            Err(err) => {
+                let _ = self.remove_node(id);
+                self.adapters.lock().unwrap().remove(&id);
+                Err(err)
+            }
+        }
+    }
+    /// Remove the adapter node joining two ports, if there is one.
+    fn disconnect_adapted(&self, a: &Arc<OpaquePort>, b: &Arc<OpaquePort>) -> Result<(), ConnectError> {
+        let id = self
+            .find_adapter((a.node_id(), a.id()), (b.node_id(), b.id()))
+            .ok_or(ConnectError::NotConnected)?;
+        self.remove_adapter(id);
+        Ok(())
+    }
+    fn find_adapter(&self, a: (NodeId, PortId), b: (NodeId, PortId)) -> Option<NodeId> {
+        self.adapters
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|&(_, &link)| link == (a, b) || link == (b, a))
+            .map(|(&id, _)| id)
+    }
+    fn remove_adapter(&self, id: NodeId) {
+        let link = self.adapters.lock().unwrap().get(&id).cloned();
+        if let Some((a, b)) = link {
+            // both directions notice the disconnect, but only one of them gets to remove the node
+            if self.remove_node(id).is_ok() {
+                self.adapters.lock().unwrap().remove(&id);
+                self.notify(GraphEvent::Disconnected(a, b));
+            }
+        }
+    }
+    /// If `peer` belongs to an adapter node, get the port on the far side of it as seen from
+    /// `port`. Otherwise returns `peer` itself.
+    fn resolve_adapter(&self, port: &OpaquePort, peer: Arc<OpaquePort>) -> Arc<OpaquePort> {
+        let link = self.adapters.lock().unwrap().get(&peer.node_id()).cloned();
+        let far = match link {
+            Some((a, b)) if a == (port.node_id(), port.id()) => b,
+            Some((a, _)) => a,
+            None => return peer,
+        };
+        self.node(far.0)
+            .and_then(|node| node.port(far.1))
+            .unwrap_or(peer)
+    }
+    fn is_adapter(&self, node: NodeId) -> bool {
+        self.adapters.lock().unwrap().contains_key(&node)
+    }
+
+    fn notify(&self, event: GraphEvent) {
+        let hidden = match event {
+            GraphEvent::NodeAdded(node)
+            | GraphEvent::NodeRemoved(node)
+            | GraphEvent::PortAdded(node, _)
+            | GraphEvent::PortRemoved(node, _) => self.is_adapter(node),
+            GraphEvent::Connected(a, b) | GraphEvent::Disconnected(a, b) => {
+                self.is_adapter(a.0) || self.is_adapter(b.0)
+            }
+        };
+        if hidden {
+            return;
+        }
+        // forget about subscribers that have gone away
+        self.subscribers
+            .lock()
+            .unwrap()
+            .retain(|tx| tx.unbounded_send(event).is_ok());
+    }
 
     fn generate_id(&self) -> usize {
         self.id_counter.fetch_add(1, Ordering::SeqCst)
@@ -95,8 +424,12 @@ impl Node {
         self.ifc.id()
     }
     /// Find a port by name
-    pub fn find_port(&self, name: &'static str) -> Option<Arc<OpaquePort>> {
-        self.ifc.find_port(name)
+    pub fn find_port(&self, name: &str) -> Option<Arc<OpaquePort>> {
+        self.ifc.find_opaque_port(name)
+    }
+    /// Get a port by id.
+    pub fn port(&self, id: PortId) -> Option<Arc<OpaquePort>> {
+        self.ifc.port(id)
     }
     /// Get a vector of references to all associated ports at the time of the call.
     pub fn ports(&self) -> Vec<Arc<OpaquePort>> {
@@ -104,6 +437,53 @@ impl Node {
     }
 }
 
+/// Returns a task which forwards data in both directions between two ports of opposite types, as
+/// if their peers were connected to each other directly. This joins an exposed port of a macro
+/// module to its counterpart inside the macro, so the ports may belong to different graphs. The
+/// task ends once both ports have been closed.
+pub fn bridge(a: &Arc<OpaquePort>, b: &Arc<OpaquePort>) -> Box<dyn Future<Item = (), Error = Never> + Send> {
+    let identity: Arc<Convert> = Arc::new(|item| item);
+    let ignore: Arc<dyn Fn() + Send + Sync> = Arc::new(|| {});
+    Box::new(
+        forward(a.clone(), b.clone(), identity.clone(), ignore.clone())
+            .join(forward(b.clone(), a.clone(), identity, ignore))
+            .map(|_| ()),
+    )
+}
+
+/// Moves data from `from` to the peers of `to`, converting it on the way. Stops once either port is
+/// closed, and calls `unplugged` whenever a disconnect interrupts it.
+fn forward(
+    from: Arc<OpaquePort>,
+    to: Arc<OpaquePort>,
+    convert: Arc<Convert>,
+    unplugged: Arc<dyn Fn() + Send + Sync>,
+) -> Box<dyn Future<Item = (), Error = Never> + Send> {
+    Box::new(future::loop_fn((from, to), move |(from, to)| {
+        let convert = convert.clone();
+        let unplugged = unplugged.clone();
+        from.read_any()
+            .wrap(to)
+            .map_err(|(to, (from, err))| (from, to, err))
+            .and_then(move |(to, (from, data))| {
+                let data = data.into_iter().map(|item| convert(item)).collect();
+                to.write_any(data)
+                    .wrap(from)
+                    .map_err(|(from, (to, err))| (from, to, err))
+            })
+            .then(move |result| -> Result<_, Never> {
+                match result {
+                    Ok((from, to)) => Ok(future::Loop::Continue((from, to))),
+                    Err((_, _, Error::Closed)) => Ok(future::Loop::Break(())),
+                    Err((from, to, _)) => {
+                        unplugged();
+                        Ok(future::Loop::Continue((from, to)))
+                    }
+                }
+            })
+    }))
+}
+
 /// The private interface for a module. The module is provided with an `Interface` upon construction.
 /// An `Interface` has a superset of the functionality of a `Node`. It can be used to manipulate the
 /// associated Ports.
@@ -111,6 +491,7 @@ pub struct Interface {
     id: NodeId,
     ports: RwLock<BTreeMap<PortId, Arc<OpaquePort>>>,
     graph: Weak<Graph>,
+    breaker: Breaker,
 }
 
 impl Interface {
@@ -119,14 +500,36 @@ impl Interface {
             id,
             ports: RwLock::new(BTreeMap::new()),
             graph: Arc::downgrade(graph),
+            breaker: Breaker::new(),
         }
     }
     /// Get the node ID.
     pub fn id(&self) -> NodeId {
         self.id
     }
+    /// Get the breaker that is tripped when the node is removed. Modules stop their tasks on it, so
+    /// that removing a node stops its module even when nobody calls `Module::stop`.
+    pub fn breaker(&self) -> Breaker {
+        self.breaker.clone()
+    }
+    /// Get the graph this node belongs to, unless it has been dropped.
+    pub fn graph(&self) -> Option<Arc<Graph>> {
+        self.graph.upgrade()
+    }
+    /// Get the audio settings of the graph, or the defaults if the graph has been dropped.
+    pub fn audio_config(&self) -> AudioConfig {
+        self.graph().map(|graph| graph.audio_config()).unwrap_or_default()
+    }
+    /// Returns a `Stream` of the audio settings of the graph, each time they change. The stream ends
+    /// when the graph is dropped.
+    pub fn watch_audio_config(&self) -> UnboundedReceiver<AudioConfig> {
+        match self.graph() {
+            Some(graph) => graph.watch_audio_config(),
+            None => mpsc::unbounded().1,
+        }
+    }
     /// Find a port by name and type.
-    pub fn find_port<I: 'static, O: 'static>(&self, name: &str) -> Option<Arc<Port<I, O>>> {
+    pub fn find_port<I: Send + 'static, O: Send + 'static>(&self, name: &str) -> Option<Arc<Port<I, O>>> {
         self.ports
             .read()
             .unwrap()
@@ -134,114 +537,408 @@ impl Interface {
             .filter(|&(_, port)| port.name() == name)
             .filter_map(|(_, port)| port.as_typed::<I, O>())
             .next()
+    }
+    /// Find a port by name, regardless of its type.
+    pub fn find_opaque_port(&self, name: &str) -> Option<Arc<OpaquePort>> {
+        self.ports
+            .read()
+            .unwrap()
+            .values()
+            .find(|port| port.name() == name)
             .cloned()
     }
+    /// Get a port by id.
+    pub fn port(&self, id: PortId) -> Option<Arc<OpaquePort>> {
+        self.ports.read().unwrap().get(&id).cloned()
+    }
     /// Get a vector of references to all associated ports at the time of the call.
     pub fn ports(&self) -> Vec<Arc<OpaquePort>> {
         self.ports.read().unwrap().values().cloned().collect()
     }
     /// Find a port by name and type if it exists, or add a new one if not.
-    pub fn get_or_create_port<I: 'static, O: 'static>(&self, name: String) -> Arc<Port<I, O>> {
+    pub fn get_or_create_port<I: Send + 'static, O: Send + 'static>(
+        &self,
+        name: String,
+    ) -> Arc<Port<I, O>> {
         if let Some(port) = self.find_port(&name) {
             port
         } else {
-            let port = Port::new(&self.graph.upgrade().unwrap(), self.id(), name);
+            let graph = self.graph.upgrade().unwrap();
+            let port = Port::new(&graph, self.id(), name);
             self.ports
                 .write()
                 .unwrap()
-                .insert(port.id, Arc::clone(port.as_opaque()));
+                .insert(port.id, port.as_opaque());
+            graph.notify(GraphEvent::PortAdded(self.id(), port.id()));
             port
         }
     }
-    /// Remove a port by ID.
+    /// Find a port by name and type if it exists, or add a new one if not. In both cases the
+    /// port's buffer is limited to `capacity` items, with `overflow` deciding what happens to
+    /// writes that would exceed it.
+    pub fn get_or_create_bounded_port<I: Send + 'static, O: Send + 'static>(
+        &self,
+        name: String,
+        capacity: usize,
+        overflow: Overflow,
+    ) -> Arc<Port<I, O>> {
+        let port = self.get_or_create_port(name);
+        port.set_capacity(Some(capacity), overflow);
+        port
+    }
+    /// Remove a port by ID. The port is disconnected and closed, like the ports of a removed node.
     pub fn remove_port(&self, port: PortId) -> Result<Arc<OpaquePort>, Error> {
-        self.ports
+        let port = self
+            .ports
             .write()
             .unwrap()
             .remove(&port)
-            .ok_or(Error::InvalidPort)
+            .ok_or(Error::InvalidPort)?;
+        Arc::clone(&port).close_opaque();
+        if let Some(graph) = self.graph.upgrade() {
+            graph.notify(GraphEvent::PortRemoved(self.id(), port.id()));
+        }
+        Ok(port)
+    }
+    fn close(&self) {
+        for port in self.ports() {
+            port.close_opaque();
+        }
     }
 }
 
-/// Ports are the connection points of modules. They can be connected one-to-one with other ports,
+/// Ports are the connection points of modules. They can be connected to any number of other ports,
 /// allowing data of type `I` to flow in and data of type `O` to flow out.
 ///
+/// Fan-out: data written to a port is duplicated to every connected peer.
+///
+/// Fan-in: when several peers write to the same port, their writes are interleaved in its buffer
+/// in the order they acquire it. The items of a single `write` call are always kept contiguous, so
+/// a reader using `read_n` with the same item count as its writers never sees a torn write.
+///
+/// Items are stored and moved as real values of type `I`, so payloads with destructors are dropped
+/// exactly once.
+///
 /// TODO think about interactions/problems with multiple graphs
 pub struct Port<I: 'static, O: 'static> {
-    // it is UNSAFE to use the type parameters I and O to store real data here
-    // because of how OpaquePort is implemented!
-    // only PhantomData is ok!
-    _in: PhantomData<I>,
-    _out: PhantomData<O>,
-    in_ty: TypeId,
-    out_ty: TypeId,
-
     name: String,
     id: PortId,
-    inner: Lock<PortInner>,
+    inner: Lock<PortInner<I>>,
     edge: Lock<Edge<I, O>>,
     node_id: NodeId,
+    graph: Weak<Graph>,
+    tag: RwLock<Option<&'static str>>,
 }
 
-struct PortInner {
-    buffer: VecDeque<u8>,
-    buffer_size: usize,
+struct PortInner<I: 'static> {
+    buffer: VecDeque<I>,
+    capacity: Option<usize>,
+    overflow: Overflow,
     disconnect_occured: bool,
+    closed: bool,
     read_wait: Vec<task::Waker>,
+    write_wait: Vec<task::Waker>,
+}
+
+/// Determines what happens when a write would grow a bounded port's buffer past its capacity.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Overflow {
+    /// The writer waits until a reader has drained enough items to make room. A write larger than
+    /// the capacity is accepted once the buffer is empty, so it can never wait forever.
+    Block,
+    /// The write always succeeds, and the oldest buffered items are discarded to make room.
+    /// Useful for real-time paths where stale data is worthless.
+    DropOldest,
 }
 
 struct Edge<I: 'static, O: 'static> {
-    other: Option<Weak<Port<O, I>>>,
+    others: Vec<Weak<Port<O, I>>>,
     connect_wait: Vec<task::Waker>,
+    closed: bool,
+}
+
+impl<I: 'static, O: 'static> Edge<I, O> {
+    fn peers(&self) -> Vec<Arc<Port<O, I>>> {
+        self.others.iter().filter_map(|x| x.upgrade()).collect()
+    }
+    fn is_connected_to(&self, id: PortId) -> bool {
+        self.others
+            .iter()
+            .filter_map(|x| x.upgrade())
+            .any(|other| other.id() == id)
+    }
+    fn remove(&mut self, id: PortId) {
+        self.others
+            .retain(|x| x.upgrade().map(|other| other.id() != id).unwrap_or(false));
+    }
+}
+
+// the buffer and edge are only accessed through their `Lock`s, so sharing a port between threads
+// only ever moves values of type I and O between them.
+unsafe impl<I: Send + 'static, O: Send + 'static> Send for Port<I, O> {}
+unsafe impl<I: Send + 'static, O: Send + 'static> Sync for Port<I, O> {}
+
+/// The type-erased interface of a `Port`. This is implemented for all `Port`s, and is mostly
+/// useful through the `OpaquePort` alias.
+pub trait AnyPort: Send + Sync + 'static {
+    /// Get the PortId.
+    fn id(&self) -> PortId;
+    /// Get the port name.
+    fn name(&self) -> &str;
+    /// Get the NodeId.
+    fn node_id(&self) -> NodeId;
+    /// Get the `TypeId` of the data flowing into this port.
+    fn in_type(&self) -> TypeId;
+    /// Get the `TypeId` of the data flowing out of this port.
+    fn out_type(&self) -> TypeId;
+    /// Get a human readable description of the data flowing through this port.
+    fn port_type(&self) -> PortType;
+    /// Get the number of items currently buffered in this port, waiting to be read.
+    fn len(&self) -> usize;
+    /// Get the maximum number of items this port will buffer, or None if it is unbounded.
+    fn capacity(&self) -> Option<usize>;
+    /// Get all ports this port is connected to at the time of the call.
+    fn opaque_edges(&self) -> Vec<Arc<OpaquePort>>;
+
+    #[doc(hidden)]
+    fn into_any(self: Arc<Self>) -> Arc<dyn Any + Send + Sync>;
+    #[doc(hidden)]
+    fn connect_opaque(self: Arc<Self>, other: &Arc<OpaquePort>) -> Result<(), ConnectError>;
+    #[doc(hidden)]
+    fn disconnect_from_opaque(self: Arc<Self>, other: &Arc<OpaquePort>) -> Result<(), ConnectError>;
+    #[doc(hidden)]
+    fn disconnect_opaque(self: Arc<Self>) -> Result<(), ConnectError>;
+    #[doc(hidden)]
+    fn close_opaque(self: Arc<Self>);
+    #[doc(hidden)]
+    fn graph(&self) -> Option<Arc<Graph>>;
+    #[doc(hidden)]
+    fn mirror(&self, ifc: &Interface, name: String) -> Arc<OpaquePort>;
+    #[doc(hidden)]
+    fn twin(&self, ifc: &Interface, name: String) -> Arc<OpaquePort>;
+    #[doc(hidden)]
+    fn read_any(self: Arc<Self>) -> ReadAnyFuture;
+    #[doc(hidden)]
+    fn write_any(self: Arc<Self>, data: Vec<Box<dyn Any + Send>>) -> WriteAnyFuture;
 }
 
-unsafe impl<I: 'static, O: 'static> Send for Port<I, O> {}
-unsafe impl<I: 'static, O: 'static> Sync for Port<I, O> {}
+/// A type-erased `Port::read`, used by adapter nodes.
+pub type ReadAnyFuture = Box<
+    dyn Future<Item = (Arc<OpaquePort>, Vec<Box<dyn Any + Send>>), Error = (Arc<OpaquePort>, Error)> + Send,
+>;
+/// A type-erased `Port::write`, used by adapter nodes.
+pub type WriteAnyFuture = Box<dyn Future<Item = Arc<OpaquePort>, Error = (Arc<OpaquePort>, Error)> + Send>;
 
 /// An OpaquePort is a port with erased types at the type level. It can be downcast to a typed port
 /// by calling `as_typed`.
-pub type OpaquePort = Port<!, !>;
+pub type OpaquePort = dyn AnyPort;
 
 impl OpaquePort {
     /// Downcasts this `OpaquePort` to a port with the given types. Returns None if the given types
     /// do not match the underlying port.
-    pub fn as_typed<'a, NewI: 'static, NewO: 'static>(
-        self: &'a Arc<OpaquePort>,
-    ) -> Option<&'a Arc<Port<NewI, NewO>>> {
-        if TypeId::of::<NewI>() == self.in_ty && TypeId::of::<NewO>() == self.out_ty {
-            Some(unsafe { mem::transmute::<&Arc<OpaquePort>, &Arc<Port<NewI, NewO>>>(self) })
-        } else {
-            None
+    pub fn as_typed<NewI: 'static, NewO: 'static>(self: &Arc<OpaquePort>) -> Option<Arc<Port<NewI, NewO>>> {
+        Arc::clone(self).into_any().downcast().ok()
+    }
+    /// Determines if two ports can be connected to each other, either directly or through an
+    /// adapter.
+    pub fn can_connect(self: &Arc<OpaquePort>, other: &Arc<OpaquePort>) -> bool {
+        let direct = self.in_type() == other.out_type() && self.out_type() == other.in_type();
+        let adapted = || {
+            self.graph()
+                .map(|graph| {
+                    graph.can_adapt(self.out_type(), other.in_type())
+                        && graph.can_adapt(other.out_type(), self.in_type())
+                })
+                .unwrap_or(false)
+        };
+        self.id() != other.id() && (direct || adapted())
+    }
+    /// Connect this port to another. If the ports have unmatched underlying types, they are
+    /// joined through an adapter node instead, and this fails with ConnectError::TypeMismatch
+    /// only if the graph has no adapter for them. See `Port::connect` for more information.
+    pub fn connect(self: &Arc<OpaquePort>, other: &Arc<OpaquePort>) -> Result<(), ConnectError> {
+        match Arc::clone(self).connect_opaque(other) {
+            Err(ConnectError::TypeMismatch(this, that)) => match self.graph() {
+                Some(graph) => graph.connect_adapted(self, other),
+                None => Err(ConnectError::TypeMismatch(this, that)),
+            },
+            result => result,
+        }
+    }
+    /// Disconnect this port from a specific peer, removing the adapter between them if there is
+    /// one. See `Port::disconnect_from`.
+    pub fn disconnect_from(self: &Arc<OpaquePort>, other: &Arc<OpaquePort>) -> Result<(), ConnectError> {
+        match Arc::clone(self).disconnect_from_opaque(other) {
+            Err(ConnectError::NotConnected) => match self.graph() {
+                Some(graph) => graph.disconnect_adapted(self, other),
+                None => Err(ConnectError::NotConnected),
+            },
+            result => result,
+        }
+    }
+    /// Disconnect this port from all of its peers, including those joined through adapters. See
+    /// `Port::disconnect`.
+    pub fn disconnect(self: &Arc<OpaquePort>) -> Result<(), ConnectError> {
+        let others = self.edges();
+        if others.is_empty() {
+            return Err(ConnectError::NotConnected);
+        }
+        for other in others {
+            match self.disconnect_from(&other) {
+                Ok(()) | Err(ConnectError::NotConnected) => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+    /// Get all ports this port is connected to at the time of the call. Peers joined through an
+    /// adapter are reported directly, rather than the adapter node's port.
+    pub fn edges(&self) -> Vec<Arc<OpaquePort>> {
+        let edges = self.opaque_edges();
+        match self.graph() {
+            Some(graph) => edges
+                .into_iter()
+                .map(|peer| graph.resolve_adapter(self, peer))
+                .collect(),
+            None => edges,
+        }
+    }
+}
+
+impl<I: Send + 'static, O: Send + 'static> AnyPort for Port<I, O> {
+    fn id(&self) -> PortId {
+        self.id
+    }
+    fn name(&self) -> &str {
+        &self.name
+    }
+    fn node_id(&self) -> NodeId {
+        self.node_id
+    }
+    fn in_type(&self) -> TypeId {
+        TypeId::of::<I>()
+    }
+    fn out_type(&self) -> TypeId {
+        TypeId::of::<O>()
+    }
+    fn port_type(&self) -> PortType {
+        Port::port_type(self)
+    }
+    fn len(&self) -> usize {
+        Port::len(self)
+    }
+    fn capacity(&self) -> Option<usize> {
+        Port::capacity(self)
+    }
+    fn opaque_edges(&self) -> Vec<Arc<OpaquePort>> {
+        self.edges().into_iter().map(|other| other.as_opaque()).collect()
+    }
+    fn into_any(self: Arc<Self>) -> Arc<dyn Any + Send + Sync> {
+        self
+    }
+    fn connect_opaque(self: Arc<Self>, other: &Arc<OpaquePort>) -> Result<(), ConnectError> {
+        let other = other
+            .as_typed::<O, I>()
+            .ok_or_else(|| ConnectError::TypeMismatch(self.port_type(), other.port_type()))?;
+        self.connect(&other)
+    }
+    fn disconnect_from_opaque(self: Arc<Self>, other: &Arc<OpaquePort>) -> Result<(), ConnectError> {
+        // ports of the wrong type can't possibly be connected to this one
+        let other = other.as_typed::<O, I>().ok_or(ConnectError::NotConnected)?;
+        self.disconnect_from(&other)
+    }
+    fn disconnect_opaque(self: Arc<Self>) -> Result<(), ConnectError> {
+        self.disconnect()
+    }
+    fn close_opaque(self: Arc<Self>) {
+        self.close()
+    }
+    fn graph(&self) -> Option<Arc<Graph>> {
+        self.graph.upgrade()
+    }
+    fn mirror(&self, ifc: &Interface, name: String) -> Arc<OpaquePort> {
+        ifc.get_or_create_port::<O, I>(name).as_opaque()
+    }
+    fn twin(&self, ifc: &Interface, name: String) -> Arc<OpaquePort> {
+        let twin = ifc.get_or_create_port::<I, O>(name);
+        if let Some(tag) = *self.tag.read().unwrap() {
+            twin.set_tag(tag);
         }
+        twin.as_opaque()
+    }
+    fn read_any(self: Arc<Self>) -> ReadAnyFuture {
+        Box::new(
+            self.read()
+                .map(|(port, data)| {
+                    let data = data
+                        .into_vec()
+                        .into_iter()
+                        .map(|item| Box::new(item) as Box<dyn Any + Send>)
+                        .collect();
+                    (port.as_opaque(), data)
+                })
+                .map_err(|(port, err)| (port.as_opaque(), err)),
+        )
+    }
+    fn write_any(self: Arc<Self>, data: Vec<Box<dyn Any + Send>>) -> WriteAnyFuture {
+        WriteAny::write_any(self, data)
+    }
+}
+
+// Writing requires cloning the data for fan-out, which not every port type supports.
+trait WriteAny {
+    fn write_any(self: Arc<Self>, data: Vec<Box<dyn Any + Send>>) -> WriteAnyFuture;
+}
+
+impl<I: Send + 'static, O: Send + 'static> WriteAny for Port<I, O> {
+    default fn write_any(self: Arc<Self>, _data: Vec<Box<dyn Any + Send>>) -> WriteAnyFuture {
+        Box::new(future::err((self.as_opaque(), Error::NotAvailable)))
     }
 }
 
-impl<I: 'static, O: 'static> Port<I, O> {
-    fn new(graph: &Graph, node_id: NodeId, name: String) -> Arc<Port<I, O>> {
+impl<I: Send + 'static, O: Send + Clone + 'static> WriteAny for Port<I, O> {
+    fn write_any(self: Arc<Self>, data: Vec<Box<dyn Any + Send>>) -> WriteAnyFuture {
+        let data = data
+            .into_iter()
+            .filter_map(|item| item.downcast::<O>().ok())
+            .map(|item| *item)
+            .collect();
+        Box::new(
+            self.write(data)
+                .map(|port| port.as_opaque())
+                .map_err(|(port, err)| (port.as_opaque(), err)),
+        )
+    }
+}
+
+impl<I: Send + 'static, O: Send + 'static> Port<I, O> {
+    fn new(graph: &Arc<Graph>, node_id: NodeId, name: String) -> Arc<Port<I, O>> {
         Arc::new(Port {
-            _in: PhantomData,
-            _out: PhantomData,
-            in_ty: TypeId::of::<I>(),
-            out_ty: TypeId::of::<O>(),
             name,
             id: PortId(graph.generate_id()),
             inner: Lock::new(PortInner {
                 buffer: VecDeque::new(),
-                buffer_size: 0,
+                capacity: None,
+                overflow: Overflow::Block,
                 disconnect_occured: false,
+                closed: false,
                 read_wait: Vec::new(),
+                write_wait: Vec::new(),
             }),
             edge: Lock::new(Edge {
-                other: None,
+                others: Vec::new(),
                 connect_wait: Vec::new(),
+                closed: false,
             }),
             node_id,
+            graph: Arc::downgrade(graph),
+            tag: RwLock::new(None),
         })
     }
 
     /// Erases types from the signature of this port, returning the corresponding OpaquePort.
-    pub fn as_opaque<'a>(self: &'a Arc<Port<I, O>>) -> &'a Arc<OpaquePort> {
-        unsafe { mem::transmute::<&Arc<Port<I, O>>, &Arc<OpaquePort>>(self) }
+    pub fn as_opaque(self: &Arc<Port<I, O>>) -> Arc<OpaquePort> {
+        Arc::clone(self)
     }
     /// Get the PortId.
     pub fn id(&self) -> PortId {
@@ -255,139 +952,224 @@ impl<I: 'static, O: 'static> Port<I, O> {
     pub fn node_id(&self) -> NodeId {
         self.node_id
     }
-    /// Determines if two ports can be connected to each other.
+    /// Get a human readable description of the data flowing through this port.
+    pub fn port_type(&self) -> PortType {
+        PortType {
+            input: any::type_name::<I>(),
+            output: any::type_name::<O>(),
+            tag: *self.tag.read().unwrap(),
+        }
+    }
+    /// Set the semantic tag of this port, such as "audio", "control", "midi" or "request".
+    pub fn set_tag(&self, tag: &'static str) {
+        *self.tag.write().unwrap() = Some(tag);
+    }
+    /// Get the number of items currently buffered in this port, waiting to be read.
+    pub fn len(&self) -> usize {
+        self.inner.spin_lock().buffer.len()
+    }
+    /// Get the maximum number of items this port will buffer, or None if it is unbounded.
+    pub fn capacity(&self) -> Option<usize> {
+        self.inner.spin_lock().capacity
+    }
+    /// Get the overflow behaviour used when the buffer is full.
+    pub fn overflow(&self) -> Overflow {
+        self.inner.spin_lock().overflow
+    }
+    /// Limit the number of items buffered in this port. `None` removes the limit.
+    pub fn set_capacity(&self, capacity: Option<usize>, overflow: Overflow) {
+        let writers;
+        {
+            let mut inner = self.inner.spin_lock();
+            inner.capacity = capacity;
+            inner.overflow = overflow;
+            writers = inner.write_wait.drain(..).collect::<Vec<_>>();
+        }
+        // the limit may have been relaxed, so let any blocked writers retry
+        for writer in writers {
+            writer.wake();
+        }
+    }
+    /// Determines if two ports can be connected to each other. The types always match, so this
+    /// only rules out connecting a port to itself.
     pub fn can_connect(self: &Arc<Port<I, O>>, other: &Arc<Port<O, I>>) -> bool {
-        self.id() != other.id() && self.in_ty == other.out_ty && self.out_ty == other.in_ty
+        self.id() != other.id()
     }
-    /// Connect this port to another. If either port is opaque and the ports have unmatched
-    /// underlying types, this fails with ConnectError::TypeMismatch. Fails with
-    /// ConnectError::AlreadyConnected if either port is already connected.
+    /// Connect this port to another. Fails with ConnectError::AlreadyConnected if the two ports
+    /// are already connected to each other. Ports may otherwise be connected to any number of
+    /// peers.
     pub fn connect(self: &Arc<Port<I, O>>, other: &Arc<Port<O, I>>) -> Result<(), ConnectError> {
-        if !self.can_connect(other) {
-            return Err(ConnectError::TypeMismatch);
-        }
         if self.id() == other.id() {
             // self edges are currently not supported
             unimplemented!();
+        }
+        // always lock the port with lower id first to prevent deadlock
+        // (circular wait condition)
+        let (mut self_edge, mut other_edge);
+        if self.id().0 < other.id().0 {
+            self_edge = self.edge.spin_lock();
+            other_edge = other.edge.spin_lock();
         } else {
-            let self_untyped = self.as_opaque();
-            let other_untyped = other.as_opaque();
-            // always lock the port with lower id first to prevent deadlock
-            // (circular wait condition)
-            let (a, b) = if self.id().0 < other.id().0 {
-                (self_untyped, other_untyped)
+            other_edge = other.edge.spin_lock();
+            self_edge = self.edge.spin_lock();
+        };
+        if self_edge.closed || other_edge.closed {
+            return Err(ConnectError::Closed);
+        }
+        if self_edge.is_connected_to(other.id()) || other_edge.is_connected_to(self.id()) {
+            return Err(ConnectError::AlreadyConnected);
+        }
+        // drop any references to ports that have since been freed
+        self_edge.others.retain(|x| x.upgrade().is_some());
+        other_edge.others.retain(|x| x.upgrade().is_some());
+        self_edge.others.push(Arc::downgrade(other));
+        other_edge.others.push(Arc::downgrade(self));
+
+        // UnsafeCells protected by edge mutex
+        for waker in self_edge
+            .connect_wait
+            .drain(..)
+            .chain(other_edge.connect_wait.drain(..))
+        {
+            waker.wake();
+        }
+        drop(self_edge);
+        drop(other_edge);
+
+        self.notify(GraphEvent::Connected(self.addr(), other.addr()));
+        Ok(())
+    }
+
+    /// Disconnect this port from a specific peer.
+    /// Fails with ConnectError::NotConnected if the two ports are not connected.
+    pub fn disconnect_from(self: &Arc<Port<I, O>>, other: &Arc<Port<O, I>>) -> Result<(), ConnectError> {
+        if other.id() == self.id() {
+            // self edges are currently not supported
+            unimplemented!();
+        }
+        {
+            // similarly to with `connect`, we need to lock the edges of the two ports in
+            // a deterministic order to prevent a deadlock.
+            let (mut self_edge, mut other_edge);
+            if self.id().0 < other.id().0 {
+                self_edge = self.edge.spin_lock();
+                other_edge = other.edge.spin_lock();
             } else {
-                (other_untyped, self_untyped)
+                other_edge = other.edge.spin_lock();
+                self_edge = self.edge.spin_lock();
             };
-            let mut a_edge = a.edge.spin_lock();
-            let mut b_edge = b.edge.spin_lock();
-            if a_edge.other.as_ref().and_then(|x| x.upgrade()).is_some()
-                || b_edge.other.as_ref().and_then(|x| x.upgrade()).is_some()
-            {
-                return Err(ConnectError::AlreadyConnected);
-            }
-            a_edge.other = Some(Arc::downgrade(&b));
-            b_edge.other = Some(Arc::downgrade(&a));
-
-            // UnsafeCells protected by edge mutex
-            for waker in a_edge.connect_wait.drain(..).chain(b_edge.connect_wait.drain(..)) {
-                waker.wake();
+            if !self_edge.is_connected_to(other.id()) {
+                return Err(ConnectError::NotConnected);
             }
-            Ok(())
+            // other should definitely be connected to self if we made it here
+            assert!(other_edge.is_connected_to(self.id()));
+            self_edge.remove(other.id());
+            other_edge.remove(self.id());
         }
+
+        // fail any waiting readers so that the task isn't left half finished across a
+        // disconnect/reconnect
+        self.disconnect_abort();
+        other.disconnect_abort();
+
+        self.notify(GraphEvent::Disconnected(self.addr(), other.addr()));
+        Ok(())
     }
 
-    /// Disconnect this port from another.
+    /// Disconnect this port from all of its peers.
     /// Fails with ConnectError::NotConnected if the port is already disconnected.
     pub fn disconnect(self: &Arc<Port<I, O>>) -> Result<(), ConnectError> {
-        // similarly to with `connect`, we need to lock the edges of the two ports in
-        // a deterministic order to prevent a deadlock.
-        // but here, we don't know the other port until we lock this port.
-        // so, we read the other port with `edge()`, lock the two in the required order,
-        // verify nothing changed in between reading and locking,
-        // then finally clear the connection.
-        // if verification fails we race again until it succeeds.
-        loop {
-            let other = &self.edge().ok_or(ConnectError::NotConnected)?;
-            if other.id() == self.id() {
-                // self edges are currently not supported
-                unimplemented!();
-            } else {
-                let (mut a_edge, mut b_edge);
-                if self.id().0 < other.id().0 {
-                    a_edge = self.edge.spin_lock();
-                    b_edge = other.edge.spin_lock();
-                } else {
-                    b_edge = other.edge.spin_lock();
-                    a_edge = self.edge.spin_lock();
-                };
-                // check that the port this one is connected to hasn't changed in between
-                // finding `other` and locking the edges
-                if !a_edge
-                    .other
-                    .as_ref()
-                    .and_then(|x| x.upgrade())
-                    .map(|self_other| Arc::ptr_eq(other, &self_other))
-                    .unwrap_or(false)
-                {
-                    continue;
-                }
-                // other should definitely be connected to self if we made it here
-                assert!(Arc::ptr_eq(
-                    &b_edge.other.as_ref().unwrap().upgrade().unwrap(),
-                    self
-                ));
-                a_edge.other = None;
-                b_edge.other = None;
-
-                drop(a_edge);
-                drop(b_edge);
-
-                // fail any waiting readers so that the task isn't left half finished across a
-                // disconnect/reconnect
-                self.disconnect_abort();
-                other.disconnect_abort();
-                break;
+        let others = self.edges();
+        if others.is_empty() {
+            return Err(ConnectError::NotConnected);
+        }
+        for other in others {
+            // the edge may have been removed concurrently in between reading and locking it,
+            // which is fine since we wanted it gone anyway
+            match self.disconnect_from(&other) {
+                Ok(()) | Err(ConnectError::NotConnected) => {}
+                Err(e) => return Err(e),
             }
         }
         Ok(())
     }
     fn disconnect_abort(&self) {
         let readers;
+        let writers;
         {
             let mut inner = self.inner.spin_lock();
             inner.disconnect_occured = true;
             readers = inner.read_wait.drain(..).collect::<Vec<_>>();
+            writers = inner.write_wait.drain(..).collect::<Vec<_>>();
         };
 
         // wake any readers that were waiting, since they need to fail now
         for reader in readers {
             reader.wake();
         }
+        // wake any writers blocked on our buffer, so they can notice they are no longer connected
+        for writer in writers {
+            writer.wake();
+        }
+    }
+    /// Permanently disconnect this port and fail everything waiting on it.
+    fn close(self: &Arc<Port<I, O>>) {
+        // refuse new connections first, so nothing can sneak in after disconnecting
+        let connecters = {
+            let mut edge = self.edge.spin_lock();
+            edge.closed = true;
+            edge.connect_wait.drain(..).collect::<Vec<_>>()
+        };
+        let _ = self.disconnect();
+        let (readers, writers) = {
+            let mut inner = self.inner.spin_lock();
+            inner.closed = true;
+            (
+                inner.read_wait.drain(..).collect::<Vec<_>>(),
+                inner.write_wait.drain(..).collect::<Vec<_>>(),
+            )
+        };
+        for waker in connecters.into_iter().chain(readers).chain(writers) {
+            waker.wake();
+        }
+    }
+    fn addr(&self) -> (NodeId, PortId) {
+        (self.node_id, self.id)
     }
-    pub fn edge(&self) -> Option<Arc<Port<O, I>>> {
-        self.edge.spin_lock().other.as_ref().and_then(|x| x.upgrade())
+    fn notify(&self, event: GraphEvent) {
+        if let Some(graph) = self.graph.upgrade() {
+            graph.notify(event);
+        }
+    }
+    /// Get all ports this port is connected to at the time of the call.
+    pub fn edges(&self) -> Vec<Arc<Port<O, I>>> {
+        self.edge.spin_lock().peers()
     }
 
-    /// Returns a `Future` which writes a `Vec` of data to a port, returning the port.
+    /// Returns a `Future` which writes a `Vec` of data to a port, returning the port. The data is
+    /// cloned to every connected peer. The future waits until at least one peer is connected.
     /// Writing cannot currently fail: TODO make the type signature reflect this.
     pub fn write(
         self: Arc<Port<I, O>>,
         data: Vec<O>,
-    ) -> impl Future<Item = Arc<Port<I, O>>, Error = (Arc<Port<I, O>>, Error)> {
-        let n_bytes = data.len() * mem::size_of::<O>();
+    ) -> impl Future<Item = Arc<Port<I, O>>, Error = (Arc<Port<I, O>>, Error)>
+    where
+        O: Clone,
+    {
         WriteFuture {
             port: Some(self),
-            other: None,
-            n: data.len(),
-            data: typed_as_bytes(data.into(), n_bytes),
+            data: Some(data),
+            pending: None,
         }.fuse()
     }
     /// Write a single item. Equivalent to `write(vec![data])`
     pub fn write1(
         self: Arc<Port<I, O>>,
         data: O,
-    ) -> impl Future<Item = Arc<Port<I, O>>, Error = (Arc<Port<I, O>>, Error)> {
+    ) -> impl Future<Item = Arc<Port<I, O>>, Error = (Arc<Port<I, O>>, Error)>
+    where
+        O: Clone,
+    {
         self.write(vec![data])
     }
 
@@ -427,7 +1209,7 @@ pub struct ReadFuture<I: 'static, O: 'static> {
     n: Option<usize>,
 }
 
-impl<I: 'static, O: 'static> Future for ReadFuture<I, O> {
+impl<I: Send + 'static, O: Send + 'static> Future for ReadFuture<I, O> {
     type Item = (Arc<Port<I, O>>, Box<[I]>);
     type Error = (Arc<Port<I, O>>, Error);
     fn poll(&mut self, cx: &mut Context) -> Result<Async<Self::Item>, Self::Error> {
@@ -438,6 +1220,10 @@ impl<I: 'static, O: 'static> Future for ReadFuture<I, O> {
                 Ok(Async::Pending) => return Ok(Async::Pending),
                 Err(_) => unreachable!(),
             };
+            if inner.closed {
+                drop(inner);
+                return Err((self.port.take().unwrap(), Error::Closed));
+            }
             // if a disconnect has occured, then we fail the future so that the task isn't left
             // in a half finished state.
             if inner.disconnect_occured {
@@ -445,10 +1231,8 @@ impl<I: 'static, O: 'static> Future for ReadFuture<I, O> {
                 drop(inner);
                 return Err((self.port.take().unwrap(), Error::Disconnected));
             }
-            // the buffer is protected by buf_lock
-            let buffer_size = inner.buffer_size;
-            let buf = &mut inner.buffer;
             // attempt read
+            let buffer_size = inner.buffer.len();
             if self.n.map(|n| buffer_size < n).unwrap_or(buffer_size == 0) {
                 // not enough data available
                 // register to wake on next write
@@ -456,11 +1240,14 @@ impl<I: 'static, O: 'static> Future for ReadFuture<I, O> {
             } else {
                 // move data out of queue
                 let n = self.n.unwrap_or(buffer_size);
-                let iter = buf.drain(..(n * mem::size_of::<I>()));
-                let data = iter.collect::<Vec<_>>().into();
-                inner.buffer_size -= n;
+                let data = inner.buffer.drain(..n).collect::<Vec<_>>().into_boxed_slice();
+                let writers = inner.write_wait.drain(..).collect::<Vec<_>>();
                 drop(inner);
-                return Ok(Async::Ready((self.port.take().unwrap(), bytes_as_typed(data, n))));
+                // room was made, so wake any writers waiting on a full buffer
+                for writer in writers {
+                    writer.wake();
+                }
+                return Ok(Async::Ready((self.port.take().unwrap(), data)));
             }
         }
 
@@ -471,51 +1258,90 @@ impl<I: 'static, O: 'static> Future for ReadFuture<I, O> {
 
 pub struct WriteFuture<I: 'static, O: 'static> {
     port: Option<Arc<Port<I, O>>>,
-    data: Box<[u8]>,
-    other: Option<Arc<Port<O, I>>>,
-    n: usize,
+    data: Option<Vec<O>>,
+    // peers that still need to receive their copy of the data
+    pending: Option<Vec<(Arc<Port<O, I>>, Vec<O>)>>,
 }
 
-impl<I: 'static, O: 'static> Future for WriteFuture<I, O> {
+impl<I: Send + 'static, O: Send + Clone + 'static> Future for WriteFuture<I, O> {
     type Item = Arc<Port<I, O>>;
     type Error = (Arc<Port<I, O>>, Error);
     fn poll(&mut self, cx: &mut Context) -> Result<Async<Self::Item>, Self::Error> {
-        if self.other.is_none() {
+        if self.pending.is_none() {
             let port = self.port.as_ref().unwrap();
-            self.other = Some({
+            let mut others = {
                 let mut edge = match port.edge.lock().poll(cx) {
                     Ok(Async::Ready(edge)) => edge,
                     Ok(Async::Pending) => return Ok(Async::Pending),
                     Err(_) => unreachable!(),
                 };
-                match edge.other.as_ref().and_then(|x| x.upgrade()) {
-                    Some(other) => other,
-                    None => {
-                        // register to wake on connect
-                        edge.connect_wait.push(cx.waker().clone());
-                        return Ok(Async::Pending);
-                    }
+                if edge.closed {
+                    drop(edge);
+                    return Err((self.port.take().unwrap(), Error::Closed));
                 }
-            });
-        }
-        let other = self.other.as_ref().unwrap();
-
-        let readers;
-        {
-            let mut inner = match other.inner.lock().poll(cx) {
-                Ok(Async::Ready(inner)) => inner,
-                Ok(Async::Pending) => return Ok(Async::Pending),
-                Err(_) => unreachable!(),
+                let others = edge.peers();
+                if others.is_empty() {
+                    // register to wake on connect
+                    edge.connect_wait.push(cx.waker().clone());
+                    return Ok(Async::Pending);
+                }
+                others
             };
-            let buf = &mut inner.buffer;
-            buf.extend(self.data.into_iter());
-            inner.buffer_size += self.n;
-            readers = inner.read_wait.drain(..).collect::<Vec<_>>();
+            // give each peer its own copy of the data, moving the original into the last one
+            let data = self.data.take().unwrap();
+            let last = others.pop().unwrap();
+            let mut pending: Vec<_> = others.into_iter().map(|other| (other, data.clone())).collect();
+            pending.push((last, data));
+            self.pending = Some(pending);
         }
 
-        // wake any readers that are waiting for a write here
-        for reader in readers {
-            reader.wake();
+        let port = self.port.as_ref().unwrap();
+        let pending = self.pending.as_mut().unwrap();
+        while !pending.is_empty() {
+            let readers;
+            {
+                let (other, data) = pending.last_mut().unwrap();
+                {
+                    let edge = port.edge.spin_lock();
+                    if edge.closed {
+                        drop(edge);
+                        return Err((self.port.take().unwrap(), Error::Closed));
+                    }
+                    // skip peers that were disconnected while we were waiting on them
+                    if !edge.is_connected_to(other.id()) {
+                        drop(edge);
+                        pending.pop();
+                        continue;
+                    }
+                }
+                let mut inner = match other.inner.lock().poll(cx) {
+                    Ok(Async::Ready(inner)) => inner,
+                    Ok(Async::Pending) => return Ok(Async::Pending),
+                    Err(_) => unreachable!(),
+                };
+                if let Some(capacity) = inner.capacity {
+                    let len = inner.buffer.len();
+                    if len > 0 && len + data.len() > capacity && inner.overflow == Overflow::Block {
+                        // register to wake when a reader makes room
+                        inner.write_wait.push(cx.waker().clone());
+                        return Ok(Async::Pending);
+                    }
+                }
+                inner.buffer.extend(data.drain(..));
+                if let Some(capacity) = inner.capacity {
+                    if inner.overflow == Overflow::DropOldest && inner.buffer.len() > capacity {
+                        let excess = inner.buffer.len() - capacity;
+                        inner.buffer.drain(..excess);
+                    }
+                }
+                readers = inner.read_wait.drain(..).collect::<Vec<_>>();
+            }
+            pending.pop();
+
+            // wake any readers that are waiting for a write here
+            for reader in readers {
+                reader.wake();
+            }
         }
 
         Ok(Async::Ready(self.port.take().unwrap()))
@@ -525,8 +1351,12 @@ impl<I: 'static, O: 'static> Future for WriteFuture<I, O> {
 #[derive(Debug)]
 pub enum ConnectError {
     AlreadyConnected,
-    TypeMismatch,
+    /// The ports can't be connected. Holds the types of the port being connected and of its
+    /// intended peer.
+    TypeMismatch(PortType, PortType),
     NotConnected,
+    /// One of the ports belongs to a node that has been removed from the graph.
+    Closed,
 }
 
 /// Error cases
@@ -537,19 +1367,347 @@ pub enum Error {
     InvalidPort,
     NotAvailable,
     Disconnected,
+    /// The port belongs to a node that has been removed from the graph.
+    Closed,
+}
+
+#[test]
+fn test_fan_out_fan_in() {
+    use futures::executor::block_on;
+
+    let graph = Graph::new();
+    let src = graph.add_node().get_or_create_port::<(), i32>("Output".into());
+    let dst_a = graph.add_node().get_or_create_port::<i32, ()>("Input".into());
+    let dst_b = graph.add_node().get_or_create_port::<i32, ()>("Input".into());
+    src.connect(&dst_a).unwrap();
+    src.connect(&dst_b).unwrap();
+    match src.connect(&dst_a) {
+        Err(ConnectError::AlreadyConnected) => {}
+        other => panic!("expected AlreadyConnected, got {:?}", other),
+    }
+    assert_eq!(src.edges().len(), 2);
+
+    // fan-out: both peers receive a copy
+    let src = block_on(src.write(vec![1, 2, 3])).ok().unwrap();
+    let (dst_a, data) = block_on(dst_a.read()).ok().unwrap();
+    assert_eq!(&*data, &[1, 2, 3]);
+    let (dst_b, data) = block_on(dst_b.read()).ok().unwrap();
+    assert_eq!(&*data, &[1, 2, 3]);
+
+    // fan-in: writes from both peers are interleaved whole
+    let dst_a = block_on(dst_a.write(vec![(), ()])).ok().unwrap();
+    let dst_b = block_on(dst_b.write(vec![()])).ok().unwrap();
+    let (src, data) = block_on(src.read()).ok().unwrap();
+    assert_eq!(data.len(), 3);
+
+    src.disconnect_from(&dst_a).unwrap();
+    assert_eq!(src.edges().len(), 1);
+    assert!(dst_a.edges().is_empty());
+    src.disconnect().unwrap();
+    assert!(dst_b.edges().is_empty());
+}
+
+#[test]
+fn test_bounded_port() {
+    use futures::executor::block_on;
+
+    let graph = Graph::new();
+    let src = graph.add_node().get_or_create_port::<(), i32>("Output".into());
+    let dst = graph
+        .add_node()
+        .get_or_create_bounded_port::<i32, ()>("Input".into(), 4, Overflow::DropOldest);
+    src.connect(&dst).unwrap();
+    assert_eq!(dst.capacity(), Some(4));
+
+    let src = block_on(src.write(vec![1, 2, 3])).ok().unwrap();
+    assert_eq!(dst.len(), 3);
+    let src = block_on(src.write(vec![4, 5, 6])).ok().unwrap();
+    assert_eq!(dst.len(), 4);
+    let (dst, data) = block_on(dst.read()).ok().unwrap();
+    assert_eq!(&*data, &[3, 4, 5, 6]);
+    assert_eq!(dst.len(), 0);
+
+    // a blocking port accepts a write into an empty buffer even if it is oversized
+    dst.set_capacity(Some(2), Overflow::Block);
+    let src = block_on(src.write(vec![7, 8, 9])).ok().unwrap();
+    assert_eq!(dst.len(), 3);
+    let (dst, data) = block_on(dst.read()).ok().unwrap();
+    assert_eq!(&*data, &[7, 8, 9]);
+    drop((src, dst));
+}
+
+#[cfg(test)]
+struct DropCounter(Arc<AtomicUsize>);
+#[cfg(test)]
+impl Clone for DropCounter {
+    fn clone(&self) -> DropCounter {
+        DropCounter(self.0.clone())
+    }
+}
+#[cfg(test)]
+impl Drop for DropCounter {
+    fn drop(&mut self) {
+        self.0.fetch_add(1, Ordering::SeqCst);
+    }
+}
+
+#[test]
+fn test_drop_types_fan_out() {
+    use futures::executor::block_on;
+
+    let drops = Arc::new(AtomicUsize::new(0));
+    let graph = Graph::new();
+    let src = graph
+        .add_node()
+        .get_or_create_port::<(), DropCounter>("Output".into());
+    let dst_a = graph
+        .add_node()
+        .get_or_create_port::<DropCounter, ()>("Input".into());
+    let dst_b = graph
+        .add_node()
+        .get_or_create_port::<DropCounter, ()>("Input".into());
+    src.connect(&dst_a).unwrap();
+    src.connect(&dst_b).unwrap();
+
+    let data = (0..3).map(|_| DropCounter(drops.clone())).collect();
+    let src = block_on(src.write(data)).ok().unwrap();
+    // the originals were moved into one peer and cloned into the other, nothing dropped yet
+    assert_eq!(drops.load(Ordering::SeqCst), 0);
+
+    let (dst_a, data) = block_on(dst_a.read()).ok().unwrap();
+    assert_eq!(data.len(), 3);
+    drop(data);
+    assert_eq!(drops.load(Ordering::SeqCst), 3);
+
+    // items still sitting in a buffer are dropped along with the port
+    drop((src, dst_a, dst_b));
+    drop(graph);
+    assert_eq!(drops.load(Ordering::SeqCst), 6);
+}
+
+#[test]
+fn test_drop_types_overflow() {
+    use futures::executor::block_on;
+
+    let drops = Arc::new(AtomicUsize::new(0));
+    let graph = Graph::new();
+    let src = graph
+        .add_node()
+        .get_or_create_port::<(), DropCounter>("Output".into());
+    let dst = graph
+        .add_node()
+        .get_or_create_bounded_port::<DropCounter, ()>("Input".into(), 2, Overflow::DropOldest);
+    src.connect(&dst).unwrap();
+
+    let data = (0..5).map(|_| DropCounter(drops.clone())).collect();
+    let src = block_on(src.write(data)).ok().unwrap();
+    // the three oldest items were discarded
+    assert_eq!(drops.load(Ordering::SeqCst), 3);
+    let (dst, data) = block_on(dst.read()).ok().unwrap();
+    assert_eq!(data.len(), 2);
+    drop(data);
+    assert_eq!(drops.load(Ordering::SeqCst), 5);
+    drop((src, dst));
 }
 
-fn typed_as_bytes<T: 'static>(data: Box<[T]>, size: usize) -> Box<[u8]> {
-    assert!(mem::size_of::<T>() == 0 || size == data.len() * mem::size_of::<T>());
-    let raw = Box::into_raw(data);
-    unsafe { Box::from_raw(slice::from_raw_parts_mut(raw as *mut u8, size)) }
+#[test]
+fn test_opaque_roundtrip() {
+    let graph = Graph::new();
+    let ifc = graph.add_node();
+    let port = ifc.get_or_create_port::<i32, ()>("Input".into());
+    let opaque = port.as_opaque();
+    assert!(opaque.as_typed::<(), i32>().is_none());
+    assert!(Arc::ptr_eq(&opaque.as_typed::<i32, ()>().unwrap(), &port));
+
+    let other = graph.add_node().get_or_create_port::<(), i64>("Output".into());
+    match opaque.connect(&other.as_opaque()) {
+        Err(ConnectError::TypeMismatch(this, other)) => {
+            assert_eq!(this.input, any::type_name::<i32>());
+            assert_eq!(other.output, any::type_name::<i64>());
+        }
+        other => panic!("expected TypeMismatch, got {:?}", other),
+    }
+}
+
+#[test]
+fn test_graph_events() {
+    let graph = Graph::new();
+    let mut events = graph.subscribe();
+    let a = graph.add_node();
+    let b = graph.add_node();
+    let src = a.get_or_create_port::<(), i32>("Output".into());
+    let dst = b.get_or_create_port::<i32, ()>("Input".into());
+    src.connect(&dst).unwrap();
+    src.disconnect_from(&dst).unwrap();
+    b.remove_port(dst.id()).unwrap();
+    graph.remove_node(b.id()).unwrap();
+
+    let src_addr = (a.id(), src.id());
+    let dst_addr = (b.id(), dst.id());
+    let mut received = Vec::new();
+    while let Ok(Some(event)) = events.try_next() {
+        received.push(event);
+    }
+    assert_eq!(
+        received,
+        vec![
+            GraphEvent::NodeAdded(a.id()),
+            GraphEvent::NodeAdded(b.id()),
+            GraphEvent::PortAdded(a.id(), src.id()),
+            GraphEvent::PortAdded(b.id(), dst.id()),
+            GraphEvent::Connected(src_addr, dst_addr),
+            GraphEvent::Disconnected(src_addr, dst_addr),
+            GraphEvent::PortRemoved(b.id(), dst.id()),
+            GraphEvent::NodeRemoved(b.id()),
+        ]
+    );
+}
+
+#[test]
+fn test_remove_node() {
+    use futures::executor::block_on;
+    use std::thread;
+    use std::time::Duration;
+
+    let graph = Graph::new();
+    let a = graph.add_node();
+    let b = graph.add_node();
+    let src = a.get_or_create_port::<(), i32>("Output".into());
+    let dst = b.get_or_create_port::<i32, ()>("Input".into());
+    let dst2 = graph.add_node().get_or_create_port::<i32, ()>("Input".into());
+    src.connect(&dst).unwrap();
+    src.connect(&dst2).unwrap();
+
+    // a reader blocked on the removed node is woken with an error
+    let reader = {
+        let dst = dst.clone();
+        thread::spawn(move || block_on(dst.read()).map(|_| ()).map_err(|(_, err)| err))
+    };
+    thread::sleep(Duration::from_millis(50));
+    graph.remove_node(b.id()).unwrap();
+    match reader.join().unwrap() {
+        Err(Error::Closed) => {}
+        other => panic!("expected Closed, got {:?}", other),
+    }
+
+    // no dangling edges remain on either side
+    assert!(dst.edges().is_empty());
+    assert_eq!(src.edges().len(), 1);
+    assert!(Arc::ptr_eq(&src.edges()[0], &dst2));
+    assert!(graph.node(b.id()).is_none());
+
+    // the removed port refuses further use
+    match src.connect(&dst) {
+        Err(ConnectError::Closed) => {}
+        other => panic!("expected Closed, got {:?}", other),
+    }
+    match block_on(dst.clone().write1(())) {
+        Err((_, Error::Closed)) => {}
+        _ => panic!("expected Closed"),
+    }
+    match graph.remove_node(b.id()) {
+        Err(Error::InvalidNode) => {}
+        _ => panic!("expected InvalidNode"),
+    }
 }
 
-fn bytes_as_typed<T: 'static>(data: Box<[u8]>, size: usize) -> Box<[T]> {
-    assert!(
-        mem::size_of::<T>() == 0
-            || (data.len() % mem::size_of::<T>() == 0 && size == data.len() / mem::size_of::<T>())
+#[test]
+fn test_adapter() {
+    use futures::executor::{block_on, ThreadPool};
+
+    let graph = Graph::new();
+    graph.set_executor(ThreadPool::new().unwrap());
+    graph.register_adapter(|x: i32| i64::from(x));
+    let mut events = graph.subscribe();
+    let a = graph.add_node();
+    let b = graph.add_node();
+    let src = a.get_or_create_port::<usize, i32>("Output".into());
+    let dst = b.get_or_create_port::<i64, usize>("Input".into());
+    let (src_op, dst_op) = (src.as_opaque(), dst.as_opaque());
+    assert!(src_op.can_connect(&dst_op));
+    // ports with no conversion between them still can't be connected
+    let other = graph.add_node().get_or_create_port::<i32, usize>("Input".into());
+    assert!(!dst_op.can_connect(&other.as_opaque()));
+    src_op.connect(&dst_op).unwrap();
+    match src_op.connect(&dst_op) {
+        Err(ConnectError::AlreadyConnected) => {}
+        other => panic!("expected AlreadyConnected, got {:?}", other),
+    }
+    assert!(Arc::ptr_eq(&src_op.edges()[0], &dst_op));
+    assert!(Arc::ptr_eq(&dst_op.edges()[0], &src_op));
+
+    // data is converted on the way, and flows unchanged where the types already match
+    block_on(dst.clone().write1(3)).unwrap();
+    assert_eq!(block_on(src.clone().read1()).unwrap().1, 3);
+    block_on(src.clone().write(vec![1, 2, 3])).unwrap();
+    assert_eq!(&*block_on(dst.clone().read_n(3)).unwrap().1, &[1i64, 2, 3]);
+
+    let nodes = graph.nodes().len();
+    dst_op.disconnect_from(&src_op).unwrap();
+    assert_eq!(graph.nodes().len(), nodes - 1);
+    assert!(src_op.edges().is_empty());
+
+    // only the logical connection is visible to subscribers
+    let mut received = Vec::new();
+    while let Ok(Some(event)) = events.try_next() {
+        match event {
+            GraphEvent::Connected(..) | GraphEvent::Disconnected(..) => received.push(event),
+            _ => {}
+        }
+    }
+    let src_addr = (a.id(), src.id());
+    let dst_addr = (b.id(), dst.id());
+    assert_eq!(
+        received,
+        vec![
+            GraphEvent::Connected(src_addr, dst_addr),
+            GraphEvent::Disconnected(src_addr, dst_addr),
+        ]
     );
-    let raw = Box::into_raw(data);
-    unsafe { Box::from_raw(slice::from_raw_parts_mut(raw as *mut T, size)) }
+}
+
+#[test]
+fn test_audio_config() {
+    let graph = Graph::new();
+    let child = graph.new_child();
+    let ifc = child.add_node();
+    let mut changes = ifc.watch_audio_config();
+    assert_eq!(ifc.audio_config(), AudioConfig::default());
+
+    let config = AudioConfig {
+        rate: 44100,
+        block_size: 64,
+        channels: 1,
+    };
+    graph.set_audio_config(config);
+    // setting the same thing again is not a change
+    graph.set_audio_config(config);
+    assert_eq!(ifc.audio_config(), config);
+    assert_eq!(changes.try_next().unwrap(), Some(config));
+    assert!(changes.try_next().is_err());
+}
+
+#[test]
+fn test_audio_claim() {
+    let graph = Graph::new();
+    let child = graph.new_child();
+    let config = AudioConfig {
+        rate: 44100,
+        block_size: 64,
+        channels: 1,
+    };
+    let other = AudioConfig {
+        rate: 8000,
+        ..config
+    };
+    let claim = graph.claim_audio_config(config).unwrap();
+    // agreeing claims can be held together, even from a child graph
+    let same = child.claim_audio_config(config).unwrap();
+    assert_eq!(child.claim_audio_config(other).err(), Some(config));
+    assert_eq!(graph.audio_config(), config);
+    drop(claim);
+    assert!(graph.claim_audio_config(other).is_err());
+    drop(same);
+    let _other = graph.claim_audio_config(other).unwrap();
+    assert_eq!(child.audio_config(), other);
 }
@@ -3,16 +3,25 @@ use futures::executor;
 use futures::future;
 use futures::prelude::*;
 
-use notify::*;
+use notify::{DebouncedEvent, RecommendedWatcher, RecursiveMode, Watcher};
 
 use future_ext::{Breaker, FutureWrapExt};
-use module::{audio_io::Frame, flow, Module};
+use module::{audio_io::{Frame, FrameMismatch}, flow, Module};
 
+use ron;
+
+use std::collections::VecDeque;
+use std::env;
+use std::fs;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::mem;
 use std::path::{Path, PathBuf};
 use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{Receiver, RecvTimeoutError, Sender, TryRecvError};
 use std::sync::{Arc, Mutex};
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 fn start_simple_processor<F: FnMut(Frame) -> Frame + Send + 'static, Ex: executor::Executor>(
     processor: F,
@@ -95,6 +104,96 @@ fn start_simple_processor<F: FnMut(Frame) -> Frame + Send + 'static, Ex: executo
 #[derive(Debug)]
 enum UserCommand {
     NewFile(String),
+    Rebuild,
+    Restart,
+}
+
+/// Settings of a `LiveCode` module.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct LiveCodeConfig {
+    /// Shell command that builds the source into a program. `{src}` is replaced by the source file and
+    /// `{out}` by where the program should go. When not set, one is picked from
+    /// `DEFAULT_BUILD_COMMANDS` by the source's extension, and sources of other kinds are run as they
+    /// are.
+    pub build_command: Option<String>,
+    /// What comes out in place of frames the child doesn't process in time.
+    pub fallback: Fallback,
+    /// Blocks over which a rebuilt program is faded in, while the old one keeps running and fades out.
+    /// If the old one stops partway, the new one fades in over the fallback instead. At 0 the old one
+    /// is stopped as soon as the new one starts.
+    pub fade_blocks: usize,
+}
+impl Default for LiveCodeConfig {
+    fn default() -> LiveCodeConfig {
+        LiveCodeConfig {
+            build_command: None,
+            fallback: Fallback::Passthrough,
+            fade_blocks: 16,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Fallback {
+    Passthrough,
+    Silence,
+}
+impl Default for Fallback {
+    fn default() -> Fallback {
+        Fallback::Passthrough
+    }
+}
+
+/// Build commands for the kinds of source that can be built without being told how.
+pub const DEFAULT_BUILD_COMMANDS: [(&str, &str); 4] = [
+    ("c", "cc -O2 -o {out} {src} -lm"),
+    ("cpp", "c++ -O2 -o {out} {src}"),
+    ("rs", "rustc -O -o {out} {src}"),
+    ("zig", "zig build-exe -O ReleaseFast -femit-bin={out} {src}"),
+];
+
+/// How far the module got with the source it was last given.
+#[derive(Clone, Debug, PartialEq)]
+enum BuildStatus {
+    Idle,
+    Building,
+    Running,
+    /// The build command failed, with its output.
+    BuildFailed(String),
+    SpawnFailed(String),
+}
+
+/// Lines the children wrote to stderr, the oldest first.
+#[derive(Default)]
+struct Log {
+    lines: VecDeque<String>,
+    // lines ever written, so that new ones can be noticed even once old ones are dropped
+    written: usize,
+}
+/// Most lines kept in the log.
+const LOG_LINES: usize = 500;
+impl Log {
+    fn push(&mut self, line: String) {
+        if self.lines.len() == LOG_LINES {
+            self.lines.pop_front();
+        }
+        self.lines.push_back(line);
+        self.written += 1;
+    }
+}
+
+/// What the module shares with the threads that build and run its program.
+#[derive(Clone)]
+struct Shared {
+    config: Arc<Mutex<LiveCodeConfig>>,
+    status: Arc<Mutex<BuildStatus>>,
+    children: Arc<Mutex<Children>>,
+    log: Arc<Mutex<Log>>,
+    // the file being watched
+    source: Arc<Mutex<Option<PathBuf>>>,
+    // held while building, so that builds don't overlap
+    building: Arc<Mutex<()>>,
 }
 
 pub struct LiveCode {
@@ -105,39 +204,52 @@ pub struct LiveCode {
     cmd_rx: Option<UnboundedReceiver<UserCommand>>,
     cmd_tx: Option<UnboundedSender<UserCommand>>,
     watcher: Arc<Mutex<Option<RecommendedWatcher>>>,
-    child: Arc<Mutex<Option<process::Child>>>,
+    shared: Shared,
 }
 
 impl Drop for LiveCode {
     fn drop(&mut self) {
-        self.child.lock().unwrap().take().map(|mut child| {
+        let mut children = self.shared.children.lock().unwrap();
+        let leftovers = children.current.take().into_iter().chain(children.incoming.take().map(|x| x.child));
+        for child in leftovers {
             println!("killing leftover child");
-            child.kill()
-        });
+            remove_built(&child.kill());
+        }
     }
 }
 
 impl Module for LiveCode {
     fn new(ifc: Arc<flow::Interface>) -> LiveCode {
-        let in_port = ifc.get_or_create_port("Input".into());
-        let out_port = ifc.get_or_create_port("Output".into());
+        let breaker = ifc.breaker();
+        let in_port: Arc<flow::Port<Frame, ()>> = ifc.get_or_create_port("Input".into());
+        let out_port: Arc<flow::Port<(), Frame>> = ifc.get_or_create_port("Output".into());
+        in_port.set_tag("audio");
+        out_port.set_tag("audio");
         let (cmd_tx, cmd_rx) = mpsc::unbounded();
         LiveCode {
             ifc,
             in_port,
             out_port,
-            breaker: Breaker::new(),
+            breaker,
             cmd_rx: Some(cmd_rx),
             cmd_tx: Some(cmd_tx),
             watcher: Arc::default(),
-            child: Arc::default(),
+            shared: Shared {
+                config: Arc::default(),
+                status: Arc::new(Mutex::new(BuildStatus::Idle)),
+                children: Arc::default(),
+                log: Arc::default(),
+                source: Arc::default(),
+                building: Arc::default(),
+            },
         }
     }
 
     fn start<Ex: executor::Executor>(&mut self, mut exec: Ex) {
         let cmd_rx = self.cmd_rx.take().unwrap();
         let watcher_handle = self.watcher.clone();
-        let child_handle = self.child.clone();
+        let shared = self.shared.clone();
+        let ifc = self.ifc.clone();
         exec.spawn(Box::new(
             cmd_rx
                 .for_each(move |event| {
@@ -153,65 +265,134 @@ impl Module for LiveCode {
                             watcher.watch(parent_dir, RecursiveMode::NonRecursive).unwrap();
                             // store it globally because otherwise it gets dropped and stops watching
                             *watcher_handle.lock().unwrap() = Some(watcher);
-                            let child_handle = child_handle.clone();
-                            spawn_child(&child_handle, PathBuf::from(&filename));
+                            *shared.source.lock().unwrap() = Some(PathBuf::from(&filename));
+                            let shared = shared.clone();
+                            let ifc = ifc.clone();
 
                             // TODO
                             // This thread gets leaked, as does the thread spawned internally inside
                             // the watcher... the notify crate is not cleaning up properly.
                             // Not sure if it's mio that's broken or what.
-                            thread::spawn(move || loop {
-                                match rx.recv() {
-                                    Ok(event) => {
-                                        println!("{:?}", event);
-                                        match event {
-                                            DebouncedEvent::Write(path) => {
-                                                if path.to_str().unwrap() != filename {
-                                                    continue;
-                                                }
-                                                spawn_child(&child_handle, path);
+                            thread::spawn(move || {
+                                rebuild(&shared, ifc.audio_config());
+                                while let Ok(event) = rx.recv() {
+                                    println!("{:?}", event);
+                                    match event {
+                                        // editors that save by renaming a new file over the old one
+                                        // show up as creates
+                                        DebouncedEvent::Write(path) | DebouncedEvent::Create(path) => {
+                                            if path.to_str().unwrap() == filename {
+                                                rebuild(&shared, ifc.audio_config());
                                             }
-                                            _ => {}
                                         }
-                                    }
-                                    Err(e) => {
-                                        println!("Watcher thread done: {:?}", e);
-                                        return;
+                                        _ => {}
                                     }
                                 }
+                                println!("Watcher thread done");
                             });
                         }
+                        UserCommand::Rebuild => {
+                            let shared = shared.clone();
+                            let config = ifc.audio_config();
+                            thread::spawn(move || rebuild(&shared, config));
+                        }
+                        UserCommand::Restart => {
+                            let shared = shared.clone();
+                            let config = ifc.audio_config();
+                            thread::spawn(move || restart(&shared, config));
+                        }
                     }
                     Ok(())
                 })
                 .then(|x| Ok(())),
         )).unwrap();
 
-        let child_handle = self.child.clone();
+        // the child sizes its buffers when it starts, so restart it whenever the settings change. The old
+        // one can't take the new frames, so there's no fading between them, and a build that was still
+        // fading in takes over straight away.
+        let shared = self.shared.clone();
+        exec.spawn(Box::new(
+            self.ifc
+                .watch_audio_config()
+                .for_each(move |config| {
+                    let program = shared.children.lock().unwrap().newest_program();
+                    if let Some(program) = program {
+                        spawn_child(&shared, program, config, false);
+                    }
+                    Ok(())
+                })
+                .then(|_| Ok(())),
+        )).unwrap();
+
+        let shared = self.shared.clone();
+        let mut last_mismatch = None;
         start_simple_processor(
             move |mut frame: Frame| -> Frame {
-                let mut guard = child_handle.lock().unwrap();
-                let child = match *guard {
-                    Some(ref mut child) => child,
+                let fallback = shared.config.lock().unwrap().fallback;
+                let mut children = shared.children.lock().unwrap();
+                let header = match children.current {
+                    Some(ref child) => child.header,
                     None => return frame,
                 };
-                let stdin = child.stdin.as_mut().unwrap();
-                let stdout = child.stdout.as_mut().unwrap();
-                //temporary hack
-                //need to use futures for io
-                use std::io::{Read, Write};
-                use std::mem;
-                let mut buffer: Vec<_> = frame.data.iter().cloned().collect();
-                let bytes: &mut [u8] = unsafe {
-                    ::std::slice::from_raw_parts_mut(
-                        buffer.as_ptr() as *mut u8,
-                        buffer.len() * mem::size_of::<f32>(),
-                    )
-                };
-                stdin.write(bytes).unwrap();
-                stdout.read(bytes).unwrap();
-                for (outs, ins) in frame.data.iter_mut().zip(buffer.drain(..)) {
-                    *outs = ins;
+                // the child would read past the end of the frame, or leave some of it behind, so
+                // frames it isn't expecting go straight through
+                match header.check(&frame) {
+                    Ok(()) => last_mismatch = None,
+                    Err(mismatch) => {
+                        if last_mismatch != Some(mismatch) {
+                            println!("Livecode passing through a bad frame: {}", mismatch);
+                            last_mismatch = Some(mismatch);
+                        }
+                        return frame;
+                    }
+                }
+                // both children get the frame before either is waited on, so they share the deadline
+                let deadline = Instant::now() + header.duration(frame.data.shape()[0]);
+                let incoming = children.incoming.take().map(|mut incoming| {
+                    let faded = frame.clone();
+                    let sent = incoming.child.io.send(&faded);
+                    (incoming, faded, sent)
+                });
+                {
+                    let current = children.current.as_mut().unwrap();
+                    let sent = current.io.send(&frame);
+                    if !(sent && current.io.receive(&mut frame, deadline)) && fallback == Fallback::Silence {
+                        frame.data.fill(0.0);
+                    }
+                }
+                if let Some((mut incoming, mut faded, sent)) = incoming {
+                    if sent && incoming.child.io.receive(&mut faded, deadline) {
+                        incoming.mix(&mut frame, &faded);
+                    } else {
+                        incoming.missed += 1;
+                    }
+                    if incoming.child.io.dead {
+                        // keep the old one running, since the new one isn't going to take over
+                        *shared.status.lock().unwrap() = BuildStatus::SpawnFailed(
+                            "The new program stopped before it took over, so the old one is still running"
+                                .into(),
+                        );
+                        children.discard(incoming.child);
+                    } else if incoming.done() {
+                        children.promote(incoming.child);
+                    } else if children.current.as_ref().unwrap().io.dead {
+                        // the fallback stands in for the old one, and the new one keeps fading in over it
+                        // rather than jumping to full level. A slow new one is still better than nothing.
+                        if incoming.missed > MAX_FADE_MISSES {
+                            children.promote(incoming.child);
+                        } else {
+                            children.incoming = Some(incoming);
+                        }
+                    } else if incoming.missed > MAX_FADE_MISSES {
+                        // the fade only moves along when it answers, so it would never finish
+                        *shared.status.lock().unwrap() = BuildStatus::SpawnFailed(
+                            "The new program was too slow to take over, so the old one is still running"
+                                .into(),
+                        );
+                        children.discard(incoming.child);
+                    } else {
+                        children.incoming = Some(incoming);
+                    }
                 }
                 frame
             },
@@ -230,50 +411,616 @@ impl Module for LiveCode {
     fn ports(&self) -> Vec<Arc<flow::OpaquePort>> {
         self.ifc.ports()
     }
+    fn save_state(&self) -> Option<String> {
+        ron::ser::to_string(&*self.shared.config.lock().unwrap()).ok()
+    }
+    fn load_state(&mut self, state: &str) {
+        match ron::de::from_str(state) {
+            Ok(config) => *self.shared.config.lock().unwrap() = config,
+            Err(err) => println!("Could not load Livecode settings: {:?}", err),
+        }
+    }
+}
+
+/// Where built programs go. Anything in here belongs to some livecode module, and is removed once it
+/// is no longer run.
+fn build_dir() -> PathBuf {
+    env::temp_dir().join("flow-synth-livecode")
+}
+
+/// Remove a program, if it was built rather than being a source run as it is.
+fn remove_built(program: &Path) {
+    if program.starts_with(build_dir()) {
+        let _ = fs::remove_file(program);
+    }
+}
+
+/// Quote a path for the shell.
+fn shell_quote(path: &Path) -> String {
+    format!("'{}'", path.to_string_lossy().replace('\'', "'\\''"))
 }
-fn spawn_child(child_handle: &Arc<Mutex<Option<process::Child>>>, path: PathBuf) {
-    let child = match process::Command::new(path)
+
+/// The build command for a source, if it needs building.
+fn build_command(config: &LiveCodeConfig, source: &Path) -> Option<String> {
+    config.build_command.clone().or_else(|| {
+        let extension = source.extension()?.to_str()?;
+        DEFAULT_BUILD_COMMANDS
+            .iter()
+            .find(|&&(known, _)| known == extension)
+            .map(|&(_, command)| command.into())
+    })
+}
+
+/// Run a build command in the source's directory. On failure, its output is returned.
+fn build(command: &str, source: &Path, out: &Path) -> Result<(), String> {
+    let command = command.replace("{src}", &shell_quote(source)).replace("{out}", &shell_quote(out));
+    let output = process::Command::new("sh")
+        .arg("-c")
+        .arg(&command)
+        .current_dir(source.parent().unwrap_or_else(|| Path::new(".")))
+        .output()
+        .map_err(|err| format!("Could not run {}: {}", command, err))?;
+    if output.status.success() {
+        Ok(())
+    } else {
+        let mut errors = String::from_utf8_lossy(&output.stderr).into_owned();
+        errors.push_str(&String::from_utf8_lossy(&output.stdout));
+        if errors.trim().is_empty() {
+            errors = format!("{} failed with {}", command, output.status);
+        }
+        Err(errors)
+    }
+}
+
+/// Build the source, if it needs building, and start the program, fading it in over the old one. If
+/// the build fails, the previous program keeps running.
+fn rebuild(shared: &Shared, config: flow::AudioConfig) {
+    static BUILDS: AtomicUsize = AtomicUsize::new(0);
+
+    let _building = shared.building.lock().unwrap();
+    let source = match shared.source.lock().unwrap().clone() {
+        Some(source) => source,
+        None => return,
+    };
+    let command = build_command(&shared.config.lock().unwrap(), &source);
+    let program = match command {
+        Some(command) => {
+            *shared.status.lock().unwrap() = BuildStatus::Building;
+            // each build gets a new name, since the running program's file can't be written over
+            let build_id = BUILDS.fetch_add(1, Ordering::Relaxed);
+            let out = build_dir().join(format!("{}-{}", process::id(), build_id));
+            let built = fs::create_dir_all(build_dir())
+                .map_err(|err| format!("Could not create {:?}: {}", build_dir(), err))
+                .and_then(|_| build(&command, &source, &out));
+            if let Err(errors) = built {
+                *shared.status.lock().unwrap() = BuildStatus::BuildFailed(errors);
+                return;
+            }
+            out
+        }
+        None => source,
+    };
+    spawn_child(shared, program, config, true);
+}
+
+/// Start the newest program again, or build it if there isn't one, as after a failed start.
+fn restart(shared: &Shared, config: flow::AudioConfig) {
+    let program = shared.children.lock().unwrap().newest_program();
+    match program {
+        Some(program) => spawn_child(shared, program, config, true),
+        None => rebuild(shared, config),
+    }
+}
+
+/// Start of the stream header.
+pub const MAGIC: [u8; 4] = *b"FSYN";
+/// Version of the protocol, for children to check they understand it.
+pub const PROTOCOL_VERSION: u32 = 1;
+/// Interleaved 32 bit floats, the only sample format so far.
+pub const FORMAT_F32: u32 = 1;
+
+/// What the child is told about the audio, before any of it. On the wire it is `MAGIC` followed by
+/// `PROTOCOL_VERSION` and the fields in order, each a `u32` in the host's byte order, for 24 bytes in
+/// all. `livecode-examples/flowsynth.h` reads it from C.
+///
+/// Each block after it starts with its number of frames as a `u32`, which is never more than
+/// `block_size`, followed by the samples. The child answers with the same number of frames, without a
+/// header.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct StreamHeader {
+    pub rate: u32,
+    pub channels: u32,
+    pub block_size: u32,
+    pub format: u32,
+}
+impl StreamHeader {
+    pub fn new(config: flow::AudioConfig) -> StreamHeader {
+        StreamHeader {
+            rate: config.rate,
+            channels: config.channels as u32,
+            block_size: config.block_size as u32,
+            format: FORMAT_F32,
+        }
+    }
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let fields = [PROTOCOL_VERSION, self.rate, self.channels, self.block_size, self.format];
+        let mut bytes = MAGIC.to_vec();
+        for field in &fields {
+            bytes.extend_from_slice(&field.to_ne_bytes());
+        }
+        bytes
+    }
+    /// How long a block of `frames` lasts when played.
+    fn duration(&self, frames: usize) -> Duration {
+        Duration::from_nanos(frames as u64 * 1_000_000_000 / u64::from(self.rate.max(1)))
+    }
+    /// Check that the child can take a frame. Frames may be shorter than a block, since each one
+    /// carries its length.
+    fn check(&self, frame: &Frame) -> Result<(), FrameMismatch> {
+        let length = frame.data.shape()[0];
+        if length > self.block_size as usize {
+            return Err(FrameMismatch::Length {
+                expected: self.block_size as usize,
+                found: length,
+            });
+        }
+        frame.check(&flow::AudioConfig {
+            rate: self.rate,
+            block_size: length,
+            channels: self.channels as usize,
+        })
+    }
+}
+
+/// Send a block of interleaved samples to the child, after its header.
+fn write_block<W: Write>(writer: &mut W, samples: &[f32], frames: usize) -> io::Result<()> {
+    let bytes: &[u8] =
+        unsafe { ::std::slice::from_raw_parts(samples.as_ptr() as *const u8, mem::size_of_val(samples)) };
+    writer.write_all(&(frames as u32).to_ne_bytes())?;
+    writer.write_all(bytes)
+}
+
+/// Read the child's answer to a block, filling all of `samples`.
+fn read_block<R: Read>(reader: &mut R, samples: &mut [f32]) -> io::Result<()> {
+    let bytes: &mut [u8] = unsafe {
+        ::std::slice::from_raw_parts_mut(samples.as_mut_ptr() as *mut u8, mem::size_of_val(samples))
+    };
+    reader.read_exact(bytes)
+}
+
+/// Talks to a child on a thread of its own, so that a child that stalls can only hold up that thread
+/// and not the graph.
+struct ChildIo {
+    requests: Sender<(Vec<f32>, usize)>,
+    responses: Receiver<io::Result<Vec<f32>>>,
+    // whether the answer to the last block is still to come
+    late: bool,
+    // whether the child has stopped answering for good
+    dead: bool,
+    // blocks the child didn't answer in time
+    misses: usize,
+    // reused for each block
+    buffer: Vec<f32>,
+}
+impl ChildIo {
+    /// Start the thread. It stops when the child closes its end or this is dropped.
+    fn new<W, R>(mut stdin: W, mut stdout: R) -> ChildIo
+    where
+        W: Write + Send + 'static,
+        R: Read + Send + 'static,
+    {
+        let (requests, request_rx) = ::std::sync::mpsc::channel();
+        let (response_tx, responses) = ::std::sync::mpsc::channel();
+        thread::spawn(move || {
+            for (mut samples, frames) in request_rx {
+                let result = write_block(&mut stdin, &samples, frames)
+                    .and_then(|_| read_block(&mut stdout, &mut samples))
+                    .map(|_| samples);
+                let failed = result.is_err();
+                if response_tx.send(result).is_err() || failed {
+                    return;
+                }
+            }
+        });
+        ChildIo {
+            requests,
+            responses,
+            late: false,
+            dead: false,
+            misses: 0,
+            buffer: Vec::new(),
+        }
+    }
+
+    /// Send a frame for the child to process. Returns false if it can't be, because the child hasn't
+    /// answered the last one yet or has stopped answering. Until a late answer comes, no more frames
+    /// are sent, and once it does it is thrown away, since its frame has already gone by.
+    fn send(&mut self, frame: &Frame) -> bool {
+        if self.late {
+            match self.responses.try_recv() {
+                Ok(Ok(samples)) => {
+                    self.buffer = samples;
+                    self.late = false;
+                }
+                Ok(Err(err)) => {
+                    self.stopped(err);
+                    return false;
+                }
+                Err(TryRecvError::Empty) => return false,
+                Err(TryRecvError::Disconnected) => {
+                    self.dead = true;
+                    return false;
+                }
+            }
+        }
+        let mut samples = mem::replace(&mut self.buffer, Vec::new());
+        samples.clear();
+        samples.extend(frame.data.iter());
+        if self.requests.send((samples, frame.data.shape()[0])).is_err() {
+            self.dead = true;
+            return false;
+        }
+        true
+    }
+
+    /// Wait for the child's answer to the frame just sent, until `deadline`, and write it over the
+    /// frame. Returns false, leaving the frame as it was, if the child didn't answer in time.
+    fn receive(&mut self, frame: &mut Frame, deadline: Instant) -> bool {
+        let now = Instant::now();
+        let timeout = if deadline > now { deadline - now } else { Duration::from_secs(0) };
+        match self.responses.recv_timeout(timeout) {
+            Ok(Ok(samples)) => {
+                for (out, &sample) in frame.data.iter_mut().zip(&samples) {
+                    *out = sample;
+                }
+                self.buffer = samples;
+                true
+            }
+            Ok(Err(err)) => {
+                self.stopped(err);
+                false
+            }
+            Err(RecvTimeoutError::Timeout) => {
+                if self.misses == 0 {
+                    println!("Livecode child missed its deadline");
+                }
+                self.misses += 1;
+                self.late = true;
+                false
+            }
+            Err(RecvTimeoutError::Disconnected) => {
+                self.dead = true;
+                false
+            }
+        }
+    }
+
+    fn stopped(&mut self, err: io::Error) {
+        println!("Livecode child stopped answering: {}", err);
+        self.dead = true;
+    }
+}
+
+/// A running livecode program, and the header it was started with.
+struct Child {
+    process: process::Child,
+    // what it runs, which is the source itself if it isn't built
+    program: PathBuf,
+    header: StreamHeader,
+    io: ChildIo,
+}
+/// Whether a child is still running, for display.
+#[derive(Copy, Clone, Debug, PartialEq)]
+struct ChildState {
+    pid: u32,
+    exit: Option<process::ExitStatus>,
+}
+impl Child {
+    fn state(&mut self) -> ChildState {
+        ChildState {
+            pid: self.process.id(),
+            exit: self.process.try_wait().ok().and_then(|exit| exit),
+        }
+    }
+    /// Stop the program. Returns what it ran, for removing if it was built.
+    fn kill(mut self) -> PathBuf {
+        let _ = self.process.kill();
+        let _ = self.process.wait();
+        self.program
+    }
+}
+
+/// Blocks an incoming child can fail to answer in time before it is given up on.
+const MAX_FADE_MISSES: usize = 16;
+
+/// A child that is being faded in over the current one.
+struct Incoming {
+    child: Child,
+    // frames faded in so far, out of `length`
+    faded: usize,
+    length: usize,
+    // blocks that weren't answered in time, and so didn't move the fade along
+    missed: usize,
+}
+impl Incoming {
+    /// Mix the incoming child's output into the current one's, moving the fade along.
+    fn mix(&mut self, frame: &mut Frame, incoming: &Frame) {
+        for (i, (mut out, new)) in frame.data.outer_iter_mut().zip(incoming.data.outer_iter()).enumerate() {
+            let gain = ((self.faded + i) as f32 / self.length as f32).min(1.0);
+            for (out, &new) in out.iter_mut().zip(new.iter()) {
+                *out = *out * (1.0 - gain) + new * gain;
+            }
+        }
+        self.faded += frame.data.shape()[0];
+    }
+    fn done(&self) -> bool {
+        self.faded >= self.length
+    }
+}
+
+/// The running children of a `LiveCode` module.
+#[derive(Default)]
+struct Children {
+    current: Option<Child>,
+    incoming: Option<Incoming>,
+}
+impl Children {
+    /// Make a child the current one, stopping the one before it.
+    fn promote(&mut self, child: Child) {
+        if let Some(previous) = self.current.take() {
+            println!("killing previous child");
+            let program = previous.kill();
+            if program != child.program {
+                remove_built(&program);
+            }
+        }
+        self.current = Some(child);
+    }
+    /// The program of the child fading in if there is one, since it is the newest build, or else that
+    /// of the current child.
+    fn newest_program(&self) -> Option<PathBuf> {
+        match self.incoming {
+            Some(ref incoming) => Some(incoming.child.program.clone()),
+            None => self.current.as_ref().map(|current| current.program.clone()),
+        }
+    }
+    /// Stop a child that never became the current one.
+    fn discard(&mut self, child: Child) {
+        let program = child.kill();
+        if self.current.as_ref().map_or(true, |current| current.program != program) {
+            remove_built(&program);
+        }
+    }
+}
+
+/// Remove a program that couldn't be started, if it was built and no running child uses it, as when
+/// restarting one fails.
+fn abandon(shared: &Shared, program: &Path) {
+    let children = shared.children.lock().unwrap();
+    let current = children.current.as_ref().map(|current| &current.program);
+    let incoming = children.incoming.as_ref().map(|incoming| &incoming.child.program);
+    if current.map_or(true, |x| x != program) && incoming.map_or(true, |x| x != program) {
+        remove_built(program);
+    }
+}
+
+/// Start the livecode program. If `fade` is set, it fades in over the current one for the configured
+/// number of blocks, and otherwise replaces it straight away. The audio settings are sent to it in a
+/// `StreamHeader` before anything else.
+fn spawn_child(shared: &Shared, program: PathBuf, config: flow::AudioConfig, fade: bool) {
+    let mut process = match process::Command::new(&program)
         .stdin(process::Stdio::piped())
         .stdout(process::Stdio::piped())
+        .stderr(process::Stdio::piped())
         .spawn()
     {
-        Ok(child) => child,
+        Ok(process) => process,
         Err(e) => {
-            println!("err spawning: {:?}", e);
+            *shared.status.lock().unwrap() = BuildStatus::SpawnFailed(e.to_string());
+            abandon(shared, &program);
             return;
         }
     };
-
-    let mut child_handle = child_handle.lock().unwrap();
-    child_handle.take().map(|mut child| {
-        println!("killing previous child");
-        child.kill().unwrap();
+    let header = StreamHeader::new(config);
+    let mut stdin = process.stdin.take().unwrap();
+    if let Err(e) = stdin.write_all(&header.to_bytes()) {
+        *shared.status.lock().unwrap() = BuildStatus::SpawnFailed(format!("Could not send header: {}", e));
+        let _ = process.kill();
+        let _ = process.wait();
+        abandon(shared, &program);
+        return;
+    }
+    let io = ChildIo::new(stdin, process.stdout.take().unwrap());
+    shared.log.lock().unwrap().push(format!("Started PID {}", process.id()));
+    let (log, stderr) = (shared.log.clone(), process.stderr.take().unwrap());
+    thread::spawn(move || {
+        for line in BufReader::new(stderr).lines() {
+            match line {
+                Ok(line) => log.lock().unwrap().push(line),
+                Err(_) => return,
+            }
+        }
     });
-    *child_handle = Some(child);
+    let child = Child {
+        process,
+        program,
+        header,
+        io,
+    };
+
+    let fade_blocks = shared.config.lock().unwrap().fade_blocks;
+    let mut children = shared.children.lock().unwrap();
+    if let Some(incoming) = children.incoming.take() {
+        println!("killing child that was still fading in");
+        if incoming.child.program == child.program {
+            // it is being restarted, so its program has to stay
+            incoming.child.kill();
+        } else {
+            children.discard(incoming.child);
+        }
+    }
+    // there's only something to fade from if the current child is still running and takes the same frames
+    let fade = fade
+        && fade_blocks > 0
+        && children.current.as_mut().map_or(false, |current| {
+            current.header == header && !current.io.dead && current.state().exit.is_none()
+        });
+    if fade {
+        children.incoming = Some(Incoming {
+            child,
+            faded: 0,
+            length: fade_blocks * header.block_size as usize,
+            missed: 0,
+        });
+    } else {
+        children.promote(child);
+    }
+    *shared.status.lock().unwrap() = BuildStatus::Running;
 }
 
 use gfx_device_gl as gl;
-use gui::{button::*, component::*, event::*, geom::*, module_gui::*, render::*};
+use gui::{button::*, component::*, event::*, geom::*, module_gui::*, render::*, textbox::*};
 struct LiveCodeGui {
     bounds: Box3,
     open_button: Button,
+    build_box: TextBox,
+    fallback_button: Button,
+    fade_box: TextBox,
+    restart_button: Button,
+    up_button: Button,
+    down_button: Button,
     cmd_tx: UnboundedSender<UserCommand>,
+    shared: Shared,
+    // lines scrolled back from the end of the log
+    scroll: usize,
+    // the status, child and number of log lines as of the last render
+    shown: (BuildStatus, Option<ChildState>, usize),
 }
 const PADDING: f32 = 4.0;
+const ROW_HEIGHT: f32 = 26.0;
+const LABEL_WIDTH: f32 = 48.0;
+const LINE_HEIGHT: f32 = 14.0;
+const SMALL_BUTTON_WIDTH: f32 = 20.0;
+const RESTART_WIDTH: f32 = 64.0;
 impl ModuleGui for LiveCode {
     fn new_body(&mut self, ctx: &mut RenderContext, bounds: Box3) -> Box<dyn GuiComponent<BodyUpdate>> {
-        Box::new(LiveCodeGui {
+        let config = self.shared.config.lock().unwrap().clone();
+        let row = |row: usize| PADDING * (row + 1) as f32 + ROW_HEIGHT * row as f32;
+        // the fallback and fade share a row
+        let half = (bounds.size.x - PADDING * 3.0) / 2.0;
+        // the buttons for the child go at the end of the row that shows its state
+        let right = |width: f32, offset: f32| Box3 {
+            pos: bounds.pos + Pt3::new(bounds.size.x - PADDING - offset - width, row(3), 0.0),
+            size: Pt3::new(width, ROW_HEIGHT, 0.0),
+        };
+        let mut gui = LiveCodeGui {
             cmd_tx: self.cmd_tx.take().unwrap(),
             bounds,
             open_button: Button::new(
                 ctx.clone(),
                 "Pick file".into(),
                 Box3 {
-                    pos: bounds.pos + Pt3::new(PADDING, PADDING, 0.0),
-                    size: Pt3::new(bounds.size.x - PADDING * 2.0, 26.0, 0.0),
+                    pos: bounds.pos + Pt3::new(PADDING, row(0), 0.0),
+                    size: Pt3::new(bounds.size.x - PADDING * 2.0, ROW_HEIGHT, 0.0),
                 },
             ),
-        })
+            build_box: TextBox::new(
+                ctx.clone(),
+                config.build_command.unwrap_or_default(),
+                Box3 {
+                    pos: bounds.pos + Pt3::new(LABEL_WIDTH, row(1), 0.0),
+                    size: Pt3::new(bounds.size.x - LABEL_WIDTH - PADDING, ROW_HEIGHT, 0.0),
+                },
+            ),
+            fallback_button: Button::new(
+                ctx.clone(),
+                fallback_text(config.fallback),
+                Box3 {
+                    pos: bounds.pos + Pt3::new(PADDING, row(2), 0.0),
+                    size: Pt3::new(half, ROW_HEIGHT, 0.0),
+                },
+            ),
+            fade_box: TextBox::new(
+                ctx.clone(),
+                config.fade_blocks.to_string(),
+                Box3 {
+                    pos: bounds.pos + Pt3::new(PADDING * 2.0 + half + LABEL_WIDTH, row(2), 0.0),
+                    size: Pt3::new(half - LABEL_WIDTH, ROW_HEIGHT, 0.0),
+                },
+            ),
+            restart_button: Button::new(ctx.clone(), "Restart".into(), right(RESTART_WIDTH, 0.0)),
+            up_button: Button::new(
+                ctx.clone(),
+                "^".into(),
+                right(SMALL_BUTTON_WIDTH, RESTART_WIDTH + SMALL_BUTTON_WIDTH + PADDING * 2.0),
+            ),
+            down_button: Button::new(
+                ctx.clone(),
+                "v".into(),
+                right(SMALL_BUTTON_WIDTH, RESTART_WIDTH + PADDING),
+            ),
+            shared: self.shared.clone(),
+            scroll: 0,
+            shown: (BuildStatus::Idle, None, 0),
+        };
+        gui.shown = gui.current();
+        Box::new(gui)
+    }
+}
+fn fallback_text(fallback: Fallback) -> String {
+    format!("When late: {:?}", fallback)
+}
+/// How a child exited, for display.
+fn exit_text(status: process::ExitStatus) -> String {
+    if let Some(code) = status.code() {
+        return format!("exited with code {}", code);
+    }
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::ExitStatusExt;
+        if let Some(signal) = status.signal() {
+            return format!("killed by signal {}", signal);
+        }
+    }
+    "exited".into()
+}
+impl LiveCodeGui {
+    fn text_boxes(&mut self) -> [&mut TextBox; 2] {
+        [&mut self.build_box, &mut self.fade_box]
+    }
+    /// Use the settings being edited, rebuilding if the build command changed. Left empty, the command
+    /// is picked by the source's extension.
+    fn apply(&mut self) {
+        let command = self.build_box.content().trim().to_string();
+        let command = if command.is_empty() { None } else { Some(command) };
+        let fade_blocks = match self.fade_box.content().trim().parse() {
+            Ok(fade_blocks) => fade_blocks,
+            Err(_) => {
+                println!("The fade must be a whole number of blocks");
+                return;
+            }
+        };
+        let rebuild = {
+            let mut config = self.shared.config.lock().unwrap();
+            config.fade_blocks = fade_blocks;
+            mem::replace(&mut config.build_command, command.clone()) != command
+        };
+        for text_box in &mut self.text_boxes() {
+            text_box.set_focused(false);
+        }
+        if rebuild {
+            self.cmd_tx.unbounded_send(UserCommand::Rebuild).unwrap();
+        }
+    }
+    fn current(&self) -> (BuildStatus, Option<ChildState>, usize) {
+        let status = self.shared.status.lock().unwrap().clone();
+        // the children are locked while they process a block, so rather than wait, what was last seen
+        // is shown again
+        let child = match self.shared.children.try_lock() {
+            Ok(mut children) => children.current.as_mut().map(Child::state),
+            Err(_) => self.shown.1,
+        };
+        (status, child, self.shared.log.lock().unwrap().written)
     }
 }
 impl GuiComponent<bool> for LiveCodeGui {
@@ -285,11 +1032,77 @@ impl GuiComponent<bool> for LiveCodeGui {
     }
     fn render(&mut self, device: &mut gl::Device, ctx: &mut RenderContext) {
         self.open_button.render(device, ctx);
+        let pos = self.build_box.bounds().pos;
+        ctx.draw_text("Build", Pt3::new(self.bounds.pos.x + PADDING, pos.y + 6.0, pos.z), [1.0; 3]);
+        self.build_box.render(device, ctx);
+        self.fallback_button.render(device, ctx);
+        let pos = self.fade_box.bounds().pos;
+        ctx.draw_text("Fade", Pt3::new(pos.x - LABEL_WIDTH + PADDING, pos.y + 6.0, pos.z), [1.0; 3]);
+        self.fade_box.render(device, ctx);
+        self.restart_button.render(device, ctx);
+        self.up_button.render(device, ctx);
+        self.down_button.render(device, ctx);
+
+        self.shown = self.current();
+        let (ref status, child, _) = self.shown;
+        let (text, color) = match child {
+            None => ("No child".to_string(), [0.6; 3]),
+            Some(ChildState {
+                pid,
+                exit: None,
+            }) => (format!("PID {} running", pid), [0.2, 1.0, 0.2]),
+            Some(ChildState {
+                pid,
+                exit: Some(exit),
+            }) => (format!("PID {} {}", pid, exit_text(exit)), [1.0, 0.2, 0.2]),
+        };
+        let pos = self.restart_button.bounds().pos;
+        ctx.draw_text(&text, Pt3::new(self.bounds.pos.x + PADDING, pos.y + 6.0, pos.z), color);
+
+        let (heading, color) = match *status {
+            BuildStatus::Idle => ("No file", [0.6; 3]),
+            BuildStatus::Building => ("Building...", [1.0; 3]),
+            BuildStatus::Running => ("Log:", [1.0; 3]),
+            BuildStatus::BuildFailed(_) => ("Build failed:", [1.0, 0.2, 0.2]),
+            BuildStatus::SpawnFailed(_) => ("Could not start:", [1.0, 0.2, 0.2]),
+        };
+        // failures are shown in place of the log until the next start
+        let lines: Vec<String> = match *status {
+            BuildStatus::BuildFailed(ref details) | BuildStatus::SpawnFailed(ref details) => {
+                details.lines().map(String::from).collect()
+            }
+            _ => self.shared.log.lock().unwrap().lines.iter().cloned().collect(),
+        };
+        let top = self.bounds.pos + Pt3::new(PADDING, PADDING * 5.0 + ROW_HEIGHT * 4.0, 0.0);
+        let rows = ((self.bounds.size.y - (top.y - self.bounds.pos.y)) / LINE_HEIGHT).max(1.0) as usize - 1;
+        // show the end of the lines, unless scrolled back
+        self.scroll = self.scroll.min(lines.len().saturating_sub(rows));
+        let end = lines.len() - self.scroll;
+        let visible = lines[end.saturating_sub(rows)..end].iter().map(|line| line.replace('\t', "    "));
+        ctx.draw_text(heading, top, color);
+        for (row, line) in visible.enumerate() {
+            ctx.draw_text(&line, top + Pt3::new(0.0, (row + 1) as f32 * LINE_HEIGHT, 0.0), [0.8; 3]);
+        }
     }
     fn handle(&mut self, event: &Event) -> BodyUpdate {
+        if let EventData::Character('\r') = event.data {
+            if self.text_boxes().iter().any(|text_box| text_box.focused()) {
+                self.apply();
+                return true;
+            }
+        }
+        let mut update = false;
+        for text_box in &mut self.text_boxes() {
+            // only the text box under the cursor should take focus
+            let focus = match event.data {
+                EventData::Click(pos, _, _) => event.focus && text_box.intersect(pos),
+                _ => event.focus,
+            };
+            update |= text_box.handle(&event.with_focus(focus)) != TextBoxUpdate::Unchanged;
+        }
         match self.open_button.handle(event) {
-            ButtonUpdate::Unchanged => false,
-            ButtonUpdate::NeedRender => true,
+            ButtonUpdate::Unchanged => {}
+            ButtonUpdate::NeedRender => update = true,
             ButtonUpdate::Clicked => {
                 match nfd::open_file_dialog(None, None).unwrap() {
                     nfd::Response::Okay(path) => {
@@ -299,8 +1112,158 @@ impl GuiComponent<bool> for LiveCodeGui {
                     nfd::Response::Cancel => println!("selection cancelled"),
                     _ => panic!(),
                 }
-                true
+                update = true;
+            }
+        }
+        match self.fallback_button.handle(event) {
+            ButtonUpdate::Unchanged => {}
+            ButtonUpdate::NeedRender => update = true,
+            ButtonUpdate::Clicked => {
+                let mut config = self.shared.config.lock().unwrap();
+                config.fallback = match config.fallback {
+                    Fallback::Passthrough => Fallback::Silence,
+                    Fallback::Silence => Fallback::Passthrough,
+                };
+                self.fallback_button.set_label(fallback_text(config.fallback));
+                update = true;
+            }
+        }
+        match self.restart_button.handle(event) {
+            ButtonUpdate::Unchanged => {}
+            ButtonUpdate::NeedRender => update = true,
+            ButtonUpdate::Clicked => {
+                self.cmd_tx.unbounded_send(UserCommand::Restart).unwrap();
+                update = true;
             }
         }
+        match self.up_button.handle(event) {
+            ButtonUpdate::Unchanged => {}
+            ButtonUpdate::NeedRender => update = true,
+            ButtonUpdate::Clicked => {
+                // rendering keeps this from going past the start
+                self.scroll += 1;
+                update = true;
+            }
+        }
+        match self.down_button.handle(event) {
+            ButtonUpdate::Unchanged => {}
+            ButtonUpdate::NeedRender => update = true,
+            ButtonUpdate::Clicked => {
+                self.scroll = self.scroll.saturating_sub(1);
+                update = true;
+            }
+        }
+        // builds finish and children write and exit on their own, so check whether there is something
+        // new to show
+        update || self.current() != self.shown
+    }
+}
+
+#[test]
+fn test_stream_header() {
+    let header = StreamHeader::new(flow::AudioConfig {
+        rate: 44100,
+        block_size: 64,
+        channels: 2,
+    });
+    let bytes = header.to_bytes();
+    assert_eq!(bytes.len(), 24);
+    assert_eq!(&bytes[..4], b"FSYN");
+    let field = |idx: usize| u32::from_ne_bytes([bytes[idx], bytes[idx + 1], bytes[idx + 2], bytes[idx + 3]]);
+    assert_eq!(
+        [field(4), field(8), field(12), field(16), field(20)],
+        [PROTOCOL_VERSION, 44100, 2, 64, FORMAT_F32]
+    );
+
+    let mut block = Vec::new();
+    write_block(&mut block, &[1.0, -1.0], 1).unwrap();
+    assert_eq!(block.len(), 12);
+    assert_eq!(&block[..4], &1u32.to_ne_bytes());
+    assert_eq!(&block[4..8], &1.0f32.to_bits().to_ne_bytes());
+
+    let frame = |length, channels| Frame {
+        rate: 44100.0,
+        data: ::ndarray::Array2::zeros((length, channels)),
+    };
+    assert_eq!(header.check(&frame(32, 2)), Ok(()));
+    assert!(header.check(&frame(65, 2)).is_err());
+    assert!(header.check(&frame(64, 1)).is_err());
+}
+
+#[test]
+fn test_build_command() {
+    let config = LiveCodeConfig::default();
+    assert_eq!(
+        build_command(&config, Path::new("/tmp/synth.c")),
+        Some("cc -O2 -o {out} {src} -lm".to_string())
+    );
+    assert_eq!(build_command(&config, Path::new("/tmp/synth.py")), None);
+    assert_eq!(build_command(&config, Path::new("/tmp/synth")), None);
+    let config = LiveCodeConfig {
+        build_command: Some("make".into()),
+        ..LiveCodeConfig::default()
+    };
+    assert_eq!(build_command(&config, Path::new("/tmp/synth")), Some("make".to_string()));
+    assert_eq!(shell_quote(Path::new("/tmp/it's here")), "'/tmp/it'\\''s here'");
+}
+
+#[test]
+fn test_child_io() {
+    /// A child that never answers.
+    struct Stalled;
+    impl Read for Stalled {
+        fn read(&mut self, _: &mut [u8]) -> io::Result<usize> {
+            thread::sleep(Duration::from_secs(1));
+            Ok(0)
+        }
+    }
+    let frame = || Frame {
+        rate: 48000.0,
+        data: ::ndarray::Array2::from_shape_fn((2, 2), |(i, c)| (i * 2 + c) as f32),
+    };
+    let deadline = || Instant::now() + Duration::from_millis(100);
+
+    // the answer replaces the frame
+    let mut answer = Vec::new();
+    write_block(&mut answer, &[4.0, 3.0, 2.0, 1.0], 2).unwrap();
+    let mut io = ChildIo::new(io::sink(), io::Cursor::new(answer[4..].to_vec()));
+    let mut processed = frame();
+    assert!(io.send(&processed) && io.receive(&mut processed, deadline()));
+    assert_eq!(processed.data.iter().cloned().collect::<Vec<_>>(), vec![4.0, 3.0, 2.0, 1.0]);
+
+    // a short answer is an error rather than a partly filled frame
+    let mut io = ChildIo::new(io::sink(), io::Cursor::new(vec![0; 6]));
+    let mut processed = frame();
+    assert!(io.send(&processed) && !io.receive(&mut processed, deadline()));
+    assert!(io.dead);
+    assert_eq!(processed.data, frame().data);
+
+    // a stalled child is given up on, and isn't sent more until it answers
+    let mut io = ChildIo::new(io::sink(), Stalled);
+    let mut processed = frame();
+    assert!(io.send(&processed) && !io.receive(&mut processed, deadline()));
+    assert!(io.late && !io.dead);
+    assert_eq!(io.misses, 1);
+    assert!(!io.send(&processed));
+    assert_eq!(processed.data, frame().data);
+}
+
+#[test]
+fn test_exit_text() {
+    let status = |script| process::Command::new("sh").arg("-c").arg(script).status().unwrap();
+    assert_eq!(exit_text(status("exit 3")), "exited with code 3");
+    #[cfg(unix)]
+    assert_eq!(exit_text(status("kill -9 $$")), "killed by signal 9");
+}
+
+#[test]
+fn test_log() {
+    // only the newest lines are kept, but every line is counted
+    let mut log = Log::default();
+    for i in 0..LOG_LINES + 10 {
+        log.push(i.to_string());
     }
+    assert_eq!(log.lines.len(), LOG_LINES);
+    assert_eq!(log.lines.front().map(|x| &x[..]), Some("10"));
+    assert_eq!(log.written, LOG_LINES + 10);
 }
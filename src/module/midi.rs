@@ -0,0 +1,467 @@
+use futures::executor;
+use futures::future;
+use futures::prelude::*;
+use futures::task;
+
+use future_ext::{Breaker, FutureWrapExt, RemoteWaker};
+use module::{flow, Module};
+
+use crossbeam::queue::ArrayQueue;
+use jack;
+
+use std::mem;
+use std::sync::Arc;
+use std::thread::Thread;
+
+/// The most events a block can hold. Blocks are preallocated so that the JACK thread never
+/// allocates, so any events past this in a single cycle are dropped.
+pub const MAX_EVENTS: usize = 256;
+/// The number of blocks in flight in each direction.
+const POOL_SIZE: usize = 4;
+
+/// A MIDI message, decoded from its raw bytes.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum MidiMessage {
+    NoteOff { channel: u8, note: u8, velocity: u8 },
+    NoteOn { channel: u8, note: u8, velocity: u8 },
+    ControlChange { channel: u8, controller: u8, value: u8 },
+    /// Bend amount from -8192 to 8191, where 0 is centered.
+    PitchBend { channel: u8, value: i16 },
+    Clock,
+    Start,
+    Continue,
+    Stop,
+}
+
+impl MidiMessage {
+    /// Decode a message. Returns `None` for messages of other kinds, and for truncated ones.
+    pub fn parse(bytes: &[u8]) -> Option<MidiMessage> {
+        let status = *bytes.get(0)?;
+        let data = |idx: usize| bytes.get(idx).map(|byte| byte & 0x7f);
+        let channel = status & 0x0f;
+        match status & 0xf0 {
+            0x80 => Some(MidiMessage::NoteOff {
+                channel,
+                note: data(1)?,
+                velocity: data(2)?,
+            }),
+            // a note on with no velocity is a note off in disguise
+            0x90 if data(2)? == 0 => Some(MidiMessage::NoteOff {
+                channel,
+                note: data(1)?,
+                velocity: 0,
+            }),
+            0x90 => Some(MidiMessage::NoteOn {
+                channel,
+                note: data(1)?,
+                velocity: data(2)?,
+            }),
+            0xb0 => Some(MidiMessage::ControlChange {
+                channel,
+                controller: data(1)?,
+                value: data(2)?,
+            }),
+            0xe0 => Some(MidiMessage::PitchBend {
+                channel,
+                value: ((data(2)? as i16) << 7 | data(1)? as i16) - 8192,
+            }),
+            0xf0 => match status {
+                0xf8 => Some(MidiMessage::Clock),
+                0xfa => Some(MidiMessage::Start),
+                0xfb => Some(MidiMessage::Continue),
+                0xfc => Some(MidiMessage::Stop),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    /// Encode the message, returning a buffer and how much of it is used.
+    pub fn to_bytes(&self) -> ([u8; 3], usize) {
+        match *self {
+            MidiMessage::NoteOff {
+                channel,
+                note,
+                velocity,
+            } => ([0x80 | channel & 0x0f, note & 0x7f, velocity & 0x7f], 3),
+            MidiMessage::NoteOn {
+                channel,
+                note,
+                velocity,
+            } => ([0x90 | channel & 0x0f, note & 0x7f, velocity & 0x7f], 3),
+            MidiMessage::ControlChange {
+                channel,
+                controller,
+                value,
+            } => ([0xb0 | channel & 0x0f, controller & 0x7f, value & 0x7f], 3),
+            MidiMessage::PitchBend {
+                channel,
+                value,
+            } => {
+                let value = (value.max(-8192).min(8191) + 8192) as u16;
+                ([0xe0 | channel & 0x0f, (value & 0x7f) as u8, (value >> 7) as u8], 3)
+            }
+            MidiMessage::Clock => ([0xf8, 0, 0], 1),
+            MidiMessage::Start => ([0xfa, 0, 0], 1),
+            MidiMessage::Continue => ([0xfb, 0, 0], 1),
+            MidiMessage::Stop => ([0xfc, 0, 0], 1),
+        }
+    }
+}
+
+/// A MIDI message, timed to the sample within its block.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct MidiEvent {
+    /// Offset of the event from the start of the block, in samples.
+    pub time: u32,
+    pub message: MidiMessage,
+}
+
+/// The MIDI events of one JACK cycle. This is the MIDI counterpart of `Frame`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct MidiBlock {
+    pub rate: f32,
+    /// Length of the block, in samples.
+    pub length: usize,
+    /// Events in the order they occur.
+    pub events: Vec<MidiEvent>,
+}
+
+impl MidiBlock {
+    fn with_capacity(capacity: usize) -> MidiBlock {
+        MidiBlock {
+            rate: 0.0,
+            length: 0,
+            events: Vec::with_capacity(capacity),
+        }
+    }
+}
+
+/// Preallocated blocks, recycled between the JACK thread and the graph like the frame buffers of
+/// `AudioIO`.
+struct BlockPool {
+    /// Empty blocks for the producer to fill.
+    free: ArrayQueue<MidiBlock>,
+    /// Filled blocks waiting for the consumer.
+    filled: ArrayQueue<MidiBlock>,
+    waker: Thread,
+}
+impl BlockPool {
+    fn new(waker: Thread) -> BlockPool {
+        let pool = BlockPool {
+            free: ArrayQueue::new(POOL_SIZE),
+            filled: ArrayQueue::new(POOL_SIZE),
+            waker,
+        };
+        for _ in 0..POOL_SIZE {
+            let _ = pool.free.push(MidiBlock::with_capacity(MAX_EVENTS));
+        }
+        pool
+    }
+}
+
+/// Open a JACK client for a MIDI module, reporting failure.
+fn open_client(name: &str) -> Option<jack::Client> {
+    match jack::Client::new(name, jack::ClientOptions::NO_START_SERVER) {
+        Ok((client, _status)) => Some(client),
+        Err(err) => {
+            println!("Could not open JACK client {:?}: {:?}", name, err);
+            None
+        }
+    }
+}
+
+/// Receives MIDI from JACK. Each request on the `Output` port is answered with the events of the next
+/// JACK cycle.
+pub struct MidiInput {
+    ifc: Arc<flow::Interface>,
+    out_port: Arc<flow::Port<(), MidiBlock>>,
+    client: Option<jack::AsyncClient<(), MidiInProcessor>>,
+    breaker: Breaker,
+}
+
+impl Module for MidiInput {
+    fn new(ifc: Arc<flow::Interface>) -> MidiInput {
+        let breaker = ifc.breaker();
+        let out_port = ifc.get_or_create_port("Output".into());
+        out_port.set_tag("midi");
+        MidiInput {
+            ifc,
+            out_port,
+            client: None,
+            breaker,
+        }
+    }
+    fn name() -> &'static str {
+        "MIDI In"
+    }
+    fn start<Ex: executor::Executor>(&mut self, mut exec: Ex) {
+        let waker = Arc::new(RemoteWaker::new());
+        let pool = Arc::new(BlockPool::new(waker.thread()));
+        let client = open_client("flow-synth-midi-in").and_then(|client| {
+            let port = client.register_port("in", jack::MidiIn::default()).ok()?;
+            let processor = MidiInProcessor {
+                port,
+                pool: pool.clone(),
+            };
+            jack::AsyncClient::new(client, (), processor).ok()
+        });
+        if client.is_none() {
+            println!("MIDI input is unavailable");
+            return;
+        }
+        self.client = client;
+
+        exec.spawn(Box::new(future::loop_fn(
+            (self.out_port.clone(), waker, pool, self.breaker.clone()),
+            |(port, waker, pool, breaker)| {
+                port.read1()
+                    .wrap((waker, pool))
+                    .and_then(|((waker, pool), (port, _req))| {
+                        let (next_waker, next_pool) = (waker.clone(), pool.clone());
+                        future::poll_fn(move |cx| Ok(next_block(cx, &next_waker, &next_pool)))
+                            .and_then(|block| port.write1(block).wrap((waker, pool)))
+                    })
+                    .then(move |result| -> Result<_, Never> {
+                        match result {
+                            Ok(((waker, pool), port)) if !breaker.test() => {
+                                Ok(future::Loop::Continue((port, waker, pool, breaker)))
+                            }
+                            Err((_, (_, flow::Error::Closed))) | Ok(_) => Ok(future::Loop::Break(())),
+                            Err(((waker, pool), (port, err))) => {
+                                println!("MIDI in err: {:?}", err);
+                                Ok(future::Loop::Continue((port, waker, pool, breaker)))
+                            }
+                        }
+                    })
+            },
+        ))).unwrap();
+    }
+    fn stop(&mut self) {
+        self.breaker.brake();
+        if let Some(client) = self.client.take() {
+            let _ = client.deactivate();
+        }
+    }
+    fn ports(&self) -> Vec<Arc<flow::OpaquePort>> {
+        self.ifc.ports()
+    }
+}
+
+/// Take the next filled block. The graph gets its own copy, so that the block can go straight back to
+/// the JACK thread.
+fn next_block(cx: &mut task::Context, waker: &RemoteWaker, pool: &BlockPool) -> Async<MidiBlock> {
+    waker.register(cx);
+    match pool.filled.pop() {
+        Ok(block) => {
+            let copy = block.clone();
+            let _ = pool.free.push(block);
+            Async::Ready(copy)
+        }
+        Err(_) => Async::Pending,
+    }
+}
+
+struct MidiInProcessor {
+    port: jack::Port<jack::MidiIn>,
+    pool: Arc<BlockPool>,
+}
+impl jack::ProcessHandler for MidiInProcessor {
+    fn process(&mut self, client: &jack::Client, ps: &jack::ProcessScope) -> jack::Control {
+        // if the graph has fallen behind, this cycle's events are dropped
+        if let Ok(mut block) = self.pool.free.pop() {
+            block.rate = client.sample_rate() as f32;
+            block.length = ps.n_frames() as usize;
+            block.events.clear();
+            for raw in self.port.iter(ps) {
+                // never grow the block on this thread
+                if block.events.len() == block.events.capacity() {
+                    break;
+                }
+                if let Some(message) = MidiMessage::parse(raw.bytes) {
+                    block.events.push(MidiEvent {
+                        time: raw.time,
+                        message,
+                    });
+                }
+            }
+            // there is always room, since every block came from here
+            let _ = self.pool.filled.push(block).map_err(mem::forget);
+            self.pool.waker.unpark();
+        }
+        jack::Control::Continue
+    }
+}
+
+/// Sends MIDI to JACK. One block is requested from the `Input` port for every JACK cycle.
+pub struct MidiOutput {
+    ifc: Arc<flow::Interface>,
+    in_port: Arc<flow::Port<MidiBlock, ()>>,
+    client: Option<jack::AsyncClient<(), MidiOutProcessor>>,
+    breaker: Breaker,
+}
+
+impl Module for MidiOutput {
+    fn new(ifc: Arc<flow::Interface>) -> MidiOutput {
+        let breaker = ifc.breaker();
+        let in_port = ifc.get_or_create_port("Input".into());
+        in_port.set_tag("midi");
+        MidiOutput {
+            ifc,
+            in_port,
+            client: None,
+            breaker,
+        }
+    }
+    fn name() -> &'static str {
+        "MIDI Out"
+    }
+    fn start<Ex: executor::Executor>(&mut self, mut exec: Ex) {
+        let waker = Arc::new(RemoteWaker::new());
+        let pool = Arc::new(BlockPool::new(waker.thread()));
+        let client = open_client("flow-synth-midi-out").and_then(|client| {
+            let port = client.register_port("out", jack::MidiOut::default()).ok()?;
+            let processor = MidiOutProcessor {
+                port,
+                pool: pool.clone(),
+            };
+            jack::AsyncClient::new(client, (), processor).ok()
+        });
+        if client.is_none() {
+            println!("MIDI output is unavailable");
+            return;
+        }
+        self.client = client;
+
+        exec.spawn(Box::new(future::loop_fn(
+            (self.in_port.clone(), waker, pool, self.breaker.clone()),
+            |(port, waker, pool, breaker)| {
+                port.write1(())
+                    .and_then(|port| port.read1())
+                    .wrap((waker, pool))
+                    .and_then(|((waker, pool), (port, block))| {
+                        let (queue_waker, queue_pool) = (waker.clone(), pool.clone());
+                        future::poll_fn(move |cx| Ok(queue_block(cx, &queue_waker, &queue_pool, &block)))
+                            .map(|()| ((waker, pool), port))
+                    })
+                    .then(move |result| -> Result<_, Never> {
+                        match result {
+                            Ok(((waker, pool), port)) if !breaker.test() => {
+                                Ok(future::Loop::Continue((port, waker, pool, breaker)))
+                            }
+                            Err((_, (_, flow::Error::Closed))) | Ok(_) => Ok(future::Loop::Break(())),
+                            Err(((waker, pool), (port, err))) => {
+                                println!("MIDI out err: {:?}", err);
+                                Ok(future::Loop::Continue((port, waker, pool, breaker)))
+                            }
+                        }
+                    })
+            },
+        ))).unwrap();
+    }
+    fn stop(&mut self) {
+        self.breaker.brake();
+        if let Some(client) = self.client.take() {
+            let _ = client.deactivate();
+        }
+    }
+    fn ports(&self) -> Vec<Arc<flow::OpaquePort>> {
+        self.ifc.ports()
+    }
+}
+
+/// Copy a block from the graph into a free block, once there is one, and queue it to be sent.
+fn queue_block(
+    cx: &mut task::Context,
+    waker: &RemoteWaker,
+    pool: &BlockPool,
+    block: &MidiBlock,
+) -> Async<()> {
+    waker.register(cx);
+    match pool.free.pop() {
+        Ok(mut free) => {
+            free.rate = block.rate;
+            free.length = block.length;
+            free.events.clear();
+            free.events.extend(block.events.iter().take(MAX_EVENTS));
+            let _ = pool.filled.push(free);
+            Async::Ready(())
+        }
+        Err(_) => Async::Pending,
+    }
+}
+
+struct MidiOutProcessor {
+    port: jack::Port<jack::MidiOut>,
+    pool: Arc<BlockPool>,
+}
+impl jack::ProcessHandler for MidiOutProcessor {
+    fn process(&mut self, client: &jack::Client, ps: &jack::ProcessScope) -> jack::Control {
+        let mut writer = self.port.writer(ps);
+        if let Ok(block) = self.pool.filled.pop() {
+            let last = ps.n_frames().saturating_sub(1);
+            for event in &block.events {
+                let (bytes, len) = event.message.to_bytes();
+                // events past the end of the cycle go out at its last sample
+                let raw = jack::RawMidi {
+                    time: event.time.min(last),
+                    bytes: &bytes[..len],
+                };
+                // fails only when the port's buffer is full, and then there's nothing to be done
+                let _ = writer.write(&raw);
+            }
+            let _ = self.pool.free.push(block).map_err(mem::forget);
+            self.pool.waker.unpark();
+        }
+        jack::Control::Continue
+    }
+}
+
+#[test]
+fn test_parse() {
+    let messages = [
+        MidiMessage::NoteOn {
+            channel: 3,
+            note: 60,
+            velocity: 100,
+        },
+        MidiMessage::NoteOff {
+            channel: 15,
+            note: 127,
+            velocity: 0,
+        },
+        MidiMessage::ControlChange {
+            channel: 0,
+            controller: 7,
+            value: 64,
+        },
+        MidiMessage::PitchBend {
+            channel: 1,
+            value: -8192,
+        },
+        MidiMessage::PitchBend {
+            channel: 1,
+            value: 8191,
+        },
+        MidiMessage::Clock,
+        MidiMessage::Stop,
+    ];
+    for message in &messages {
+        let (bytes, len) = message.to_bytes();
+        assert_eq!(MidiMessage::parse(&bytes[..len]), Some(*message));
+    }
+    // a note on with no velocity is a note off
+    assert_eq!(
+        MidiMessage::parse(&[0x92, 64, 0]),
+        Some(MidiMessage::NoteOff {
+            channel: 2,
+            note: 64,
+            velocity: 0,
+        })
+    );
+    assert_eq!(MidiMessage::parse(&[0xe0, 0, 0x40]).unwrap().to_bytes().0, [0xe0, 0, 0x40]);
+    // truncated and unsupported messages
+    assert_eq!(MidiMessage::parse(&[0x90, 64]), None);
+    assert_eq!(MidiMessage::parse(&[0xf0, 1, 2, 0xf7]), None);
+    assert_eq!(MidiMessage::parse(&[]), None);
+}
@@ -1,9 +1,16 @@
+pub mod audio_backend;
 pub mod audio_io;
 pub mod debug;
 pub mod flow;
 pub mod livecode;
+pub mod midi;
+pub mod offline;
+pub mod resample;
+pub mod subgraph;
+pub mod transport;
 
-use futures::executor;
+use futures::executor::{self, ThreadPool};
+use std::any::Any;
 use std::sync::Arc;
 
 pub trait Module: Send {
@@ -16,4 +23,87 @@ pub trait Module: Send {
     fn start<Ex: executor::Executor>(&mut self, exec: Ex);
     fn stop(&mut self);
     fn ports(&self) -> Vec<Arc<flow::OpaquePort>>;
+    /// Settings to keep in a saved patch, serialized as RON. Modules without settings return `None`.
+    fn save_state(&self) -> Option<String> {
+        None
+    }
+    /// Restore settings produced by `save_state`. This may happen after the module has started.
+    fn load_state(&mut self, state: &str) {}
+}
+
+/// An object safe view of a `Module`, so that modules of different types can be kept together.
+pub trait DynModule: Send {
+    fn name(&self) -> &'static str;
+    fn start(&mut self, exec: ThreadPool);
+    fn stop(&mut self);
+    fn ports(&self) -> Vec<Arc<flow::OpaquePort>>;
+    fn save_state(&self) -> Option<String>;
+    fn load_state(&mut self, state: &str);
+    /// Get the underlying module, for downcasting.
+    fn as_any_mut(&mut self) -> &mut dyn Any;
+}
+
+/// Construct a module of type `T`, erasing its type.
+pub fn new_dyn<T: Module + 'static>(ifc: Arc<flow::Interface>) -> Box<dyn DynModule> {
+    into_dyn(T::new(ifc))
+}
+
+/// Erase the type of a module.
+pub fn into_dyn<T: Module + 'static>(module: T) -> Box<dyn DynModule> {
+    Box::new(DynWrapper(module))
+}
+
+struct DynWrapper<T: Module>(T);
+impl<T: Module + 'static> DynModule for DynWrapper<T> {
+    fn name(&self) -> &'static str {
+        T::name()
+    }
+    fn start(&mut self, exec: ThreadPool) {
+        self.0.start(exec)
+    }
+    fn stop(&mut self) {
+        self.0.stop()
+    }
+    fn ports(&self) -> Vec<Arc<flow::OpaquePort>> {
+        self.0.ports()
+    }
+    fn save_state(&self) -> Option<String> {
+        self.0.save_state()
+    }
+    fn load_state(&mut self, state: &str) {
+        self.0.load_state(state)
+    }
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        &mut self.0
+    }
+}
+
+/// Something that wants to know about every type of module that can be created by name, such as
+/// when loading a saved patch.
+pub trait ModuleVisitor {
+    fn visit<T: Module + 'static>(&mut self);
+}
+
+/// Call `visitor.visit` with every type of module. This is the one list of modules shared by the
+/// GUI and headless modes.
+pub fn visit_modules<V: ModuleVisitor>(visitor: &mut V) {
+    visitor.visit::<debug::Printer<i32>>();
+    visitor.visit::<debug::Printer<i64>>();
+    visitor.visit::<debug::Counter<i32>>();
+    visitor.visit::<audio_io::AudioIO>();
+    visitor.visit::<midi::MidiInput>();
+    visitor.visit::<midi::MidiOutput>();
+    visitor.visit::<transport::Transport>();
+    visitor.visit::<resample::Resample>();
+    visitor.visit::<livecode::LiveCode>();
+    visitor.visit::<subgraph::Subgraph>();
+}
+
+/// Register the built-in conversions between the data types used by the modules in this crate, so
+/// that their ports can be connected even when the types don't line up exactly.
+pub fn register_adapters(graph: &flow::Graph) {
+    // a request for a number of items can stand in for a plain request
+    graph.register_adapter(|_: usize| ());
+    debug::register_adapters(graph);
+    audio_io::register_adapters(graph);
 }
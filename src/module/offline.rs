@@ -0,0 +1,287 @@
+use futures::executor;
+use futures::future;
+use futures::prelude::*;
+
+use future_ext::Breaker;
+use module::{audio_io::Frame, flow, Module};
+use wav;
+
+use ndarray::Array2;
+
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter};
+use std::path::PathBuf;
+use std::sync::mpsc::Sender;
+use std::sync::Arc;
+
+/// Settings for an offline render.
+#[derive(Clone, Debug)]
+pub struct OfflineConfig {
+    /// Sample rate of the frames pulled through the graph and of the output file.
+    pub rate: u32,
+    /// Number of samples in each frame.
+    pub block_size: usize,
+    /// Number of channels recorded to the output file.
+    pub channels: usize,
+    /// Number of frames to render.
+    pub blocks: usize,
+    /// File to write the rendered audio to.
+    pub output: PathBuf,
+    /// File to play through the `Output` port. Silence is played if this is not set, or once it
+    /// runs out.
+    pub input: Option<PathBuf>,
+}
+
+impl OfflineConfig {
+    /// The settings frames have to match to be recorded.
+    pub fn audio_config(&self) -> flow::AudioConfig {
+        flow::AudioConfig {
+            rate: self.rate,
+            block_size: self.block_size,
+            channels: self.channels,
+        }
+    }
+}
+
+impl Default for OfflineConfig {
+    fn default() -> OfflineConfig {
+        OfflineConfig {
+            rate: 48000,
+            block_size: 256,
+            channels: 2,
+            blocks: 48000 * 10 / 256,
+            output: "render.wav".into(),
+            input: None,
+        }
+    }
+}
+
+type InPort = Arc<flow::Port<Frame, ()>>;
+type Pull = Box<dyn Future<Item = (InPort, Option<Frame>), Error = (InPort, flow::Error)> + Send>;
+
+/// Plays the role of `AudioIO` without an audio server, pulling frames through the graph as fast as
+/// possible and writing them to a WAV file.
+pub struct OfflineRender {
+    ifc: Arc<flow::Interface>,
+    in_port: InPort,
+    out_port: Arc<flow::Port<(), Frame>>,
+    config: OfflineConfig,
+    done: Option<Sender<io::Result<()>>>,
+    breaker: Breaker,
+}
+
+impl Module for OfflineRender {
+    fn new(ifc: Arc<flow::Interface>) -> OfflineRender {
+        OfflineRender::with_config(ifc, OfflineConfig::default(), None)
+    }
+    fn name() -> &'static str {
+        "OfflineRender"
+    }
+    fn start<Ex: executor::Executor>(&mut self, mut exec: Ex) {
+        // the rest of the graph renders at the same settings
+        if let Some(graph) = self.ifc.graph() {
+            graph.set_audio_config(self.config.audio_config());
+        }
+        let input = match self.config.input {
+            Some(ref path) => match File::open(path).and_then(|file| wav::read(BufReader::new(file))) {
+                Ok((spec, samples)) => {
+                    if spec.rate != self.config.rate {
+                        println!(
+                            "{:?} has a sample rate of {}, but rendering at {}",
+                            path, spec.rate, self.config.rate
+                        );
+                    }
+                    Some((spec.channels as usize, samples))
+                }
+                Err(err) => {
+                    println!("Could not read {:?}: {:?}", path, err);
+                    None
+                }
+            },
+            None => None,
+        };
+        exec.spawn(self.play(input)).unwrap();
+        exec.spawn(self.record()).unwrap();
+    }
+    fn stop(&mut self) {
+        self.breaker.brake();
+    }
+    fn ports(&self) -> Vec<Arc<flow::OpaquePort>> {
+        self.ifc.ports()
+    }
+}
+
+impl OfflineRender {
+    /// Create a renderer. Once the render is complete, or has failed, the result is sent to `done`.
+    pub fn with_config(
+        ifc: Arc<flow::Interface>,
+        config: OfflineConfig,
+        done: Option<Sender<io::Result<()>>>,
+    ) -> OfflineRender {
+        let breaker = ifc.breaker();
+        let in_port = ifc.get_or_create_port("Input".into());
+        let out_port = ifc.get_or_create_port("Output".into());
+        in_port.set_tag("audio");
+        out_port.set_tag("audio");
+        OfflineRender {
+            ifc,
+            in_port,
+            out_port,
+            config,
+            done,
+            breaker,
+        }
+    }
+
+    /// Answer each request on the `Output` port with the next frame of the input file.
+    fn play(&self, input: Option<(usize, Vec<f32>)>) -> Box<dyn Future<Item = (), Error = Never> + Send> {
+        let (channels, samples) = input.unwrap_or((self.config.channels, Vec::new()));
+        let rate = self.config.rate as f32;
+        let block_size = self.config.block_size;
+        Box::new(future::loop_fn(
+            (self.out_port.clone(), 0, self.breaker.clone()),
+            move |(port, pos, breaker)| {
+                let frame = Frame {
+                    rate,
+                    data: Array2::from_shape_fn((block_size, channels), |(i, c)| {
+                        samples.get((pos + i) * channels + c).cloned().unwrap_or(0.0)
+                    }),
+                };
+                port.read1()
+                    .and_then(move |(port, _req)| port.write1(frame))
+                    .then(move |result| -> Result<_, Never> {
+                        match result {
+                            Err((_, flow::Error::Closed)) => Ok(future::Loop::Break(())),
+                            _ if breaker.test() => Ok(future::Loop::Break(())),
+                            Ok(port) => Ok(future::Loop::Continue((port, pos + block_size, breaker))),
+                            // the frame didn't make it, so try it again
+                            Err((port, _)) => Ok(future::Loop::Continue((port, pos, breaker))),
+                        }
+                    })
+            },
+        ))
+    }
+
+    /// Pull the configured number of frames from the `Input` port and write them to the output
+    /// file. Frames of the wrong rate or length are recorded as silence.
+    fn record(&mut self) -> Box<dyn Future<Item = (), Error = Never> + Send> {
+        let config = self.config.clone();
+        let done = self.done.take();
+        let recorded = Vec::with_capacity(config.blocks * config.block_size * config.channels);
+        let silence = Frame {
+            rate: config.rate as f32,
+            data: Array2::zeros((config.block_size, config.channels)),
+        };
+        let channels = config.channels;
+        let blocks = config.blocks;
+        let audio = config.audio_config();
+        let connected = !self.in_port.edges().is_empty();
+        Box::new(
+            future::loop_fn(
+                (self.in_port.clone(), recorded, 0, 0, self.breaker.clone()),
+                move |(port, mut recorded, n, bad, breaker)| {
+                    let silence = silence.clone();
+                    let pull: Pull = if n == blocks {
+                        Box::new(future::ok((port, None)))
+                    } else if connected {
+                        Box::new(
+                            port.write1(())
+                                .and_then(|port| port.read1())
+                                .map(|(port, frame)| (port, Some(frame))),
+                        )
+                    } else {
+                        // nothing will ever arrive, so record silence instead of waiting on it
+                        Box::new(future::ok((port, Some(silence.clone()))))
+                    };
+                    pull.then(move |result| -> Result<_, Never> {
+                        let (port, frame, bad) = match result {
+                            Ok((_, None)) | Err((_, flow::Error::Closed)) => {
+                                return Ok(future::Loop::Break((recorded, bad)));
+                            }
+                            Ok((port, Some(frame))) => match frame.check(&audio) {
+                                Err(ref mismatch) if !mismatch.is_playable() => {
+                                    if bad == 0 {
+                                        println!("Recording silence in place of a bad frame: {}", mismatch);
+                                    }
+                                    (port, silence, bad + 1)
+                                }
+                                _ => (port, frame, bad),
+                            },
+                            // keep the timing intact by filling in the missing frame
+                            Err((port, _)) => (port, silence, bad),
+                        };
+                        for row in frame.data.outer_iter() {
+                            for c in 0..channels {
+                                recorded.push(row.get(c).cloned().unwrap_or(0.0));
+                            }
+                        }
+                        if breaker.test() {
+                            Ok(future::Loop::Break((recorded, bad)))
+                        } else {
+                            Ok(future::Loop::Continue((port, recorded, n + 1, bad, breaker)))
+                        }
+                    })
+                },
+            ).map(move |(recorded, bad)| {
+                if bad > 0 {
+                    println!("{} of {} frames didn't match the render settings", bad, config.blocks);
+                }
+                let spec = wav::WavSpec {
+                    channels: config.channels as u16,
+                    rate: config.rate,
+                };
+                let result = File::create(&config.output)
+                    .and_then(|file| wav::write(BufWriter::new(file), spec, &recorded));
+                if let Err(ref err) = result {
+                    println!("Could not write {:?}: {:?}", config.output, err);
+                }
+                if let Some(done) = done {
+                    let _ = done.send(result);
+                }
+            }),
+        )
+    }
+}
+
+#[test]
+fn test_loopback() {
+    use futures::executor::ThreadPool;
+    use std::env;
+    use std::sync::mpsc;
+
+    let dir = env::temp_dir();
+    let input = dir.join(format!("flow-synth-test-in-{}.wav", std::process::id()));
+    let output = dir.join(format!("flow-synth-test-out-{}.wav", std::process::id()));
+    let spec = wav::WavSpec {
+        channels: 2,
+        rate: 1000,
+    };
+    let samples: Vec<f32> = (0..100).map(|x| x as f32 / 100.0).collect();
+    wav::write(File::create(&input).unwrap(), spec, &samples).unwrap();
+
+    // the renderer's own output is fed straight back into it
+    let graph = flow::Graph::new();
+    let (done_tx, done_rx) = mpsc::channel();
+    let config = OfflineConfig {
+        rate: 1000,
+        block_size: 16,
+        channels: 2,
+        blocks: 4,
+        output: output.clone(),
+        input: Some(input.clone()),
+    };
+    let mut render = OfflineRender::with_config(graph.add_node(), config, Some(done_tx));
+    render.out_port.connect(&render.in_port).unwrap();
+    render.start(ThreadPool::new().unwrap());
+    done_rx.recv().unwrap().unwrap();
+    render.stop();
+
+    let (out_spec, out_samples) = wav::read(File::open(&output).unwrap()).unwrap();
+    assert_eq!(out_spec, spec);
+    // four blocks of 16 stereo samples, padded with silence past the end of the input
+    let mut expected = samples.clone();
+    expected.resize(4 * 16 * 2, 0.0);
+    assert_eq!(out_samples, expected);
+    let _ = std::fs::remove_file(input);
+    let _ = std::fs::remove_file(output);
+}
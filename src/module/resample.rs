@@ -0,0 +1,490 @@
+use futures::executor;
+use futures::future;
+use futures::prelude::*;
+
+use future_ext::{Breaker, FutureWrapExt};
+use module::{audio_io::Frame, flow, Module};
+
+use ndarray::Array2;
+use ron;
+
+use std::f64::consts::PI;
+use std::sync::{Arc, Mutex};
+
+/// How hard the resampler works to keep aliasing out.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Quality {
+    Low,
+    Medium,
+    High,
+}
+impl Quality {
+    /// Zero crossings of the kernel on either side of its center.
+    fn zero_crossings(self) -> usize {
+        match self {
+            Quality::Low => 8,
+            Quality::Medium => 16,
+            Quality::High => 32,
+        }
+    }
+    /// The next setting, for cycling through them.
+    fn next(self) -> Quality {
+        match self {
+            Quality::Low => Quality::Medium,
+            Quality::Medium => Quality::High,
+            Quality::High => Quality::Low,
+        }
+    }
+}
+
+/// Settings of a `Resample` module.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ResampleConfig {
+    /// Rate to convert to. When not set, the rate of the graph is used.
+    pub target_rate: Option<u32>,
+    pub quality: Quality,
+}
+impl Default for ResampleConfig {
+    fn default() -> ResampleConfig {
+        ResampleConfig {
+            target_rate: None,
+            quality: Quality::Medium,
+        }
+    }
+}
+
+/// Points per zero crossing in the kernel table. Values in between are interpolated.
+const TABLE_RESOLUTION: usize = 256;
+/// Fraction of the lower Nyquist frequency that is kept when the rate changes, leaving room for the
+/// filter to roll off.
+const ROLLOFF: f64 = 0.95;
+
+/// Converts a stream of frames from one rate to another with a windowed sinc filter. Input is
+/// buffered across frames, so that the output is continuous no matter how the stream is divided.
+pub struct Resampler {
+    quality: Quality,
+    source_rate: f64,
+    target_rate: f64,
+    // input samples per output sample
+    step: f64,
+    // cutoff frequency, relative to the input's Nyquist frequency
+    cutoff: f64,
+    // how far the kernel reaches on either side, in input samples
+    reach: f64,
+    // one side of the kernel, by zero crossing
+    table: Vec<f64>,
+    // input waiting to be resampled, one buffer per channel
+    buffers: Vec<Vec<f32>>,
+    // position of the next output sample in the buffers
+    pos: f64,
+}
+
+impl Resampler {
+    pub fn new(quality: Quality, source_rate: f64, target_rate: f64) -> Resampler {
+        let mut resampler = Resampler {
+            quality,
+            source_rate: 0.0,
+            target_rate: 0.0,
+            step: 1.0,
+            cutoff: 1.0,
+            reach: 0.0,
+            table: Vec::new(),
+            buffers: Vec::new(),
+            pos: 0.0,
+        };
+        resampler.configure(quality, source_rate, target_rate);
+        resampler
+    }
+
+    /// Change the settings. Buffered input is kept, so that the change doesn't interrupt the output.
+    pub fn configure(&mut self, quality: Quality, source_rate: f64, target_rate: f64) {
+        if quality == self.quality && source_rate == self.source_rate && target_rate == self.target_rate {
+            return;
+        }
+        if quality != self.quality || self.table.is_empty() {
+            // a sinc under a Blackman window
+            let zero_crossings = quality.zero_crossings() as f64;
+            self.table = (0..quality.zero_crossings() * TABLE_RESOLUTION + 1)
+                .map(|i| {
+                    let x = i as f64 / TABLE_RESOLUTION as f64;
+                    let sinc = if i == 0 { 1.0 } else { (PI * x).sin() / (PI * x) };
+                    let r = x / zero_crossings;
+                    sinc * (0.42 + 0.5 * (PI * r).cos() + 0.08 * (2.0 * PI * r).cos())
+                })
+                .collect();
+        }
+        self.quality = quality;
+        self.source_rate = source_rate;
+        self.target_rate = target_rate;
+        self.step = source_rate / target_rate;
+        // at the same rate, the kernel lands on whole samples and passes them through untouched
+        self.cutoff = if source_rate == target_rate {
+            1.0
+        } else {
+            ROLLOFF * (target_rate / source_rate).min(1.0)
+        };
+        self.reach = quality.zero_crossings() as f64 / self.cutoff;
+    }
+
+    pub fn source_rate(&self) -> f64 {
+        self.source_rate
+    }
+    pub fn target_rate(&self) -> f64 {
+        self.target_rate
+    }
+
+    /// The filter kernel at a distance of `x` input samples.
+    fn kernel(&self, x: f64) -> f64 {
+        let u = (x * self.cutoff).abs() * TABLE_RESOLUTION as f64;
+        let idx = u as usize;
+        if idx + 1 >= self.table.len() {
+            return 0.0;
+        }
+        let frac = u - idx as f64;
+        self.cutoff * (self.table[idx] * (1.0 - frac) + self.table[idx + 1] * frac)
+    }
+
+    /// Add a frame of input. If the number of channels changes, the resampler starts over.
+    pub fn push(&mut self, frame: &Frame) {
+        let channels = frame.data.shape()[1];
+        if channels != self.buffers.len() {
+            // start from silence, so that the first samples have something before them
+            let history = self.reach.ceil() as usize;
+            self.buffers = vec![vec![0.0; history]; channels];
+            self.pos = history as f64;
+        }
+        for (channel, buffer) in self.buffers.iter_mut().enumerate() {
+            buffer.extend(frame.data.column(channel).iter());
+        }
+    }
+
+    /// Produce `length` samples of output, if enough input has been pushed to do so.
+    pub fn pull(&mut self, length: usize) -> Option<Frame> {
+        let available = self.buffers.get(0).map_or(0, Vec::len);
+        let last = self.pos + length.saturating_sub(1) as f64 * self.step;
+        if self.buffers.is_empty() || (last + self.reach).floor() as usize >= available {
+            return None;
+        }
+        let mut data = Array2::zeros((length, self.buffers.len()));
+        for i in 0..length {
+            let t = self.pos + i as f64 * self.step;
+            let first = (t - self.reach).ceil().max(0.0) as usize;
+            let end = (t + self.reach).floor() as usize + 1;
+            for k in first..end {
+                let weight = self.kernel(t - k as f64) as f32;
+                for (channel, buffer) in self.buffers.iter().enumerate() {
+                    data[(i, channel)] += buffer[k] * weight;
+                }
+            }
+        }
+        self.pos += length as f64 * self.step;
+        // forget the input that no later output can reach
+        let done = ((self.pos - self.reach).floor().max(0.0) as usize).min(available);
+        for buffer in &mut self.buffers {
+            buffer.drain(..done);
+        }
+        self.pos -= done as f64;
+        Some(Frame {
+            rate: self.target_rate as f32,
+            data,
+        })
+    }
+}
+
+type InPort = Arc<flow::Port<Frame, ()>>;
+type Step =
+    Box<dyn Future<Item = future::Loop<(InPort, Frame), InPort>, Error = (InPort, flow::Error)> + Send>;
+
+/// Converts frames to another rate. Each request on the `Output` port is answered with one frame at
+/// the target rate, the length of the graph's block size, pulling as many frames from the `Input`
+/// port as that takes.
+pub struct Resample {
+    ifc: Arc<flow::Interface>,
+    in_port: InPort,
+    out_port: Arc<flow::Port<(), Frame>>,
+    config: Arc<Mutex<ResampleConfig>>,
+    resampler: Arc<Mutex<Resampler>>,
+    breaker: Breaker,
+}
+
+impl Module for Resample {
+    fn new(ifc: Arc<flow::Interface>) -> Resample {
+        let breaker = ifc.breaker();
+        let in_port = ifc.get_or_create_port("Input".into());
+        let out_port = ifc.get_or_create_port("Output".into());
+        in_port.set_tag("audio");
+        out_port.set_tag("audio");
+        let rate = ifc.audio_config().rate as f64;
+        let config = ResampleConfig::default();
+        Resample {
+            ifc,
+            in_port,
+            out_port,
+            resampler: Arc::new(Mutex::new(Resampler::new(config.quality, rate, rate))),
+            config: Arc::new(Mutex::new(config)),
+            breaker,
+        }
+    }
+    fn name() -> &'static str {
+        "Resample"
+    }
+    fn start<Ex: executor::Executor>(&mut self, mut exec: Ex) {
+        let (ifc, config, resampler) = (self.ifc.clone(), self.config.clone(), self.resampler.clone());
+        exec.spawn(Box::new(future::loop_fn(
+            (self.in_port.clone(), self.out_port.clone(), self.breaker.clone()),
+            move |(in_port, out_port, breaker)| {
+                let (ifc, config, resampler) = (ifc.clone(), config.clone(), resampler.clone());
+                out_port
+                    .read1()
+                    .wrap(in_port)
+                    .map_err(|(in_port, (out_port, err))| (in_port, out_port, err))
+                    .and_then(move |(in_port, (out_port, _req))| {
+                        resample(in_port, &ifc, config, resampler)
+                            .wrap(out_port)
+                            .map_err(|(out_port, (in_port, err))| (in_port, out_port, err))
+                    })
+                    .and_then(|(out_port, (in_port, frame))| {
+                        out_port
+                            .write1(frame)
+                            .wrap(in_port)
+                            .map_err(|(in_port, (out_port, err))| (in_port, out_port, err))
+                    })
+                    .then(move |result| -> Result<_, Never> {
+                        match result {
+                            Ok((in_port, out_port)) if !breaker.test() => {
+                                Ok(future::Loop::Continue((in_port, out_port, breaker)))
+                            }
+                            Err((_, _, flow::Error::Closed)) | Ok(_) => Ok(future::Loop::Break(())),
+                            Err((in_port, out_port, err)) => {
+                                println!("Resample err: {:?}", err);
+                                Ok(future::Loop::Continue((in_port, out_port, breaker)))
+                            }
+                        }
+                    })
+            },
+        ))).unwrap();
+    }
+    fn stop(&mut self) {
+        self.breaker.brake();
+    }
+    fn ports(&self) -> Vec<Arc<flow::OpaquePort>> {
+        self.ifc.ports()
+    }
+    fn save_state(&self) -> Option<String> {
+        ron::ser::to_string(&self.config()).ok()
+    }
+    fn load_state(&mut self, state: &str) {
+        match ron::de::from_str(state) {
+            Ok(config) => self.set_config(config),
+            Err(err) => println!("Could not load Resample settings: {:?}", err),
+        }
+    }
+}
+
+impl Resample {
+    pub fn config(&self) -> ResampleConfig {
+        self.config.lock().unwrap().clone()
+    }
+    /// Change the settings. They take effect from the next frame on.
+    pub fn set_config(&mut self, config: ResampleConfig) {
+        *self.config.lock().unwrap() = config;
+    }
+}
+
+/// Pull frames from `port` until there is enough input for a frame of output. If nothing is
+/// connected, silence is produced instead of waiting.
+fn resample(
+    port: InPort,
+    ifc: &flow::Interface,
+    config: Arc<Mutex<ResampleConfig>>,
+    resampler: Arc<Mutex<Resampler>>,
+) -> Box<dyn Future<Item = (InPort, Frame), Error = (InPort, flow::Error)> + Send> {
+    let audio = ifc.audio_config();
+    let (target, quality) = {
+        let config = config.lock().unwrap();
+        (config.target_rate.unwrap_or(audio.rate) as f64, config.quality)
+    };
+    Box::new(future::loop_fn(port, move |port| -> Step {
+        if port.edges().is_empty() {
+            let silence = Frame {
+                rate: target as f32,
+                data: Array2::zeros((audio.block_size, audio.channels)),
+            };
+            return Box::new(future::ok(future::Loop::Break((port, silence))));
+        }
+        {
+            let mut resampler = resampler.lock().unwrap();
+            let source = resampler.source_rate();
+            resampler.configure(quality, source, target);
+            if let Some(frame) = resampler.pull(audio.block_size) {
+                return Box::new(future::ok(future::Loop::Break((port, frame))));
+            }
+        }
+        let resampler = resampler.clone();
+        Box::new(port.write1(()).and_then(|port| port.read1()).map(move |(port, frame)| {
+            let mut resampler = resampler.lock().unwrap();
+            // frames of unknown rate are taken to be at the target rate already
+            let source = if frame.rate > 0.0 { frame.rate as f64 } else { target };
+            resampler.configure(quality, source, target);
+            resampler.push(&frame);
+            future::Loop::Continue(port)
+        }))
+    }))
+}
+
+use gfx_device_gl as gl;
+use gui::{button::*, component::*, event::*, geom::*, module_gui::*, render::*, textbox::*};
+struct ResampleGui {
+    bounds: Box3,
+    config: Arc<Mutex<ResampleConfig>>,
+    resampler: Arc<Mutex<Resampler>>,
+    rate_box: TextBox,
+    quality_button: Button,
+    // the settings and rates as of the last render
+    shown: (ResampleConfig, f64, f64),
+}
+const PADDING: f32 = 4.0;
+const ROW_HEIGHT: f32 = 22.0;
+const LABEL_WIDTH: f32 = 48.0;
+impl ModuleGui for Resample {
+    fn new_body(&mut self, ctx: &mut RenderContext, bounds: Box3) -> Box<dyn GuiComponent<BodyUpdate>> {
+        let config = self.config();
+        let row = |row: usize, x: f32| Box3 {
+            pos: bounds.pos + Pt3::new(x, PADDING + row as f32 * (ROW_HEIGHT + PADDING), 0.0),
+            size: Pt3::new(bounds.size.x - x - PADDING, ROW_HEIGHT, 0.0),
+        };
+        Box::new(ResampleGui {
+            bounds,
+            rate_box: TextBox::new(ctx.clone(), rate_text(config.target_rate), row(0, LABEL_WIDTH)),
+            quality_button: Button::new(ctx.clone(), quality_text(config.quality), row(1, PADDING)),
+            shown: (config, 0.0, 0.0),
+            config: self.config.clone(),
+            resampler: self.resampler.clone(),
+        })
+    }
+}
+/// The target rate is edited as text, where nothing means the rate of the graph.
+fn rate_text(rate: Option<u32>) -> String {
+    rate.map(|rate| rate.to_string()).unwrap_or_default()
+}
+fn quality_text(quality: Quality) -> String {
+    format!("Quality: {:?}", quality)
+}
+impl ResampleGui {
+    fn apply(&mut self) {
+        let text = self.rate_box.content().trim().to_string();
+        let target_rate = match text.parse() {
+            Ok(0) => None,
+            Ok(rate) => Some(rate),
+            Err(_) if text.is_empty() => None,
+            Err(_) => {
+                println!("The target rate must be a whole number, or nothing to follow the graph");
+                return;
+            }
+        };
+        self.config.lock().unwrap().target_rate = target_rate;
+        self.rate_box.set_focused(false);
+    }
+    fn current(&self) -> (ResampleConfig, f64, f64) {
+        let resampler = self.resampler.lock().unwrap();
+        (
+            self.config.lock().unwrap().clone(),
+            resampler.source_rate(),
+            resampler.target_rate(),
+        )
+    }
+}
+impl GuiComponent<bool> for ResampleGui {
+    fn set_bounds(&mut self, bounds: Box3) {
+        self.bounds = bounds;
+    }
+    fn bounds(&self) -> Box3 {
+        self.bounds
+    }
+    fn render(&mut self, device: &mut gl::Device, ctx: &mut RenderContext) {
+        self.shown = self.current();
+        let (ref config, source, target) = self.shown;
+        // show the current settings, unless they are being edited
+        if !self.rate_box.focused() {
+            self.rate_box.set_content(rate_text(config.target_rate));
+        }
+        self.quality_button.set_label(quality_text(config.quality));
+
+        let pos = self.rate_box.bounds().pos;
+        ctx.draw_text("Rate", Pt3::new(self.bounds.pos.x + PADDING, pos.y + 4.0, pos.z), [1.0; 3]);
+        self.rate_box.render(device, ctx);
+        self.quality_button.render(device, ctx);
+        let status = format!("{} -> {} Hz", source, target);
+        let pos = self.bounds.pos + Pt3::new(PADDING, PADDING + 2.0 * (ROW_HEIGHT + PADDING) + 4.0, 0.0);
+        ctx.draw_text(&status, pos, [1.0; 3]);
+    }
+    fn handle(&mut self, event: &Event) -> BodyUpdate {
+        if let EventData::Character('\r') = event.data {
+            if self.rate_box.focused() {
+                self.apply();
+                return true;
+            }
+        }
+        let focus = match event.data {
+            EventData::Click(pos, _, _) => event.focus && self.rate_box.intersect(pos),
+            _ => event.focus,
+        };
+        let mut update = self.rate_box.handle(&event.with_focus(focus)) != TextBoxUpdate::Unchanged;
+        match self.quality_button.handle(event) {
+            ButtonUpdate::Unchanged => {}
+            ButtonUpdate::NeedRender => update = true,
+            ButtonUpdate::Clicked => {
+                let mut config = self.config.lock().unwrap();
+                config.quality = config.quality.next();
+                update = true;
+            }
+        }
+        // the rates come from the frames flowing through, so check whether there is something new to
+        // show
+        update || self.current() != self.shown
+    }
+}
+
+#[test]
+fn test_resample() {
+    let (source, target) = (44100.0, 48000.0);
+    let freq = 1000.0;
+    let mut resampler = Resampler::new(Quality::Medium, source, target);
+    let mut output = Vec::new();
+    let mut n = 0;
+    // odd block sizes on both sides, so that the boundaries never line up
+    while output.len() < 4000 {
+        let frame = Frame {
+            rate: source as f32,
+            data: Array2::from_shape_fn((100, 1), |(i, _)| {
+                (2.0 * PI * freq * (n + i) as f64 / source).sin() as f32
+            }),
+        };
+        n += 100;
+        resampler.push(&frame);
+        while let Some(frame) = resampler.pull(64) {
+            assert_eq!(frame.rate, target as f32);
+            output.extend(frame.data.iter().cloned());
+        }
+    }
+    // past the start, where the input was preceded by silence, the output is the same sine at the new
+    // rate
+    for (i, &sample) in output.iter().enumerate().skip(200) {
+        let expected = (2.0 * PI * freq * i as f64 / target).sin() as f32;
+        assert!((sample - expected).abs() < 1e-3, "sample {}: {} != {}", i, sample, expected);
+    }
+
+    // at the same rate, samples pass straight through
+    let mut resampler = Resampler::new(Quality::Low, source, source);
+    let frame = Frame {
+        rate: source as f32,
+        data: Array2::from_shape_fn((64, 2), |(i, c)| (i * 2 + c) as f32),
+    };
+    resampler.push(&frame);
+    resampler.push(&frame);
+    let out = resampler.pull(32).unwrap();
+    for i in 0..32 {
+        assert_eq!(out.data.row(i), frame.data.row(i));
+    }
+}
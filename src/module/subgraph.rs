@@ -0,0 +1,182 @@
+use futures::executor;
+
+use module::{flow, Module};
+
+use std::sync::Arc;
+
+/// A macro module, which wraps a patch of its own. Ports inside the patch can be exposed as ports
+/// of the macro, with data forwarded across the boundary.
+pub struct Subgraph {
+    ifc: Arc<flow::Interface>,
+    graph: Arc<flow::Graph>,
+    // holds the inner ends of the exposed ports
+    boundary: Arc<flow::Interface>,
+    exposed: Vec<Exposed>,
+}
+
+struct Exposed {
+    inner: Arc<flow::OpaquePort>,
+    outer: Arc<flow::OpaquePort>,
+    bridge: Arc<flow::OpaquePort>,
+}
+
+impl Module for Subgraph {
+    fn new(ifc: Arc<flow::Interface>) -> Subgraph {
+        let graph = match ifc.graph() {
+            Some(outer) => outer.new_child(),
+            None => flow::Graph::new(),
+        };
+        let boundary = graph.add_node();
+        Subgraph {
+            ifc,
+            graph,
+            boundary,
+            exposed: Vec::new(),
+        }
+    }
+    fn name() -> &'static str {
+        "Macro"
+    }
+    fn start<Ex: executor::Executor>(&mut self, exec: Ex) {
+        // exposed ports are bridged on the graph's executor as soon as they are exposed
+    }
+    fn stop(&mut self) {
+        // closing the inner ends of the bridges lets them finish
+        let _ = self.graph.remove_node(self.boundary.id());
+    }
+    fn ports(&self) -> Vec<Arc<flow::OpaquePort>> {
+        self.exposed.iter().map(|exposed| exposed.outer.clone()).collect()
+    }
+}
+
+impl Subgraph {
+    /// Get the graph inside this macro.
+    pub fn graph(&self) -> &Arc<flow::Graph> {
+        &self.graph
+    }
+    /// Determines if a port inside the macro is exposed.
+    pub fn is_exposed(&self, port: &Arc<flow::OpaquePort>) -> bool {
+        self.exposed.iter().any(|exposed| Arc::ptr_eq(&exposed.inner, port))
+    }
+    /// Get the node ID and name of every exposed port inside the macro, in the order they were
+    /// exposed.
+    pub fn exposed(&self) -> Vec<(flow::NodeId, String)> {
+        self.exposed
+            .iter()
+            .map(|exposed| (exposed.inner.node_id(), exposed.inner.name().into()))
+            .collect()
+    }
+    /// Expose a port inside the macro as a port of the macro itself. Fails with
+    /// `Error::NotAvailable` if the graph has no executor to forward data with.
+    pub fn expose(&mut self, port: &Arc<flow::OpaquePort>) -> Result<Arc<flow::OpaquePort>, flow::Error> {
+        if let Some(exposed) = self.exposed.iter().find(|exposed| Arc::ptr_eq(&exposed.inner, port)) {
+            return Ok(exposed.outer.clone());
+        }
+        // the node id keeps names unique when several inner modules have a port of the same name
+        let name = format!("{} {}", port.name(), port.node_id().0);
+        let outer = port.twin(&self.ifc, name.clone());
+        let bridge = port.mirror(&self.boundary, name);
+        let result = port
+            .connect(&bridge)
+            .map_err(|_| flow::Error::NotAvailable)
+            .and_then(|_| self.graph.spawn(flow::bridge(&outer, &bridge)));
+        if let Err(err) = result {
+            let _ = self.ifc.remove_port(outer.id());
+            let _ = self.boundary.remove_port(bridge.id());
+            return Err(err);
+        }
+        self.exposed.push(Exposed {
+            inner: port.clone(),
+            outer: outer.clone(),
+            bridge,
+        });
+        Ok(outer)
+    }
+    /// Stop exposing a port inside the macro, removing the corresponding port of the macro.
+    pub fn unexpose(&mut self, port: &Arc<flow::OpaquePort>) -> Result<(), flow::Error> {
+        let idx = self
+            .exposed
+            .iter()
+            .position(|exposed| Arc::ptr_eq(&exposed.inner, port))
+            .ok_or(flow::Error::InvalidPort)?;
+        let exposed = self.exposed.remove(idx);
+        // closing both ends finishes the bridge
+        self.ifc.remove_port(exposed.outer.id())?;
+        self.boundary.remove_port(exposed.bridge.id())?;
+        Ok(())
+    }
+}
+
+use gfx_device_gl as gl;
+use gui::{component::*, event::*, geom::*, module_gui::*, render::*};
+struct SubgraphGui {
+    bounds: Box3,
+}
+impl ModuleGui for Subgraph {
+    fn new_body(&mut self, ctx: &mut RenderContext, bounds: Box3) -> Box<dyn GuiComponent<BodyUpdate>> {
+        Box::new(SubgraphGui {
+            bounds,
+        })
+    }
+    fn inner_graph(&self) -> Option<Arc<flow::Graph>> {
+        Some(self.graph.clone())
+    }
+    fn exposed(&self) -> Vec<(flow::NodeId, String)> {
+        Subgraph::exposed(self)
+    }
+    fn toggle_exposed(&mut self, port: &Arc<flow::OpaquePort>) {
+        let result = if self.is_exposed(port) {
+            self.unexpose(port)
+        } else {
+            self.expose(port).map(|_| ())
+        };
+        if let Err(err) = result {
+            println!("Could not expose {:?}: {:?}", port.name(), err);
+        }
+    }
+}
+impl GuiComponent<bool> for SubgraphGui {
+    fn set_bounds(&mut self, bounds: Box3) {
+        self.bounds = bounds;
+    }
+    fn bounds(&self) -> Box3 {
+        self.bounds
+    }
+    fn render(&mut self, device: &mut gl::Device, ctx: &mut RenderContext) {
+        ctx.draw_text(
+            "Double click title to edit",
+            self.bounds.pos + Pt3::new(4.0, 4.0, 0.0),
+            [0.6, 0.6, 0.6],
+        );
+    }
+    fn handle(&mut self, event: &Event) -> BodyUpdate {
+        false
+    }
+}
+
+#[test]
+fn test_expose() {
+    use futures::executor::{block_on, ThreadPool};
+
+    let outer = flow::Graph::new();
+    outer.set_executor(ThreadPool::new().unwrap());
+    let mut subgraph = Subgraph::new(outer.add_node());
+    let inner = subgraph.graph().add_node().get_or_create_port::<usize, i32>("Output".into());
+    let exposed = subgraph.expose(&inner.as_opaque()).unwrap();
+    assert_eq!(subgraph.ports().len(), 1);
+    assert_eq!(subgraph.exposed(), vec![(inner.node_id(), "Output".to_string())]);
+
+    // data crosses the boundary in both directions
+    let dst = outer.add_node().get_or_create_port::<i32, usize>("Input".into());
+    dst.as_opaque().connect(&exposed).unwrap();
+    block_on(dst.clone().write1(2)).unwrap();
+    let (inner, request) = block_on(inner.read1()).unwrap();
+    assert_eq!(request, 2);
+    block_on(inner.clone().write(vec![5, 6])).unwrap();
+    assert_eq!(&*block_on(dst.clone().read_n(2)).unwrap().1, &[5, 6]);
+
+    subgraph.unexpose(&inner.as_opaque()).unwrap();
+    assert!(subgraph.ports().is_empty());
+    assert!(dst.edges().is_empty());
+    subgraph.stop();
+}
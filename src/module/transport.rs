@@ -0,0 +1,428 @@
+use futures::executor;
+use futures::future;
+use futures::prelude::*;
+use futures::task;
+
+use future_ext::{Breaker, FutureWrapExt, RemoteWaker};
+use module::{flow, Module};
+
+use crossbeam::queue::{ArrayQueue, PushError};
+use jack;
+use jack_sys;
+
+use std::sync::{Arc, Mutex};
+use std::thread::Thread;
+
+/// The number of transport states waiting for the graph. If the graph falls behind, the oldest are
+/// dropped.
+const QUEUE_SIZE: usize = 4;
+/// The number of commands waiting for the next JACK cycle.
+const COMMAND_QUEUE_SIZE: usize = 16;
+
+/// Position in bars and beats, as published by the JACK timebase master.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Bbt {
+    /// Current bar, counting from 1.
+    pub bar: i32,
+    /// Current beat within the bar, counting from 1.
+    pub beat: i32,
+    /// Current tick within the beat, counting from 0.
+    pub tick: i32,
+    pub ticks_per_beat: f64,
+    pub beats_per_minute: f64,
+    /// Time signature numerator.
+    pub beats_per_bar: f32,
+    /// Time signature denominator.
+    pub beat_type: f32,
+}
+
+/// The JACK transport as of the start of one cycle.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct TransportState {
+    pub rolling: bool,
+    /// Position of the transport, in samples.
+    pub frame: u32,
+    pub rate: u32,
+    /// Length of the cycle, in samples.
+    pub length: usize,
+    /// Musical position and tempo. This is only known if some JACK client is the timebase master.
+    pub bbt: Option<Bbt>,
+}
+
+impl TransportState {
+    fn from_position(state: jack_sys::jack_transport_state_t, pos: &jack_sys::jack_position_t) -> Self {
+        // the position is packed, so every field is copied out before use
+        let valid = pos.valid;
+        let bbt = if valid & jack_sys::JackPositionBBT != 0 {
+            Some(Bbt {
+                bar: pos.bar,
+                beat: pos.beat,
+                tick: pos.tick,
+                ticks_per_beat: pos.ticks_per_beat,
+                beats_per_minute: pos.beats_per_minute,
+                beats_per_bar: pos.beats_per_bar,
+                beat_type: pos.beat_type,
+            })
+        } else {
+            None
+        };
+        TransportState {
+            rolling: state == jack_sys::JackTransportRolling,
+            frame: pos.frame,
+            rate: pos.frame_rate,
+            length: 0,
+            bbt,
+        }
+    }
+}
+
+/// Something to do to the JACK transport. Every JACK client shares the same transport, so this moves
+/// the timeline of every synced application.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum TransportCommand {
+    Start,
+    Stop,
+    /// Move to a position, in samples.
+    Locate(u32),
+}
+
+/// State shared between the module, its GUI and the JACK thread.
+#[derive(Clone)]
+struct Shared {
+    commands: Arc<ArrayQueue<TransportCommand>>,
+    /// The most recent state, for display. The JACK thread only updates it when it is not in use.
+    latest: Arc<Mutex<Option<TransportState>>>,
+}
+impl Shared {
+    fn send(&self, command: TransportCommand) {
+        if self.commands.push(command).is_err() {
+            println!("Transport command {:?} dropped: too many pending", command);
+        }
+    }
+}
+
+/// Follows the JACK transport. Each request on the `Output` port is answered with the transport state
+/// of the next JACK cycle, and commands read from the `Control` port start, stop or move the
+/// transport.
+pub struct Transport {
+    ifc: Arc<flow::Interface>,
+    out_port: Arc<flow::Port<(), TransportState>>,
+    control_port: Arc<flow::Port<TransportCommand, ()>>,
+    shared: Shared,
+    client: Option<jack::AsyncClient<(), TransportProcessor>>,
+    breaker: Breaker,
+}
+
+impl Module for Transport {
+    fn new(ifc: Arc<flow::Interface>) -> Transport {
+        let breaker = ifc.breaker();
+        let out_port = ifc.get_or_create_port("Output".into());
+        let control_port = ifc.get_or_create_port("Control".into());
+        out_port.set_tag("transport");
+        control_port.set_tag("control");
+        Transport {
+            ifc,
+            out_port,
+            control_port,
+            shared: Shared {
+                commands: Arc::new(ArrayQueue::new(COMMAND_QUEUE_SIZE)),
+                latest: Arc::new(Mutex::new(None)),
+            },
+            client: None,
+            breaker,
+        }
+    }
+    fn name() -> &'static str {
+        "Transport"
+    }
+    fn start<Ex: executor::Executor>(&mut self, mut exec: Ex) {
+        let waker = Arc::new(RemoteWaker::new());
+        let states = Arc::new(ArrayQueue::new(QUEUE_SIZE));
+        let processor = TransportProcessor {
+            states: states.clone(),
+            shared: self.shared.clone(),
+            waker: waker.thread(),
+        };
+        let name = "flow-synth-transport";
+        let client = match jack::Client::new(name, jack::ClientOptions::NO_START_SERVER) {
+            Ok((client, _status)) => jack::AsyncClient::new(client, (), processor).ok(),
+            Err(err) => {
+                println!("Could not open JACK client {:?}: {:?}", name, err);
+                None
+            }
+        };
+        if client.is_none() {
+            println!("JACK transport is unavailable");
+            return;
+        }
+        self.client = client;
+
+        exec.spawn(Box::new(future::loop_fn(
+            (self.out_port.clone(), waker, states, self.breaker.clone()),
+            |(port, waker, states, breaker)| {
+                port.read1()
+                    .wrap((waker, states))
+                    .and_then(|((waker, states), (port, _req))| {
+                        let (next_waker, next_states) = (waker.clone(), states.clone());
+                        future::poll_fn(move |cx| Ok(next_state(cx, &next_waker, &next_states)))
+                            .and_then(|state| port.write1(state).wrap((waker, states)))
+                    })
+                    .then(move |result| -> Result<_, Never> {
+                        match result {
+                            Ok(((waker, states), port)) if !breaker.test() => {
+                                Ok(future::Loop::Continue((port, waker, states, breaker)))
+                            }
+                            Err((_, (_, flow::Error::Closed))) | Ok(_) => Ok(future::Loop::Break(())),
+                            Err(((waker, states), (port, err))) => {
+                                println!("Transport err: {:?}", err);
+                                Ok(future::Loop::Continue((port, waker, states, breaker)))
+                            }
+                        }
+                    })
+            },
+        ))).unwrap();
+
+        exec.spawn(Box::new(future::loop_fn(
+            (self.control_port.clone(), self.shared.clone(), self.breaker.clone()),
+            |(port, shared, breaker)| {
+                let sender = shared.clone();
+                port.write1(()) // request a command
+                    .and_then(|port| port.read1())
+                    .map(move |(port, command)| {
+                        sender.send(command);
+                        port
+                    })
+                    .then(move |result| -> Result<_, Never> {
+                        match result {
+                            Ok(port) if !breaker.test() => {
+                                Ok(future::Loop::Continue((port, shared, breaker)))
+                            }
+                            Err((_, flow::Error::Closed)) | Ok(_) => Ok(future::Loop::Break(())),
+                            Err((port, err)) => {
+                                println!("Transport control err: {:?}", err);
+                                Ok(future::Loop::Continue((port, shared, breaker)))
+                            }
+                        }
+                    })
+            },
+        ))).unwrap();
+    }
+    fn stop(&mut self) {
+        self.breaker.brake();
+        if let Some(client) = self.client.take() {
+            let _ = client.deactivate();
+        }
+    }
+    fn ports(&self) -> Vec<Arc<flow::OpaquePort>> {
+        self.ifc.ports()
+    }
+}
+
+impl Transport {
+    /// Start the transport rolling. Like all commands, this takes effect on the next JACK cycle.
+    pub fn play(&self) {
+        self.shared.send(TransportCommand::Start);
+    }
+    /// Stop the transport where it is.
+    pub fn pause(&self) {
+        self.shared.send(TransportCommand::Stop);
+    }
+    /// Move the transport to a position, in samples.
+    pub fn locate(&self, frame: u32) {
+        self.shared.send(TransportCommand::Locate(frame));
+    }
+    /// The transport state of the most recent JACK cycle, if the module is running.
+    pub fn latest(&self) -> Option<TransportState> {
+        *self.shared.latest.lock().unwrap()
+    }
+}
+
+/// Take the next transport state, once there is one.
+fn next_state(
+    cx: &mut task::Context,
+    waker: &RemoteWaker,
+    states: &ArrayQueue<TransportState>,
+) -> Async<TransportState> {
+    waker.register(cx);
+    match states.pop() {
+        Ok(state) => Async::Ready(state),
+        Err(_) => Async::Pending,
+    }
+}
+
+struct TransportProcessor {
+    states: Arc<ArrayQueue<TransportState>>,
+    shared: Shared,
+    waker: Thread,
+}
+impl jack::ProcessHandler for TransportProcessor {
+    fn process(&mut self, client: &jack::Client, ps: &jack::ProcessScope) -> jack::Control {
+        // the transport functions are real-time safe, and none of this allocates
+        while let Ok(command) = self.shared.commands.pop() {
+            unsafe {
+                match command {
+                    TransportCommand::Start => jack_sys::jack_transport_start(client.raw()),
+                    TransportCommand::Stop => jack_sys::jack_transport_stop(client.raw()),
+                    TransportCommand::Locate(frame) => {
+                        jack_sys::jack_transport_locate(client.raw(), frame);
+                    }
+                }
+            }
+        }
+        let mut pos = jack_sys::jack_position_t::default();
+        let state = unsafe { jack_sys::jack_transport_query(client.raw(), &mut pos) };
+        let mut state = TransportState::from_position(state, &pos);
+        state.length = ps.n_frames() as usize;
+
+        // make room by dropping the oldest state, so that the graph always catches up to the present
+        if let Err(PushError(state)) = self.states.push(state) {
+            let _ = self.states.pop();
+            let _ = self.states.push(state);
+        }
+        // never wait on the GUI
+        if let Ok(mut latest) = self.shared.latest.try_lock() {
+            *latest = Some(state);
+        }
+        self.waker.unpark();
+        jack::Control::Continue
+    }
+}
+
+use gfx_device_gl as gl;
+use gui::{button::*, component::*, event::*, geom::*, module_gui::*, render::*};
+struct TransportGui {
+    bounds: Box3,
+    shared: Shared,
+    play_button: Button,
+    stop_button: Button,
+    rewind_button: Button,
+    // the state as of the last render
+    shown: Option<TransportState>,
+}
+const PADDING: f32 = 4.0;
+const ROW_HEIGHT: f32 = 22.0;
+impl ModuleGui for Transport {
+    fn new_body(&mut self, ctx: &mut RenderContext, bounds: Box3) -> Box<dyn GuiComponent<BodyUpdate>> {
+        let button_width = (bounds.size.x - PADDING * 4.0) / 3.0;
+        let button = |col: usize| Box3 {
+            pos: bounds.pos + Pt3::new(PADDING + col as f32 * (button_width + PADDING), PADDING, 0.0),
+            size: Pt3::new(button_width, ROW_HEIGHT, 0.0),
+        };
+        Box::new(TransportGui {
+            bounds,
+            shared: self.shared.clone(),
+            play_button: Button::new(ctx.clone(), "Play".into(), button(0)),
+            stop_button: Button::new(ctx.clone(), "Stop".into(), button(1)),
+            rewind_button: Button::new(ctx.clone(), "Rewind".into(), button(2)),
+            shown: None,
+        })
+    }
+}
+impl TransportGui {
+    fn current(&self) -> Option<TransportState> {
+        *self.shared.latest.lock().unwrap()
+    }
+}
+impl GuiComponent<bool> for TransportGui {
+    fn set_bounds(&mut self, bounds: Box3) {
+        self.bounds = bounds;
+    }
+    fn bounds(&self) -> Box3 {
+        self.bounds
+    }
+    fn render(&mut self, device: &mut gl::Device, ctx: &mut RenderContext) {
+        self.play_button.render(device, ctx);
+        self.stop_button.render(device, ctx);
+        self.rewind_button.render(device, ctx);
+
+        self.shown = self.current();
+        let mut lines = Vec::new();
+        match self.shown {
+            None => lines.push(("Not connected".to_string(), [0.6; 3])),
+            Some(state) => {
+                let seconds = state.frame as f64 / state.rate.max(1) as f64;
+                if state.rolling {
+                    lines.push((format!("Rolling {:.2}s", seconds), [0.2, 1.0, 0.2]));
+                } else {
+                    lines.push((format!("Stopped {:.2}s", seconds), [1.0; 3]));
+                }
+                match state.bbt {
+                    Some(bbt) => {
+                        lines.push((format!("{}|{}|{}", bbt.bar, bbt.beat, bbt.tick), [1.0; 3]));
+                        let tempo = format!(
+                            "{:.1} bpm {}/{}",
+                            bbt.beats_per_minute, bbt.beats_per_bar, bbt.beat_type
+                        );
+                        lines.push((tempo, [1.0; 3]));
+                    }
+                    None => lines.push(("No tempo".to_string(), [0.6; 3])),
+                }
+            }
+        }
+        for (row, (text, color)) in lines.iter().enumerate() {
+            let pos = Pt3::new(PADDING, PADDING + (row + 1) as f32 * (ROW_HEIGHT + PADDING) + 4.0, 0.0);
+            ctx.draw_text(text, self.bounds.pos + pos, *color);
+        }
+    }
+    fn handle(&mut self, event: &Event) -> BodyUpdate {
+        let mut update = false;
+        let buttons = [
+            (&mut self.play_button, TransportCommand::Start),
+            (&mut self.stop_button, TransportCommand::Stop),
+            (&mut self.rewind_button, TransportCommand::Locate(0)),
+        ];
+        for (button, command) in buttons.iter_mut() {
+            match button.handle(event) {
+                ButtonUpdate::Unchanged => {}
+                ButtonUpdate::NeedRender => update = true,
+                ButtonUpdate::Clicked => {
+                    self.shared.send(*command);
+                    update = true;
+                }
+            }
+        }
+        // the JACK thread reports back on its own, so check whether there is something new to show
+        update || self.current() != self.shown
+    }
+}
+
+#[test]
+fn test_from_position() {
+    let mut pos = jack_sys::jack_position_t::default();
+    pos.frame = 96000;
+    pos.frame_rate = 48000;
+    let state = TransportState::from_position(jack_sys::JackTransportStopped, &pos);
+    assert_eq!(
+        state,
+        TransportState {
+            rolling: false,
+            frame: 96000,
+            rate: 48000,
+            length: 0,
+            bbt: None,
+        }
+    );
+
+    pos.valid = jack_sys::JackPositionBBT;
+    pos.bar = 3;
+    pos.beat = 2;
+    pos.tick = 960;
+    pos.ticks_per_beat = 1920.0;
+    pos.beats_per_minute = 120.0;
+    pos.beats_per_bar = 4.0;
+    pos.beat_type = 4.0;
+    let state = TransportState::from_position(jack_sys::JackTransportRolling, &pos);
+    assert!(state.rolling);
+    assert_eq!(
+        state.bbt,
+        Some(Bbt {
+            bar: 3,
+            beat: 2,
+            tick: 960,
+            ticks_per_beat: 1920.0,
+            beats_per_minute: 120.0,
+            beats_per_bar: 4.0,
+            beat_type: 4.0,
+        })
+    );
+}
@@ -0,0 +1,64 @@
+//! The `.fsy` project format
+
+use gui::geom::*;
+use module::flow::{AudioConfig, NodeId};
+use ron;
+use std::io;
+
+#[derive(Debug)]
+pub enum Error {
+    IO(io::Error),
+    Serialize(ron::ser::Error),
+}
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Error {
+        Error::IO(e)
+    }
+}
+impl From<ron::ser::Error> for Error {
+    fn from(e: ron::ser::Error) -> Error {
+        Error::Serialize(e)
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Root {
+    pub modules: Vec<Module>,
+    pub connections: Vec<Connection>,
+    /// The audio settings of the graph. Only the outermost patch has them, since the patches inside
+    /// macro modules share its settings.
+    #[serde(default)]
+    pub audio_config: Option<AudioConfig>,
+}
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Module {
+    pub bounds: Box3,
+    pub id: NodeId,
+    pub type_name: String,
+    /// The patch inside a macro module.
+    #[serde(default)]
+    pub inner: Option<Root>,
+    /// Ports inside a macro module that are exposed as its own ports, in the order they were
+    /// exposed.
+    #[serde(default)]
+    pub exposed: Vec<Exposed>,
+    /// Module specific settings, as produced by `Module::save_state`.
+    #[serde(default)]
+    pub state: Option<String>,
+}
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Exposed {
+    pub node: NodeId,
+    pub port: String,
+}
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Connection {
+    pub src_node: NodeId,
+    pub src_port: String,
+    pub dst_node: NodeId,
+    pub dst_port: String,
+    /// Description of what flows over the wire, from the point of view of the source port.
+    /// Informational only.
+    #[serde(default)]
+    pub data_type: Option<String>,
+}
@@ -0,0 +1,235 @@
+//! Just enough of the WAV format to render audio to files and read it back
+
+use std::io::{self, Read, Seek, SeekFrom, Write};
+
+const FORMAT_PCM: u16 = 1;
+const FORMAT_FLOAT: u16 = 3;
+const FORMAT_EXTENSIBLE: u16 = 0xfffe;
+
+/// The layout of the samples in a WAV file.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct WavSpec {
+    pub channels: u16,
+    pub rate: u32,
+}
+
+/// Write interleaved samples as a 32 bit float WAV file.
+pub fn write<W: Write>(mut writer: W, spec: WavSpec, samples: &[f32]) -> io::Result<()> {
+    write_header(&mut writer, spec, samples.len() as u32)?;
+    write_samples(&mut writer, samples)
+}
+
+/// Writes a 32 bit float WAV file as samples arrive, for recordings of unknown length. The header
+/// states no samples until `finish` fills in the sizes.
+pub struct WavWriter<W: Write + Seek> {
+    writer: W,
+    spec: WavSpec,
+    samples: u32,
+}
+impl<W: Write + Seek> WavWriter<W> {
+    pub fn new(mut writer: W, spec: WavSpec) -> io::Result<WavWriter<W>> {
+        write_header(&mut writer, spec, 0)?;
+        Ok(WavWriter {
+            writer,
+            spec,
+            samples: 0,
+        })
+    }
+    /// Append interleaved samples.
+    pub fn write(&mut self, samples: &[f32]) -> io::Result<()> {
+        write_samples(&mut self.writer, samples)?;
+        self.samples += samples.len() as u32;
+        Ok(())
+    }
+    /// Rewrite the header with the sizes of everything written, and hand back the writer.
+    pub fn finish(mut self) -> io::Result<W> {
+        self.writer.seek(SeekFrom::Start(0))?;
+        write_header(&mut self.writer, self.spec, self.samples)?;
+        self.writer.seek(SeekFrom::End(0))?;
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+}
+
+fn write_header<W: Write>(writer: &mut W, spec: WavSpec, samples: u32) -> io::Result<()> {
+    let data_len = samples * 4;
+    let block_align = spec.channels * 4;
+    writer.write_all(b"RIFF")?;
+    // everything after this field: "WAVE", the fmt chunk, the fact chunk and the data chunk
+    write_u32(writer, 4 + (8 + 18) + (8 + 4) + 8 + data_len)?;
+    writer.write_all(b"WAVE")?;
+
+    writer.write_all(b"fmt ")?;
+    write_u32(writer, 18)?;
+    write_u16(writer, FORMAT_FLOAT)?;
+    write_u16(writer, spec.channels)?;
+    write_u32(writer, spec.rate)?;
+    write_u32(writer, spec.rate * block_align as u32)?;
+    write_u16(writer, block_align)?;
+    write_u16(writer, 32)?;
+    write_u16(writer, 0)?;
+
+    // non-PCM formats are supposed to state their length in samples
+    writer.write_all(b"fact")?;
+    write_u32(writer, 4)?;
+    write_u32(writer, samples / spec.channels.max(1) as u32)?;
+
+    writer.write_all(b"data")?;
+    write_u32(writer, data_len)
+}
+fn write_samples<W: Write>(writer: &mut W, samples: &[f32]) -> io::Result<()> {
+    for sample in samples {
+        write_u32(writer, sample.to_bits())?;
+    }
+    Ok(())
+}
+
+/// Read a WAV file, returning its layout and interleaved samples scaled to [-1, 1]. Supports 8, 16,
+/// 24 and 32 bit PCM and 32 bit float.
+pub fn read<R: Read>(mut reader: R) -> io::Result<(WavSpec, Vec<f32>)> {
+    let mut riff = [0; 12];
+    reader.read_exact(&mut riff)?;
+    if &riff[0..4] != b"RIFF" || &riff[8..12] != b"WAVE" {
+        return Err(invalid("not a WAV file"));
+    }
+
+    let mut format = None;
+    loop {
+        let mut header = [0; 8];
+        reader.read_exact(&mut header)?;
+        let len = u32_at(&header, 4) as usize;
+        // the length can't be trusted to allocate up front, so the chunk only grows as it is read
+        let mut chunk = Vec::new();
+        reader.by_ref().take(len as u64).read_to_end(&mut chunk)?;
+        if chunk.len() < len {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "chunk cut short"));
+        }
+        match &header[0..4] {
+            b"fmt " => {
+                if chunk.len() < 16 {
+                    return Err(invalid("fmt chunk too short"));
+                }
+                let mut tag = u16_at(&chunk, 0);
+                if tag == FORMAT_EXTENSIBLE && chunk.len() >= 26 {
+                    // the real format is the start of the sub-format GUID
+                    tag = u16_at(&chunk, 24);
+                }
+                let spec = WavSpec {
+                    channels: u16_at(&chunk, 2),
+                    rate: u32_at(&chunk, 4),
+                };
+                if spec.channels == 0 {
+                    return Err(invalid("fmt chunk has no channels"));
+                }
+                format = Some((spec, tag, u16_at(&chunk, 14)));
+            }
+            b"data" => {
+                let (spec, tag, bits) = format.ok_or_else(|| invalid("data chunk before fmt chunk"))?;
+                if bits >= 8 && chunk.len() % (bits as usize / 8) != 0 {
+                    return Err(invalid("data chunk is not a whole number of samples"));
+                }
+                let samples = match (tag, bits) {
+                    (FORMAT_PCM, 8) => chunk.iter().map(|&x| (x as f32 - 128.0) / 128.0).collect(),
+                    (FORMAT_PCM, 16) => chunk
+                        .chunks_exact(2)
+                        .map(|x| i16::from(x[0]) as f32 + (x[1] as i8) as f32 * 256.0)
+                        .map(|x| x / 32768.0)
+                        .collect(),
+                    (FORMAT_PCM, 24) => chunk
+                        .chunks_exact(3)
+                        .map(|x| ((x[0] as i32) << 8 | (x[1] as i32) << 16 | (x[2] as i32) << 24) >> 8)
+                        .map(|x| x as f32 / 8_388_608.0)
+                        .collect(),
+                    (FORMAT_PCM, 32) => chunk
+                        .chunks_exact(4)
+                        .map(|x| u32_at(x, 0) as i32 as f32 / 2_147_483_648.0)
+                        .collect(),
+                    (FORMAT_FLOAT, 32) => chunk
+                        .chunks_exact(4)
+                        .map(|x| f32::from_bits(u32_at(x, 0)))
+                        .collect(),
+                    _ => return Err(invalid("unsupported sample format")),
+                };
+                return Ok((spec, samples));
+            }
+            _ => {}
+        }
+        // chunks are padded to an even length
+        if len % 2 == 1 {
+            reader.read_exact(&mut [0])?;
+        }
+    }
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+fn u16_at(bytes: &[u8], idx: usize) -> u16 {
+    u16::from(bytes[idx]) | u16::from(bytes[idx + 1]) << 8
+}
+fn u32_at(bytes: &[u8], idx: usize) -> u32 {
+    u32::from(u16_at(bytes, idx)) | u32::from(u16_at(bytes, idx + 2)) << 16
+}
+fn write_u16<W: Write>(writer: &mut W, x: u16) -> io::Result<()> {
+    writer.write_all(&[x as u8, (x >> 8) as u8])
+}
+fn write_u32<W: Write>(writer: &mut W, x: u32) -> io::Result<()> {
+    write_u16(writer, x as u16)?;
+    write_u16(writer, (x >> 16) as u16)
+}
+
+#[test]
+fn test_roundtrip() {
+    let spec = WavSpec {
+        channels: 2,
+        rate: 48000,
+    };
+    let samples: Vec<f32> = (0..64).map(|x| (x as f32 / 10.0).sin()).collect();
+    let mut file = Vec::new();
+    write(&mut file, spec, &samples).unwrap();
+
+    // writing a block at a time makes the same file
+    let mut writer = WavWriter::new(io::Cursor::new(Vec::new()), spec).unwrap();
+    for block in samples.chunks(16) {
+        writer.write(block).unwrap();
+    }
+    assert_eq!(writer.finish().unwrap().into_inner(), file);
+    assert_eq!(read(&file[..]).unwrap(), (spec, samples));
+}
+
+#[test]
+fn test_read_pcm16() {
+    let mut file = Vec::new();
+    file.extend_from_slice(b"RIFF\x2c\x00\x00\x00WAVEfmt \x10\x00\x00\x00");
+    // mono, 8000Hz, 16000 bytes/s, 2 byte blocks, 16 bits
+    file.extend_from_slice(b"\x01\x00\x01\x00\x40\x1f\x00\x00\x80\x3e\x00\x00\x02\x00\x10\x00");
+    file.extend_from_slice(b"data\x08\x00\x00\x00\x00\x00\x00\x40\x00\x80\xff\xff");
+    let (spec, samples) = read(&file[..]).unwrap();
+    assert_eq!(
+        spec,
+        WavSpec {
+            channels: 1,
+            rate: 8000,
+        }
+    );
+    assert_eq!(samples, vec![0.0, 0.5, -1.0, -1.0 / 32768.0]);
+}
+
+#[test]
+fn test_read_partial_sample() {
+    let mut file = Vec::new();
+    file.extend_from_slice(b"RIFF\x28\x00\x00\x00WAVEfmt \x10\x00\x00\x00");
+    // mono, 8000Hz, 16 bits, but a sample and a half of data
+    file.extend_from_slice(b"\x01\x00\x01\x00\x40\x1f\x00\x00\x80\x3e\x00\x00\x02\x00\x10\x00");
+    file.extend_from_slice(b"data\x03\x00\x00\x00\x00\x00\x40\x00");
+    assert_eq!(read(&file[..]).unwrap_err().kind(), io::ErrorKind::InvalidData);
+}
+
+#[test]
+fn test_read_truncated() {
+    let mut file = Vec::new();
+    file.extend_from_slice(b"RIFF\x2c\x00\x00\x00WAVE");
+    // a chunk that claims to be almost 4GB long
+    file.extend_from_slice(b"LIST\xff\xff\xff\xff\x00\x00");
+    assert_eq!(read(&file[..]).unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+}